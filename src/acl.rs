@@ -0,0 +1,268 @@
+//! Access control between clients, compiled to an nftables ruleset for
+//! routers.
+//!
+//! Without an ACL any client can reach any other client through a router.
+//! Once the ruleset is loaded, traffic between clients is dropped unless a
+//! rule allows it, and replies to allowed traffic are let back through.
+
+use configuration::Configuration;
+use diagnostic::Diagnostic;
+use endpoint::Router;
+use errors::*;
+use ipnet::IpNet;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+
+/// The protocol a rule allows
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Protocol {
+    /// Any protocol, or with ports, either tcp or udp
+    #[default]
+    Any,
+    Tcp,
+    Udp
+}
+
+
+impl Protocol {
+    pub fn is_any(&self) -> bool {
+        *self == Protocol::Any
+    }
+}
+
+
+impl FromStr for Protocol {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Protocol> {
+        match s {
+            "any" => Ok(Protocol::Any),
+            "tcp" => Ok(Protocol::Tcp),
+            "udp" => Ok(Protocol::Udp),
+            _ => bail!(ErrorKind::InvalidArgument("protocol".to_string(),
+                                                  s.to_string()))
+        }
+    }
+}
+
+
+/// Allows traffic from the members of one group to the members of another
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Rule {
+    from: String,
+    to: String,
+    #[serde(default, skip_serializing_if = "Protocol::is_any")]
+    protocol: Protocol,
+    /// Destination ports. Empty, every port is allowed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ports: Vec<u16>
+}
+
+
+impl Rule {
+    pub fn new(from: &str, to: &str) -> Rule {
+        Rule {
+            from: from.to_string(),
+            to: to.to_string(),
+            protocol: Protocol::Any,
+            ports: Vec::new()
+        }
+    }
+
+    pub fn builder_protocol(mut self, protocol: Protocol) -> Rule {
+        self.protocol = protocol;
+        self
+    }
+
+    pub fn builder_ports(mut self, ports: Vec<u16>) -> Rule {
+        self.ports = ports;
+        self
+    }
+
+    pub fn from(&self) -> &str { &self.from }
+    pub fn to(&self) -> &str { &self.to }
+    pub fn protocol(&self) -> Protocol { self.protocol }
+    pub fn ports(&self) -> &[u16] { &self.ports }
+}
+
+
+/// Named groups of clients, and the rules between them. A group's members
+/// are the clients listed here, and any client put in the group with
+/// `EndPoint::set_groups`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Acl {
+    /// Client names by group name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    groups: BTreeMap<String, Vec<String>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    rules: Vec<Rule>
+}
+
+
+impl Acl {
+    pub fn new() -> Acl {
+        Acl::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty() && self.rules.is_empty()
+    }
+
+    pub fn set_group(&mut self, name: &str, clients: Vec<String>) {
+        self.groups.insert(name.to_string(), clients);
+    }
+
+    pub fn remove_group(&mut self, name: &str) -> Option<Vec<String>> {
+        self.groups.remove(name)
+    }
+
+    /// Take a client out of every group it is in
+    pub fn remove_client(&mut self, name: &str) {
+        for clients in self.groups.values_mut() {
+            clients.retain(|client| client != name);
+        }
+    }
+
+    pub fn push_rule(&mut self, rule: Rule) {
+        self.rules.push(rule);
+    }
+
+    pub fn groups(&self) -> &BTreeMap<String, Vec<String>> { &self.groups }
+    pub fn rules(&self) -> &[Rule] { &self.rules }
+
+    pub fn group(&self, name: &str) -> Option<&[String]> {
+        self.groups.get(name).map(|clients| clients.as_slice())
+    }
+
+
+    /// Problems with the groups and rules, such as unknown clients or groups
+    pub fn validate(&self, configuration: &Configuration) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+
+        for (group, clients) in self.groups() {
+            for client in clients {
+                if configuration.client_by_name(client).is_none() {
+                    diagnostics.push(Diagnostic::error(None,
+                        format!("acl.groups.{}", group),
+                        format!("there is no client {}", client)));
+                }
+            }
+        }
+
+        for (i, rule) in self.rules().iter().enumerate() {
+            for &(field, group) in &[("from", rule.from()),
+                                     ("to", rule.to())] {
+                if !configuration.has_group(group) {
+                    diagnostics.push(Diagnostic::error(None,
+                        format!("acl.rules.{}.{}", i, field),
+                        format!("there is no group {}", group)));
+                }
+            }
+            if rule.ports().contains(&0) {
+                diagnostics.push(Diagnostic::error(None,
+                    format!("acl.rules.{}.ports", i), "port 0 is not valid"));
+            }
+        }
+
+        diagnostics
+    }
+}
+
+
+/// An anonymous nftables set, e.g. `{ 10.0.1.0/24, 10.0.2.1/32 }`
+fn set<T: ToString>(items: &[T]) -> String {
+    let items: Vec<String> = items.iter().map(|item| item.to_string())
+        .collect();
+    format!("{{ {} }}", items.join(", "))
+}
+
+
+/// The networks of every member of a group
+fn group_nets(configuration: &Configuration, name: &str) -> Vec<IpNet> {
+    configuration.group_members(name)
+        .iter()
+        .flat_map(|client| client.allowed_ips())
+        .collect()
+}
+
+
+/// The statements a rule compiles to, one for each address family both of
+/// its groups have networks in
+fn rule_statements(configuration: &Configuration, rule: &Rule)
+    -> Vec<String> {
+
+    let from = group_nets(configuration, rule.from());
+    let to = group_nets(configuration, rule.to());
+
+    let ports = if rule.ports().is_empty() {
+        match rule.protocol() {
+            Protocol::Any => String::new(),
+            Protocol::Tcp => " meta l4proto tcp".to_string(),
+            Protocol::Udp => " meta l4proto udp".to_string()
+        }
+    }
+    else {
+        match rule.protocol() {
+            Protocol::Any =>
+                format!(" meta l4proto {{ tcp, udp }} th dport {}",
+                    set(rule.ports())),
+            Protocol::Tcp => format!(" tcp dport {}", set(rule.ports())),
+            Protocol::Udp => format!(" udp dport {}", set(rule.ports()))
+        }
+    };
+
+    let mut lines: Vec<String> = Vec::new();
+    for &(family, ipv4) in &[("ip", true), ("ip6", false)] {
+        let from: Vec<&IpNet> = from.iter()
+            .filter(|net| net.addr().is_ipv4() == ipv4)
+            .collect();
+        let to: Vec<&IpNet> = to.iter()
+            .filter(|net| net.addr().is_ipv4() == ipv4)
+            .collect();
+        if from.is_empty() || to.is_empty() {
+            continue;
+        }
+        lines.push(format!("        {} saddr {} {} daddr {}{} accept",
+            family, set(&from), family, set(&to), ports));
+    }
+    lines
+}
+
+
+/// An nftables ruleset for `nft -f`, which only lets clients reach each
+/// other through a router as the ACL allows
+pub fn nftables_ruleset(configuration: &Configuration, router: &Router)
+    -> Result<String> {
+
+    let interface = router.target().linux_interface();
+    let diagnostics = configuration.acl().validate(configuration);
+    if diagnostics.iter().any(|diagnostic| diagnostic.is_error()) {
+        bail!(ErrorKind::InvalidConfiguration(diagnostics));
+    }
+
+    // Creating the table first means deleting it can't fail on the first
+    // load, so the ruleset can be reloaded as it changes.
+    let mut lines: Vec<String> = vec![
+        "table inet wireguard_acl".to_string(),
+        "delete table inet wireguard_acl".to_string(),
+        "table inet wireguard_acl {".to_string(),
+        "    chain forward {".to_string(),
+        "        type filter hook forward priority 0; policy accept;"
+            .to_string(),
+        format!("        iifname \"{}\" oifname \"{}\" jump clients",
+            interface, interface),
+        "    }".to_string(),
+        "".to_string(),
+        "    chain clients {".to_string(),
+        "        ct state established,related accept".to_string()
+    ];
+    for rule in configuration.acl().rules() {
+        lines.extend(rule_statements(configuration, rule));
+    }
+    lines.push("        drop".to_string());
+    lines.push("    }".to_string());
+    lines.push("}".to_string());
+    Ok(lines.join("\n"))
+}
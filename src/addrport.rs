@@ -1,4 +1,7 @@
+use errors::{Error, ErrorKind, Result};
 use std::fmt;
+use std::net::Ipv6Addr;
+use std::str::FromStr;
 
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -11,7 +14,7 @@ impl AddrPort {
     pub fn new<A: Into<String>>(address: A, port: u16) -> AddrPort {
         AddrPort {
             address: address.into(),
-            port: port
+            port
         }
     }
 
@@ -26,7 +29,39 @@ impl AddrPort {
 
 
 impl fmt::Display for AddrPort {
-    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "{}:{}", self.address(), self.port())
+    /// `address:port`, with a bare IPv6 address put in brackets so it can't
+    /// run into the port
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.address().parse::<Ipv6Addr>().is_ok() {
+            write!(f, "[{}]:{}", self.address(), self.port())
+        }
+        else {
+            write!(f, "{}:{}", self.address(), self.port())
+        }
+    }
+}
+
+impl FromStr for AddrPort {
+    type Err = Error;
+
+    /// Parse `address:port`, where an IPv6 address is written in brackets
+    fn from_str(s: &str) -> Result<AddrPort> {
+        let invalid = || ErrorKind::AddressParse(s.to_string());
+
+        let split = s.rfind(':').ok_or_else(invalid)?;
+        let address = &s[..split];
+        let port: u16 = s[(split + 1)..].parse().map_err(|_| invalid())?;
+
+        if address.is_empty() {
+            bail!(invalid());
+        }
+        // Without brackets, an IPv6 address's last group can't be told
+        // apart from the port.
+        if address.contains(':')
+            && !(address.starts_with('[') && address.ends_with(']')) {
+            bail!(invalid());
+        }
+
+        Ok(AddrPort::new(address, port))
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,190 @@
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+
+/// The single-address network (`/32` or `/128`) for an address.
+pub fn host_net(address: &IpAddr) -> IpNet {
+    match *address {
+        IpAddr::V4(address) => Ipv4Net::new(address, 32).unwrap().into(),
+        IpAddr::V6(address) => Ipv6Net::new(address, 128).unwrap().into()
+    }
+}
+
+
+/// The first usable host address of a network, skipping the network
+/// address where the network is large enough to have one.
+pub fn first_host(net: &IpNet) -> IpAddr {
+    let (start, _) = range(net);
+    let start =
+        if net.prefix_len() + 1 < net.max_prefix_len() { start + 1 }
+        else { start };
+    make_net(net, start, net.max_prefix_len()).addr()
+}
+
+
+/// The first and last address of a network, as integers.
+fn range(net: &IpNet) -> (u128, u128) {
+    match *net {
+        IpNet::V4(ref net) =>
+            (u32::from(net.network()) as u128,
+             u32::from(net.broadcast()) as u128),
+        IpNet::V6(ref net) =>
+            (u128::from(net.network()), u128::from(net.broadcast()))
+    }
+}
+
+
+/// Build a network of the same family as `like`.
+fn make_net(like: &IpNet, address: u128, prefix_len: u8) -> IpNet {
+    match *like {
+        IpNet::V4(_) =>
+            Ipv4Net::new(Ipv4Addr::from(address as u32), prefix_len)
+                .unwrap()
+                .into(),
+        IpNet::V6(_) =>
+            Ipv6Net::new(Ipv6Addr::from(address), prefix_len)
+                .unwrap()
+                .into()
+    }
+}
+
+
+fn same_family(a: &IpNet, b: &IpNet) -> bool {
+    a.max_prefix_len() == b.max_prefix_len()
+}
+
+
+/// True if the two networks share at least one address.
+pub fn overlaps(a: &IpNet, b: &IpNet) -> bool {
+    if !same_family(a, b) {
+        return false;
+    }
+    let (a_start, a_end) = range(a);
+    let (b_start, b_end) = range(b);
+    a_start <= b_end && b_start <= a_end
+}
+
+
+/// Find the first `/prefix_len` network inside `within` which does not
+/// overlap any network in `used`.
+pub fn first_free(within: &IpNet, prefix_len: u8, used: &[IpNet])
+    -> Option<IpNet> {
+
+    if prefix_len < within.prefix_len() || prefix_len > within.max_prefix_len() {
+        return None;
+    }
+
+    let (mut start, end) = range(within);
+    let size: u128 = 1u128.checked_shl(
+        (within.max_prefix_len() - prefix_len) as u32).unwrap_or(0);
+
+    // Leave the network and broadcast addresses of `within` alone when
+    // handing out single addresses.
+    let (first, last) =
+        if prefix_len == within.max_prefix_len()
+            && within.prefix_len() + 1 < within.max_prefix_len() {
+            (start + 1, match *within {
+                IpNet::V4(_) => end - 1,
+                IpNet::V6(_) => end
+            })
+        }
+        else {
+            (start, end)
+        };
+    start = first;
+
+    loop {
+        let candidate_end = start.checked_add(size.wrapping_sub(1))?;
+        if candidate_end > last {
+            return None;
+        }
+
+        let candidate = make_net(within, start, prefix_len);
+
+        let collision = used.iter()
+            .filter(|net| overlaps(net, &candidate))
+            .map(|net| range(net).1)
+            .max();
+
+        match collision {
+            None => return Some(candidate),
+            Some(used_end) => {
+                // Skip past the colliding network, then realign.
+                let next = used_end.max(candidate_end).checked_add(1)?;
+                start = match size {
+                    0 | 1 => next,
+                    _ => next.checked_add(size - 1)? / size * size
+                };
+            }
+        }
+    }
+}
+
+
+/// The networks covered by `nets` but not by any network in `excluded`, as
+/// the fewest networks which don't overlap anything excluded.
+pub fn exclude(nets: &[IpNet], excluded: &[IpNet]) -> Vec<IpNet> {
+    let mut result: Vec<IpNet> = Vec::new();
+    let mut pending: Vec<IpNet> = nets.iter().rev().cloned().collect();
+
+    while let Some(net) = pending.pop() {
+        let (start, end) = range(&net);
+        let overlapping: Vec<&IpNet> = excluded.iter()
+            .filter(|excluded| overlaps(excluded, &net))
+            .collect();
+
+        if overlapping.is_empty() {
+            result.push(net);
+        }
+        else if overlapping.iter().all(|excluded| {
+            let (excluded_start, excluded_end) = range(excluded);
+            excluded_start > start || excluded_end < end
+        }) {
+            // Partially excluded, so split in half and try again. Push the
+            // upper half first to keep the result in address order.
+            let prefix_len = net.prefix_len() + 1;
+            let half = (end - start) / 2 + 1;
+            pending.push(make_net(&net, start + half, prefix_len));
+            pending.push(make_net(&net, start, prefix_len));
+        }
+    }
+
+    result
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nets(nets: &[&str]) -> Vec<IpNet> {
+        nets.iter().map(|net| net.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn exclude_nothing() {
+        assert_eq!(exclude(&nets(&["10.0.0.0/16"]), &nets(&["10.1.0.0/24"])),
+                   nets(&["10.0.0.0/16"]));
+        assert_eq!(exclude(&nets(&["10.0.0.0/16"]), &nets(&["fd00::/64"])),
+                   nets(&["10.0.0.0/16"]));
+    }
+
+    #[test]
+    fn exclude_everything() {
+        assert!(exclude(&nets(&["10.0.1.0/24"]), &nets(&["10.0.0.0/16"]))
+            .is_empty());
+    }
+
+    #[test]
+    fn exclude_splits() {
+        assert_eq!(exclude(&nets(&["10.0.0.0/22"]), &nets(&["10.0.1.0/24"])),
+                   nets(&["10.0.0.0/24", "10.0.2.0/23"]));
+        assert_eq!(exclude(&nets(&["0.0.0.0/0"]), &nets(&["128.0.0.0/1"])),
+                   nets(&["0.0.0.0/1"]));
+        assert_eq!(exclude(&nets(&["::/0"]), &nets(&["::/1", "c000::/2"])),
+                   nets(&["8000::/2"]));
+        assert_eq!(exclude(&nets(&["10.0.0.0/30"]),
+                           &nets(&["10.0.0.1/32", "10.0.0.2/32"])),
+                   nets(&["10.0.0.0/32", "10.0.0.3/32"]));
+    }
+}
@@ -1,77 +1,378 @@
-use endpoint::{EndPoint, Router};
+use acl::Acl;
+use chrono::{DateTime, Utc};
+use cidr::{exclude, first_free, host_net, overlaps};
+use diagnostic::Diagnostic;
+use endpoint::{EndPoint, Router, Tunnel};
+use errors::*;
+use group::Group;
+use interface::InterfaceSettings;
+use keys::{self, is_valid_key};
 use serde_yaml;
 use std::fs::File;
 use std::io::{Read, Write};
-use ipnet::Ipv4Net;
+use ipnet::{Contains, IpNet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
 use std::path::Path;
+use std::str::FromStr;
+use target::is_valid_interface_name;
+
+/// How clients connect to each other
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Topology {
+    /// Every client only peers with the router
+    #[default]
+    HubAndSpoke,
+    /// Clients also peer directly with each other, wherever at least one of
+    /// the two has an external address
+    Mesh
+}
+
+
+impl Topology {
+    pub fn is_hub_and_spoke(&self) -> bool {
+        *self == Topology::HubAndSpoke
+    }
+}
+
+
+impl FromStr for Topology {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Topology> {
+        match s {
+            "hub-and-spoke" => Ok(Topology::HubAndSpoke),
+            "mesh" => Ok(Topology::Mesh),
+            _ => bail!(ErrorKind::InvalidArgument("topology".to_string(),
+                                                  s.to_string()))
+        }
+    }
+}
+
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Configuration {
-    master_subnet: Option<Ipv4Net>,
-    router: Router,
-    clients: Vec<EndPoint>
+    #[serde(rename = "master_subnet", with = "::one_or_many", default,
+            skip_serializing_if = "Vec::is_empty")]
+    master_subnets: Vec<IpNet>,
+    #[serde(default, skip_serializing_if = "Topology::is_hub_and_spoke")]
+    topology: Topology,
+    /// Configurations written before there could be more than one router
+    /// have a single `router` instead
+    #[serde(alias = "router", deserialize_with = "::one_or_many::deserialize")]
+    routers: Vec<Router>,
+    clients: Vec<EndPoint>,
+    /// Interface settings for every client which doesn't set its own
+    #[serde(default, skip_serializing_if = "InterfaceSettings::is_empty")]
+    client_interface: InterfaceSettings,
+    /// Settings for the members of each group
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    groups: BTreeMap<String, Group>,
+    /// Which clients may reach each other through the routers
+    #[serde(default, skip_serializing_if = "Acl::is_empty")]
+    acl: Acl
 }
 
 
 impl Configuration {
-    pub fn open(path: &Path) -> Configuration {
+    pub fn open(path: &Path) -> Result<Configuration> {
         let mut file = File::open(path)
-            .expect(&format!("Failed to open {:?}", path));
+            .map_err(|e| ErrorKind::Io(path.to_path_buf(), e))?;
 
         let mut buffer: String = String::new();
         file.read_to_string(&mut buffer)
-            .expect("Failed to read configuration file");
+            .map_err(|e| ErrorKind::Io(path.to_path_buf(), e))?;
 
-        match serde_yaml::from_str(&buffer) {
-            Ok(configuration) => configuration,
-            Err(e) => {
-                eprintln!("Failed to parse configuration");
-                panic!("{}", e);
-            }
-        }
+        serde_yaml::from_str(&buffer)
+            .map_err(|e| {
+                let location = e.location();
+                let mut message = e.to_string();
+                // The location is shown before the message instead.
+                if let Some(ref location) = location {
+                    let suffix = format!(" at line {} column {}",
+                        location.line(), location.column());
+                    if message.ends_with(&suffix) {
+                        let len = message.len() - suffix.len();
+                        message.truncate(len);
+                    }
+                }
+                ErrorKind::Yaml(
+                    path.to_path_buf(),
+                    message,
+                    location.as_ref().map(|location| location.line()),
+                    location.as_ref().map(|location| location.column())
+                ).into()
+            })
     }
 
-    pub fn save(&self, path: &Path) {
-        let mut file = File::create(path)
-            .expect(&format!("Failed to open {:?}", path));
+    /// Validate, then save this configuration. Nothing is written if
+    /// validation finds any errors, and every diagnostic is returned in
+    /// `ErrorKind::InvalidConfiguration` instead.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        // Expiry only ever warns, so the time can't stop a save.
+        let diagnostics = self.validate(&Utc::now());
+        if diagnostics.iter().any(|diagnostic| diagnostic.is_error()) {
+            bail!(ErrorKind::InvalidConfiguration(diagnostics));
+        }
 
         let bytes = serde_yaml::to_string(&self)
-            .expect("Failed to serialize configuration");
+            .map_err(|e| ErrorKind::Yaml(path.to_path_buf(), e.to_string(),
+                                         None, None))?;
+
+        let mut file = File::create(path)
+            .map_err(|e| ErrorKind::Io(path.to_path_buf(), e))?;
 
         file.write_all(bytes.as_bytes())
-            .expect("Failed to write configuration file");
+            .map_err(|e| ErrorKind::Io(path.to_path_buf(), e))?;
+
+        Ok(())
     }
 
     pub fn new(router: Router) -> Configuration {
         Configuration {
-            master_subnet: None,
-            router: router,
-            clients: Vec::new()
+            master_subnets: Vec::new(),
+            topology: Topology::HubAndSpoke,
+            routers: vec![router],
+            clients: Vec::new(),
+            client_interface: InterfaceSettings::new(),
+            groups: BTreeMap::new(),
+            acl: Acl::new()
         }
     }
 
-    pub fn push_client(&mut self, client: EndPoint) {
+    pub fn push_client(&mut self, client: EndPoint) -> Result<()> {
+        if self.client_by_name(client.name()).is_some() {
+            bail!(ErrorKind::ClientExists(client.name().to_string()));
+        }
         self.clients.push(client);
+        Ok(())
+    }
+
+    pub fn push_router(&mut self, router: Router) -> Result<()> {
+        if self.router_by_name(router.name()).is_some() {
+            bail!(ErrorKind::RouterExists(router.name().to_string()));
+        }
+        self.routers.push(router);
+        Ok(())
     }
 
-    pub fn set_master_subnet(&mut self, master_subnet: Option<Ipv4Net>) {
-        self.master_subnet = master_subnet;
+    /// Remove a router. The last router can't be removed.
+    pub fn remove_router_by_name(&mut self, name: &str) -> Result<Router> {
+        let i = self.routers.iter()
+            .position(|router| router.name() == name)
+            .ok_or_else(|| ErrorKind::RouterNotFound(name.to_string()))?;
+        if self.routers.len() == 1 {
+            bail!("can not remove the only router");
+        }
+        Ok(self.routers.remove(i))
     }
 
-    pub fn remove_client_by_name(&mut self, name: &str) -> bool {
+    /// Set the master subnets, one per address family in use
+    pub fn set_master_subnets(&mut self, master_subnets: Vec<IpNet>) {
+        self.master_subnets = master_subnets;
+    }
+
+    pub fn remove_client_by_name(&mut self, name: &str) -> Result<EndPoint> {
         for i in 0..self.clients.len() {
             if self.clients[i].name() == name {
-                self.clients.remove(i);
-                return true;
+                self.acl.remove_client(name);
+                return Ok(self.clients.remove(i));
+            }
+        }
+        bail!(ErrorKind::ClientNotFound(name.to_string()))
+    }
+
+    pub fn set_topology(&mut self, topology: Topology) {
+        self.topology = topology;
+    }
+
+    pub fn master_subnets(&self) -> &[IpNet] { &self.master_subnets }
+    pub fn topology(&self) -> Topology { self.topology }
+    pub fn routers(&self) -> &[Router] { &self.routers }
+    pub fn acl(&self) -> &Acl { &self.acl }
+    pub fn acl_mut(&mut self) -> &mut Acl { &mut self.acl }
+
+    pub fn router_by_name(&self, name: &str) -> Option<&Router> {
+        self.routers
+            .iter()
+            .find(|router| router.name() == name)
+    }
+
+    pub fn router_by_name_mut(&mut self, name: &str) -> Option<&mut Router> {
+        self.routers
+            .iter_mut()
+            .find(|router| router.name() == name)
+    }
+
+    /// Like `router_by_name`, but a missing router is an error
+    pub fn router(&self, name: &str) -> Result<&Router> {
+        self.router_by_name(name)
+            .ok_or_else(|| ErrorKind::RouterNotFound(name.to_string()).into())
+    }
+
+    /// Like `router_by_name_mut`, but a missing router is an error
+    pub fn router_mut(&mut self, name: &str) -> Result<&mut Router> {
+        self.router_by_name_mut(name)
+            .ok_or_else(|| ErrorKind::RouterNotFound(name.to_string()).into())
+    }
+
+    /// The router a client routes through, which is the first router unless
+    /// the client names another
+    pub fn primary_router(&self, client: &EndPoint) -> Result<&Router> {
+        match client.primary_router() {
+            Some(name) => self.router(name),
+            None => Ok(&self.routers[0])
+        }
+    }
+
+    /// The router a client can fail over to, if it has one
+    pub fn secondary_router(&self, client: &EndPoint)
+        -> Result<Option<&Router>> {
+
+        match client.secondary_router() {
+            Some(name) => Ok(Some(self.router(name)?)),
+            None => Ok(None)
+        }
+    }
+
+    /// The clients which use a router as either their primary or secondary.
+    /// Disabled and expired clients are left out.
+    pub fn router_clients(&self, router: &Router, now: &DateTime<Utc>)
+        -> Vec<&EndPoint> {
+
+        self.clients()
+            .iter()
+            .filter(|client| client.is_active(now))
+            .filter(|client| {
+                let primary = client.primary_router()
+                    .unwrap_or_else(|| self.routers[0].name());
+                primary == router.name()
+                    || client.secondary_router() == Some(router.name())
+            })
+            .collect()
+    }
+
+    /// Swap a client's primary and secondary routers, so its configs route
+    /// everything through the secondary
+    pub fn fail_over(&mut self, name: &str) -> Result<()> {
+        let primary = {
+            let client = self.client(name)?;
+            if client.secondary_router().is_none() {
+                bail!("client {} has no secondary router", name);
+            }
+            self.primary_router(client)?.name().to_string()
+        };
+
+        let client = self.client_mut(name)?;
+        let secondary = client.secondary_router().map(|s| s.to_string());
+        client.set_primary_router(secondary);
+        client.set_secondary_router(Some(primary));
+        Ok(())
+    }
+
+    /// Interface settings for every client which doesn't set its own
+    pub fn client_defaults(&self) -> &InterfaceSettings {
+        &self.client_interface
+    }
+    pub fn client_defaults_mut(&mut self) -> &mut InterfaceSettings {
+        &mut self.client_interface
+    }
+
+    pub fn groups(&self) -> &BTreeMap<String, Group> { &self.groups }
+
+    pub fn group(&self, name: &str) -> Option<&Group> {
+        self.groups.get(name)
+    }
+
+    /// The settings for a group, which are created if the group has none yet
+    pub fn group_mut(&mut self, name: &str) -> &mut Group {
+        self.groups.entry(name.to_string()).or_default()
+    }
+
+    /// Drop a group's settings, and take every client out of it, including
+    /// those listed under it in the acl. A group which acl rules use can't
+    /// be removed until the rules are.
+    pub fn remove_group(&mut self, name: &str) -> Result<()> {
+        if self.acl().rules().iter()
+            .any(|rule| rule.from() == name || rule.to() == name) {
+            bail!("group {} is used by acl rules", name);
+        }
+
+        self.groups.remove(name);
+        self.acl.remove_group(name);
+        for client in &mut self.clients {
+            let groups = client.groups()
+                .iter()
+                .filter(|group| *group != name)
+                .cloned()
+                .collect();
+            client.set_groups(groups);
+        }
+        Ok(())
+    }
+
+    /// Whether anything refers to a group: group settings, a client in it,
+    /// or an acl group
+    pub fn has_group(&self, name: &str) -> bool {
+        self.groups.contains_key(name)
+            || self.acl().group(name).is_some()
+            || self.clients().iter().any(|client| client.in_group(name))
+    }
+
+    /// Whether a client is in a group, either as one of its own groups or
+    /// listed under the group in the acl
+    pub fn in_group(&self, client: &EndPoint, name: &str) -> bool {
+        client.in_group(name)
+            || self.acl().group(name).unwrap_or(&[])
+                .iter()
+                .any(|listed| listed == client.name())
+    }
+
+    /// The names of the groups a client is in: its own, in order, then the
+    /// acl groups which list it
+    pub fn client_group_names<'a>(&'a self, client: &'a EndPoint)
+        -> Vec<&'a str> {
+
+        let mut names: Vec<&str> = client.groups()
+            .iter()
+            .map(|group| group.as_str())
+            .collect();
+        for (group, listed) in self.acl().groups() {
+            if !client.in_group(group)
+                && listed.iter().any(|listed| listed == client.name()) {
+                names.push(group);
             }
         }
-        false
+        names
+    }
+
+    /// The clients in a group
+    pub fn group_members(&self, name: &str) -> Vec<&EndPoint> {
+        self.clients()
+            .iter()
+            .filter(|client| self.in_group(client, name))
+            .collect()
     }
 
-    pub fn master_subnet(&self) -> Option<&Ipv4Net> {
-        self.master_subnet.as_ref() 
+    /// The settings of each group a client is in, in the order of
+    /// `client_group_names`
+    fn client_groups(&self, client: &EndPoint) -> Vec<&Group> {
+        self.client_group_names(client)
+            .iter()
+            .filter_map(|group| self.group(group))
+            .collect()
+    }
+
+    /// The interface settings for a client, falling back to those of its
+    /// groups, in order, then to the configuration-wide client defaults
+    pub fn client_settings(&self, client: &EndPoint) -> InterfaceSettings {
+        let mut settings = client.settings().clone();
+        for group in self.client_groups(client) {
+            settings = settings.or(group.settings());
+        }
+        settings.or(self.client_defaults())
     }
-    pub fn router(&self) -> &Router { &self.router }
     pub fn clients(&self) -> &[EndPoint] { &self.clients }
 
     pub fn client_by_name(&self, name: &str) -> Option<&EndPoint> {
@@ -80,22 +381,560 @@ impl Configuration {
             .find(|client| client.name() == name)
     }
 
-    pub fn all_allowed_ips(&self) -> Vec<Ipv4Net> {
-        match self.master_subnet() {
-            Some(master_subnet) => vec![master_subnet.clone()],
-            None =>
-                self.clients()
-                    .iter()
-                    .flat_map(|client| client.allowed_ips())
-                    .collect::<Vec<Ipv4Net>>()
+    pub fn client_by_name_mut(&mut self, name: &str) -> Option<&mut EndPoint> {
+        self.clients
+            .iter_mut()
+            .find(|client| client.name() == name)
+    }
+
+    /// The clients which have expired by `now`
+    pub fn expired_clients(&self, now: &DateTime<Utc>) -> Vec<&EndPoint> {
+        self.clients()
+            .iter()
+            .filter(|client| client.is_expired(now))
+            .collect()
+    }
+
+    /// Remove every client which has expired by `now`, or with `disable`,
+    /// disable them instead. Returns the clients as they were before.
+    pub fn prune(&mut self, now: &DateTime<Utc>, disable: bool)
+        -> Vec<EndPoint> {
+
+        let pruned: Vec<EndPoint> = self.expired_clients(now)
+            .into_iter()
+            .filter(|client| !(disable && client.is_disabled()))
+            .cloned()
+            .collect();
+
+        for client in &pruned {
+            if disable {
+                self.client_by_name_mut(client.name()).unwrap()
+                    .set_disabled(true);
+            }
+            else {
+                self.remove_client_by_name(client.name()).unwrap();
+            }
         }
+        pruned
     }
 
-    pub fn client_config(&self, name: &str) -> Option<String> {
-        let client = self.client_by_name(name)?;
+    /// Like `client_by_name`, but a missing client is an error
+    pub fn client(&self, name: &str) -> Result<&EndPoint> {
+        self.client_by_name(name)
+            .ok_or_else(|| ErrorKind::ClientNotFound(name.to_string()).into())
+    }
+
+    /// Like `client_by_name_mut`, but a missing client is an error
+    pub fn client_mut(&mut self, name: &str) -> Result<&mut EndPoint> {
+        self.client_by_name_mut(name)
+            .ok_or_else(|| ErrorKind::ClientNotFound(name.to_string()).into())
+    }
+
+    pub fn all_allowed_ips(&self) -> Vec<IpNet> {
+        if !self.master_subnets().is_empty() {
+            self.master_subnets().to_vec()
+        }
+        else {
+            self.clients()
+                .iter()
+                .flat_map(|client| client.allowed_ips())
+                .collect::<Vec<IpNet>>()
+        }
+    }
 
-        Some(format!("{}\n\n{}",
-            client.interface(),
-            self.router.peer(client, &self.all_allowed_ips())))
+    /// Every network a client routes over wireguard: the wireguard network
+    /// and its groups' routes for a split tunnel, or everything for a full
+    /// tunnel, less the client's excluded routes.
+    pub fn client_allowed_ips(&self, client: &EndPoint) -> Vec<IpNet> {
+        let allowed_ips = match client.tunnel() {
+            Tunnel::Split => {
+                let mut allowed_ips = self.all_allowed_ips();
+                for group in self.client_groups(client) {
+                    for route in group.routes() {
+                        if !allowed_ips.contains(route) {
+                            allowed_ips.push(*route);
+                        }
+                    }
+                }
+                allowed_ips
+            },
+            Tunnel::Full => vec!["0.0.0.0/0".parse().unwrap(),
+                                 "::/0".parse().unwrap()]
+        };
+        exclude(&allowed_ips, client.excluded_routes())
     }
-}
\ No newline at end of file
+
+    /// Every network already claimed by router addresses, client
+    /// addresses, or client allowed ips.
+    pub fn used_networks(&self) -> Vec<IpNet> {
+        let mut used: Vec<IpNet> = self.routers()
+            .iter()
+            .flat_map(|router| router.internal_addresses())
+            .map(host_net)
+            .collect();
+
+        for client in self.clients() {
+            used.extend(client.internal_addresses().iter().map(host_net));
+            used.extend(client.allowed_ips());
+        }
+
+        used
+    }
+
+    /// Find the next free host address in `master_subnet`.
+    pub fn allocate_address(&self, master_subnet: &IpNet) -> Result<IpAddr> {
+        Ok(self.allocate_subnet(master_subnet, master_subnet.max_prefix_len())?
+            .addr())
+    }
+
+    /// Find the next free `/prefix_len` subnet in `master_subnet`, for
+    /// clients which route a whole site.
+    pub fn allocate_subnet(&self, master_subnet: &IpNet, prefix_len: u8)
+        -> Result<IpNet> {
+
+        first_free(master_subnet, prefix_len, &self.used_networks())
+            .ok_or_else(||
+                ErrorKind::NoFreeAddress(*master_subnet, prefix_len).into())
+    }
+
+    /// Check this configuration for mistakes which would otherwise only
+    /// show up as broken routing once deployed. Clients are checked for
+    /// expiry as of `now`.
+    pub fn validate(&self, now: &DateTime<Utc>) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+
+        let mut names: HashSet<&str> = HashSet::new();
+        let mut public_keys: HashMap<&str, &str> = HashMap::new();
+
+        if self.routers().is_empty() {
+            diagnostics.push(Diagnostic::error(None, "routers",
+                "there are no routers"));
+        }
+
+        for router in self.routers() {
+            let prefix = format!("routers.{}.", router.name());
+
+            if !names.insert(router.name()) {
+                diagnostics.push(Diagnostic::error(None,
+                    format!("{}name", prefix), "name is used more than once"));
+            }
+
+            validate_keys(&mut diagnostics, None, &prefix,
+                Some(router.private_key()), router.public_key(), None);
+
+            if let Some(owner) = public_keys.insert(router.public_key(),
+                                                    router.name()) {
+                diagnostics.push(Diagnostic::error(None,
+                    format!("{}public_key", prefix),
+                    format!("public key is also used by {}", owner)));
+            }
+
+            if router.internal_addresses().is_empty() {
+                diagnostics.push(Diagnostic::error(None,
+                    format!("{}internal_address", prefix),
+                    "router has no internal address"));
+            }
+            self.validate_in_master_subnet(&mut diagnostics, None,
+                &format!("{}internal_address", prefix),
+                router.internal_addresses());
+
+            diagnostics.extend(router.target().validate(None, &prefix));
+            if let Some(egress) = router.forwarding().egress_interface() {
+                if !is_valid_interface_name(egress) {
+                    diagnostics.push(Diagnostic::error(None,
+                        format!("{}forwarding.egress_interface", prefix),
+                        format!("{} is not a valid interface name", egress)));
+                }
+            }
+        }
+
+        for client in self.clients() {
+            let name = Some(client.name());
+
+            if !names.insert(client.name()) {
+                diagnostics.push(Diagnostic::error(name, "name",
+                    "name is used more than once"));
+            }
+
+            validate_keys(&mut diagnostics, name, "", client.private_key(),
+                client.public_key(), client.preshared_key());
+
+            if let Some(owner) = public_keys.insert(client.public_key(),
+                                                    client.name()) {
+                diagnostics.push(Diagnostic::error(name, "public_key",
+                    format!("public key is also used by {}", owner)));
+            }
+
+            for &(field, router) in &[("primary_router",
+                                       client.primary_router()),
+                                      ("secondary_router",
+                                       client.secondary_router())] {
+                if let Some(router) = router {
+                    if self.router_by_name(router).is_none() {
+                        diagnostics.push(Diagnostic::error(name, field,
+                            format!("there is no router {}", router)));
+                    }
+                }
+            }
+            if let (Ok(primary), Some(secondary)) =
+                    (self.primary_router(client), client.secondary_router()) {
+                if primary.name() == secondary {
+                    diagnostics.push(Diagnostic::error(name,
+                        "secondary_router",
+                        "secondary router is also the primary router"));
+                }
+            }
+
+            if client.internal_addresses().is_empty() {
+                diagnostics.push(Diagnostic::error(name, "internal_address",
+                    "client has no internal address"));
+            }
+
+            if client.is_disabled() {
+                diagnostics.push(Diagnostic::warning(name, "disabled",
+                    "client is disabled, and left out of router configs"));
+            }
+            else if client.is_expired(now) {
+                diagnostics.push(Diagnostic::warning(name, "expires_at",
+                    format!("client expired at {}, and is left out of router \
+                             configs", client.expires_at().unwrap())));
+            }
+
+            if let Ok(router) = self.primary_router(client) {
+                for excluded in client.excluded_routes() {
+                    for address in router.internal_addresses() {
+                        if excluded.contains(address) {
+                            diagnostics.push(Diagnostic::warning(name,
+                                "excluded_routes",
+                                format!("{} covers address {} of router {}, \
+                                         which will not be reachable",
+                                    excluded, address, router.name())));
+                        }
+                    }
+                }
+            }
+            self.validate_in_master_subnet(&mut diagnostics, name,
+                "internal_address", client.internal_addresses());
+            diagnostics.extend(client.target().validate(name, ""));
+
+            let allowed_ips = client.allowed_ips();
+            for address in client.internal_addresses() {
+                if !allowed_ips.iter().any(|ip| ip.contains(address)) {
+                    diagnostics.push(Diagnostic::warning(name,
+                        "allowed_ips",
+                        format!("internal address {} is not covered by \
+                                 allowed_ips, and will not be routed",
+                            address)));
+                }
+            }
+
+            if !self.master_subnets().is_empty() {
+                for allowed_ip in client.allowed_ips() {
+                    if !self.master_subnets().iter()
+                            .any(|subnet| subnet.contains(&allowed_ip)) {
+                        diagnostics.push(Diagnostic::warning(name,
+                            "allowed_ips",
+                            format!("{} is outside master_subnet, and will \
+                                     not be routed to other clients",
+                                allowed_ip)));
+                    }
+                }
+            }
+        }
+
+        let clients = self.clients();
+        for (i, client) in clients.iter().enumerate() {
+            for other in &clients[..i] {
+                for allowed_ip in client.allowed_ips() {
+                    for other_ip in other.allowed_ips() {
+                        if overlaps(&allowed_ip, &other_ip) {
+                            diagnostics.push(Diagnostic::error(
+                                Some(client.name()), "allowed_ips",
+                                format!("{} overlaps {} of client {}",
+                                    allowed_ip, other_ip, other.name())));
+                        }
+                    }
+                }
+            }
+        }
+
+        diagnostics.extend(self.acl().validate(self));
+
+        diagnostics
+    }
+
+    fn validate_in_master_subnet(
+        &self,
+        diagnostics: &mut Vec<Diagnostic>,
+        client: Option<&str>,
+        field: &str,
+        addresses: &[IpAddr]
+    ) {
+        if self.master_subnets().is_empty() {
+            return;
+        }
+
+        for address in addresses {
+            if !self.master_subnets().iter()
+                    .any(|subnet| subnet.contains(address)) {
+                diagnostics.push(Diagnostic::error(client, field,
+                    format!("{} is outside master_subnet", address)));
+            }
+        }
+    }
+
+    /// The networks `router` routes to `other`: the other router's own
+    /// addresses, and those of clients which use the other router as their
+    /// primary and don't also use this one.
+    fn router_allowed_ips(&self, router: &Router, other: &Router,
+                          now: &DateTime<Utc>) -> Vec<IpNet> {
+
+        let mut allowed_ips: Vec<IpNet> = other.internal_addresses()
+            .iter()
+            .map(host_net)
+            .collect();
+
+        for client in self.router_clients(other, now) {
+            if self.primary_router(client).ok().map(|r| r.name())
+                    == Some(other.name())
+                && client.secondary_router() != Some(router.name()) {
+                allowed_ips.extend(client.allowed_ips());
+            }
+        }
+
+        allowed_ips
+    }
+
+    /// The `[Peer]` sections of a router's config, for each of its clients,
+    /// and for every other router
+    fn router_peers(&self, router: &Router, now: &DateTime<Utc>)
+        -> Vec<String> {
+
+        let mut sections: Vec<String> = self.router_clients(router, now)
+            .iter()
+            .map(|client| client.peer())
+            .collect();
+        sections.extend(self.routers()
+            .iter()
+            .filter(|other| other.name() != router.name())
+            .map(|other| other.router_peer(
+                &self.router_allowed_ips(router, other, now))));
+        sections
+    }
+
+    /// Every network a router routes over wireguard
+    pub fn router_routes(&self, router: &Router, now: &DateTime<Utc>)
+        -> Vec<IpNet> {
+
+        let mut routes: Vec<IpNet> = self.router_clients(router, now)
+            .iter()
+            .flat_map(|client| client.allowed_ips())
+            .collect();
+        for other in self.routers() {
+            if other.name() != router.name() {
+                routes.extend(self.router_allowed_ips(router, other, now));
+            }
+        }
+        routes
+    }
+
+    /// The wireguard config for the named router, with a peer for each of
+    /// its clients, and for every other router
+    pub fn router_config(&self, name: &str, now: &DateTime<Utc>)
+        -> Result<String> {
+
+        let router = self.router(name)?;
+        let mut sections = vec![router.interface()];
+        sections.extend(self.router_peers(router, now));
+        Ok(sections.join("\n"))
+    }
+
+    /// The clients which `client` peers with directly, rather than through
+    /// the router. This is always empty unless the topology is mesh, and
+    /// never includes disabled or expired clients.
+    pub fn direct_peers(&self, client: &EndPoint, now: &DateTime<Utc>)
+        -> Vec<&EndPoint> {
+
+        if self.topology() != Topology::Mesh {
+            return Vec::new();
+        }
+
+        self.clients()
+            .iter()
+            .filter(|other| other.name() != client.name())
+            .filter(|other| other.is_active(now))
+            .filter(|other| other.external_address().is_some()
+                || client.external_address().is_some())
+            .collect()
+    }
+
+    /// The `[Peer]` sections of a client's config. The primary router is
+    /// routed everything which isn't routed to a direct peer, or to the
+    /// secondary router's own addresses. Wireguard can't route the same
+    /// network to two peers, so failing over means swapping the two routers
+    /// with `fail_over`.
+    fn client_peers(&self, client: &EndPoint, now: &DateTime<Utc>)
+        -> Result<String> {
+
+        let primary = self.primary_router(client)?;
+        let secondary = self.secondary_router(client)?;
+
+        let direct_peers = self.direct_peers(client, now);
+        let mut excluded: Vec<IpNet> = direct_peers.iter()
+            .flat_map(|peer| peer.allowed_ips())
+            .collect();
+        let secondary_allowed_ips: Vec<IpNet> = secondary.iter()
+            .flat_map(|router| router.internal_addresses())
+            .map(host_net)
+            .collect();
+        excluded.extend(secondary_allowed_ips.iter().cloned());
+
+        let mut sections = vec![primary.peer(client,
+            &exclude(&self.client_allowed_ips(client), &excluded))];
+        if let Some(secondary) = secondary {
+            sections.push(secondary.peer(client, &secondary_allowed_ips));
+        }
+        sections.extend(direct_peers.iter()
+            .map(|peer| peer.mesh_peer(client)));
+        Ok(sections.join("\n"))
+    }
+
+    /// The wireguard config for the named client
+    pub fn client_config(&self, name: &str, now: &DateTime<Utc>)
+        -> Result<String> {
+
+        let client = self.client(name)?;
+        Ok(format!("{}\n\n{}", client.interface(),
+            self.client_peers(client, now)?))
+    }
+
+    /// The config for the named router for `wg-quick`
+    pub fn router_wg_quick_config(&self, name: &str, now: &DateTime<Utc>)
+        -> Result<String> {
+
+        let router = self.router(name)?;
+        let mut sections = vec![router.wg_quick_interface()];
+        sections.extend(self.router_peers(router, now));
+        Ok(sections.join("\n"))
+    }
+
+    /// The config for the named client for `wg-quick`, which the wireguard
+    /// mobile apps also import
+    pub fn client_wg_quick_config(&self, name: &str, now: &DateTime<Utc>)
+        -> Result<String> {
+
+        let client = self.client(name)?;
+        Ok(format!("{}\n\n{}",
+            client.wg_quick_interface(&self.client_settings(client)),
+            self.client_peers(client, now)?))
+    }
+}
+
+fn validate_keys(
+    diagnostics: &mut Vec<Diagnostic>,
+    client: Option<&str>,
+    prefix: &str,
+    private_key: Option<&str>,
+    public_key: &str,
+    preshared_key: Option<&str>
+) {
+    if !is_valid_key(public_key) {
+        diagnostics.push(Diagnostic::error(client,
+            format!("{}public_key", prefix), "malformed base64 key"));
+    }
+
+    if let Some(private_key) = private_key {
+        match keys::public_key(private_key) {
+            Err(_) => diagnostics.push(Diagnostic::error(client,
+                format!("{}private_key", prefix), "malformed base64 key")),
+            Ok(ref derived) if derived != public_key =>
+                diagnostics.push(Diagnostic::error(client,
+                    format!("{}public_key", prefix),
+                    "public key does not match private key")),
+            Ok(_) => {}
+        }
+    }
+
+    if let Some(preshared_key) = preshared_key {
+        if !is_valid_key(preshared_key) {
+            diagnostics.push(Diagnostic::error(client,
+                format!("{}preshared_key", prefix), "malformed base64 key"));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use addrport::AddrPort;
+
+    fn configuration() -> Configuration {
+        let router = Router::new("router", "10.0.0.1".parse().unwrap(),
+            AddrPort::new("vpn.com", 47654)).unwrap();
+        let mut configuration = Configuration::new(router);
+        configuration.set_master_subnets(vec![
+            "10.0.0.0/16".parse().unwrap(),
+            "fd00::/48".parse().unwrap()
+        ]);
+        configuration.push_client(
+            EndPoint::new("a", "10.0.0.2".parse().unwrap()).unwrap()).unwrap();
+        configuration.push_client(
+            EndPoint::new("b", "10.0.1.1".parse().unwrap()).unwrap()
+                .builder_push_allowed_ips("10.0.1.0/24".parse().unwrap()))
+            .unwrap();
+        configuration
+    }
+
+    #[test]
+    fn allocate_address_skips_used() {
+        let configuration = configuration();
+        let master_subnets = configuration.master_subnets();
+        assert_eq!(configuration.allocate_address(&master_subnets[0]).unwrap(),
+                   "10.0.0.3".parse::<IpAddr>().unwrap());
+        assert_eq!(configuration.allocate_address(&master_subnets[1]).unwrap(),
+                   "fd00::1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn allocate_subnet_skips_used() {
+        let configuration = configuration();
+        let master_subnet = configuration.master_subnets()[0];
+        assert_eq!(configuration.allocate_subnet(&master_subnet, 24).unwrap(),
+                   "10.0.2.0/24".parse::<IpNet>().unwrap());
+        assert!(configuration.allocate_subnet(&master_subnet, 8).is_err());
+    }
+
+    #[test]
+    fn allocate_address_exhausted() {
+        let mut configuration = configuration();
+        configuration.set_master_subnets(vec!["10.0.0.0/30".parse().unwrap()]);
+        let master_subnet = configuration.master_subnets()[0];
+        assert!(configuration.allocate_address(&master_subnet).is_err());
+    }
+
+    #[test]
+    fn validate_clean() {
+        assert!(configuration().validate(&Utc::now()).is_empty());
+    }
+
+    #[test]
+    fn validate_finds_problems() {
+        let mut configuration = configuration();
+        let mut duplicate =
+            EndPoint::new("a", "10.0.1.2".parse().unwrap()).unwrap();
+        duplicate.set_public_key("not a key".to_string());
+        // push_client refuses duplicate names, so go around it.
+        configuration.clients.push(duplicate);
+
+        let diagnostics = configuration.validate(&Utc::now());
+        let fields: Vec<(Option<&str>, &str)> = diagnostics.iter()
+            .map(|diagnostic| (diagnostic.client(), diagnostic.field()))
+            .collect();
+
+        assert!(diagnostics.iter().all(|diagnostic| diagnostic.is_error()));
+        assert!(fields.contains(&(Some("a"), "name")));
+        assert!(fields.contains(&(Some("a"), "public_key")));
+        assert!(fields.contains(&(Some("a"), "allowed_ips")));
+    }
+}
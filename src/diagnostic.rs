@@ -0,0 +1,83 @@
+use std::fmt;
+
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning
+}
+
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning")
+        }
+    }
+}
+
+
+/// A single problem found while validating a configuration.
+///
+/// `client` is `None` for problems with the router or the configuration as
+/// a whole, and `field` is the yaml field the problem was found in.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Diagnostic {
+    severity: Severity,
+    client: Option<String>,
+    field: String,
+    message: String
+}
+
+
+impl Diagnostic {
+    pub fn new<F: Into<String>, M: Into<String>>(
+        severity: Severity,
+        client: Option<&str>,
+        field: F,
+        message: M
+    ) -> Diagnostic {
+        Diagnostic {
+            severity,
+            client: client.map(|client| client.to_string()),
+            field: field.into(),
+            message: message.into()
+        }
+    }
+
+    pub fn error<F: Into<String>, M: Into<String>>(
+        client: Option<&str>,
+        field: F,
+        message: M
+    ) -> Diagnostic {
+        Diagnostic::new(Severity::Error, client, field, message)
+    }
+
+    pub fn warning<F: Into<String>, M: Into<String>>(
+        client: Option<&str>,
+        field: F,
+        message: M
+    ) -> Diagnostic {
+        Diagnostic::new(Severity::Warning, client, field, message)
+    }
+
+    pub fn severity(&self) -> Severity { self.severity }
+    pub fn client(&self) -> Option<&str> { self.client.as_deref() }
+    pub fn field(&self) -> &str { &self.field }
+    pub fn message(&self) -> &str { &self.message }
+
+    pub fn is_error(&self) -> bool { self.severity == Severity::Error }
+}
+
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self.client() {
+            Some(client) => write!(f, "{}: client {}: {}: {}",
+                self.severity(), client, self.field(), self.message()),
+            None => write!(f, "{}: {}: {}",
+                self.severity(), self.field(), self.message())
+        }
+    }
+}
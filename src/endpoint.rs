@@ -1,48 +1,14 @@
 use addrport::AddrPort;
-use ipnet::Ipv4Net;
-use std::net::Ipv4Addr;
-use std::io::Write;
-use std::process::{Command, Stdio};
-
-
-fn gen_keys() -> (String, String) {
-    let output = Command::new("wg")
-        .args(&["genkey"])
-        .output()
-        .expect("Failed to execute wg genkey");
-
-    let privkey =
-        String::from_utf8(output.stdout).unwrap()
-            .trim()
-            .trim_left()
-            .to_string();
-
-    let mut command = Command::new("wg")
-        .args(&["pubkey"])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .expect("Failed to spawn wg pubkey");
-
-    command.stdin
-        .as_mut()
-        .expect("Failed to get stdin for wg pubkey")
-        .write_all(privkey.as_bytes())
-        .expect("Failed to write privkey to wg pubkey");
-
-    let output =
-        command.wait_with_output()
-            .expect("Failed to get output for wg pubkey");
-
-    let pubkey =
-        String::from_utf8(output.stdout).unwrap()
-            .trim()
-            .trim_left()
-            .to_string();
-
-    (privkey, pubkey)
-}
-
+use chrono::{DateTime, Utc};
+use cidr::host_net;
+use errors::*;
+use firewall::Forwarding;
+use interface::InterfaceSettings;
+use keys::{KeyPair, PreviousKey};
+use target::Target;
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::str::FromStr;
 
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -51,32 +17,83 @@ pub struct Router {
     private_key: String,
     public_key: String,
     external_address: AddrPort,
-    internal_address: Ipv4Addr
+    #[serde(rename = "internal_address", with = "::one_or_many")]
+    internal_addresses: Vec<IpAddr>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    previous_keys: Vec<PreviousKey>,
+    #[serde(rename = "interface", default,
+            skip_serializing_if = "InterfaceSettings::is_empty")]
+    settings: InterfaceSettings,
+    #[serde(default, skip_serializing_if = "Forwarding::is_empty")]
+    forwarding: Forwarding,
+    #[serde(default, skip_serializing_if = "Target::is_empty")]
+    target: Target
 }
 
 
 impl Router {
     pub fn new<S: Into<String>>(
         name: S,
-        internal_address: Ipv4Addr,
+        internal_address: IpAddr,
         external_address: AddrPort
-    ) -> Router {
-        let (private_key, public_key) = gen_keys();
-        Router {
+    ) -> Result<Router> {
+        let (private_key, public_key) = KeyPair::generate()?.into_keys();
+        Ok(Router {
             name: name.into(),
-            private_key: private_key,
-            public_key: public_key,
-            external_address: external_address,
-            internal_address: internal_address,
-        }
+            private_key,
+            public_key,
+            external_address,
+            internal_addresses: vec![internal_address],
+            previous_keys: Vec::new(),
+            settings: InterfaceSettings::new(),
+            forwarding: Forwarding::new(),
+            target: Target::new()
+        })
     }
 
     pub fn set_external_address(&mut self, external_address: AddrPort) {
         self.external_address = external_address;
     }
 
-    pub fn set_internal_address(&mut self, internal_address: Ipv4Addr) {
-        self.internal_address = internal_address;
+    pub fn set_internal_address(&mut self, internal_address: IpAddr) {
+        self.internal_addresses = vec![internal_address];
+    }
+
+    pub fn push_internal_address(&mut self, internal_address: IpAddr) {
+        self.internal_addresses.push(internal_address);
+    }
+
+    pub fn set_internal_addresses(&mut self, internal_addresses: Vec<IpAddr>) {
+        self.internal_addresses = internal_addresses;
+    }
+
+    /// Remove an internal address, returning false if the router did not
+    /// have it
+    pub fn remove_internal_address(&mut self, internal_address: &IpAddr)
+        -> bool {
+
+        let len = self.internal_addresses.len();
+        self.internal_addresses.retain(|address| address != internal_address);
+        self.internal_addresses.len() != len
+    }
+
+    /// Use an existing private key, such as one from an imported config
+    pub fn set_private_key(&mut self, private_key: &str) -> Result<()> {
+        let (private_key, public_key) =
+            KeyPair::from_private_key(private_key)?.into_keys();
+        self.private_key = private_key;
+        self.public_key = public_key;
+        Ok(())
+    }
+
+    /// Replace the router's keys with newly generated ones, recording the
+    /// old public key in `previous_keys` as rotated at `now`
+    pub fn rotate_keys(&mut self, now: &DateTime<Utc>) -> Result<()> {
+        let (private_key, public_key) = KeyPair::generate()?.into_keys();
+        let previous = ::std::mem::replace(&mut self.public_key, public_key);
+        self.previous_keys.push(PreviousKey::new(previous, *now));
+        self.private_key = private_key;
+        Ok(())
     }
 
     pub fn name(&self) -> &str { &self.name }
@@ -85,22 +102,50 @@ impl Router {
     pub fn external_address(&self) -> &AddrPort {
         &self.external_address
     }
-    pub fn internal_address(&self) -> &Ipv4Addr { &self.internal_address }
+    pub fn internal_addresses(&self) -> &[IpAddr] { &self.internal_addresses }
+    pub fn previous_keys(&self) -> &[PreviousKey] { &self.previous_keys }
+    pub fn settings(&self) -> &InterfaceSettings { &self.settings }
+    pub fn settings_mut(&mut self) -> &mut InterfaceSettings {
+        &mut self.settings
+    }
+    pub fn forwarding(&self) -> &Forwarding { &self.forwarding }
+    pub fn forwarding_mut(&mut self) -> &mut Forwarding {
+        &mut self.forwarding
+    }
+    pub fn target(&self) -> &Target { &self.target }
+    pub fn target_mut(&mut self) -> &mut Target { &mut self.target }
 
     pub fn interface(&self) -> String {
         let mut lines: Vec<String> = Vec::new();
         lines.push("[Interface]".to_string());
         lines.push(format!("# name: {}", self.name()));
         lines.push(format!("PrivateKey = {}", self.private_key()));
-        lines.push(format!("ListenPort = {}", self.external_address().port()));
+        lines.push(format!("ListenPort = {}", self.target().listen_port()
+            .unwrap_or_else(|| self.external_address().port())));
+        lines.join("\n")
+    }
+
+    /// The `[Interface]` section for `wg-quick`, including addresses and
+    /// the router's interface settings
+    pub fn wg_quick_interface(&self) -> String {
+        let addresses: Vec<IpNet> = self.internal_addresses()
+            .iter()
+            .map(host_net)
+            .collect();
+
+        let mut lines = vec![self.interface()];
+        lines.extend(self.settings().wg_quick_lines(&addresses));
         lines.join("\n")
     }
 
-    pub fn peer(&self, of: &EndPoint, allowed_ips: &[Ipv4Net]) -> String {
+    pub fn peer(&self, of: &EndPoint, allowed_ips: &[IpNet]) -> String {
         let mut lines: Vec<String> = Vec::new();
         lines.push("[Peer]".to_string());
         lines.push(format!("# {}", self.name()));
         lines.push(format!("PublicKey = {}", self.public_key()));
+        if let Some(preshared_key) = of.preshared_key() {
+            lines.push(format!("PresharedKey = {}", preshared_key));
+        }
         lines.push(format!("Endpoint = {}", self.external_address()));
         if let Some(keepalive) = of.persistent_keepalive() {
             lines.push(format!("PersistentKeepalive = {}", keepalive));
@@ -108,44 +153,135 @@ impl Router {
         
         lines.push(format!("AllowedIPs = {}",
             allowed_ips
-                .into_iter()
+                .iter()
                 .map(|ip| format!("{}", ip))
                 .collect::<Vec<String>>()
                 .join(", ")));
     
         lines.join("\n")
     }
+
+    /// The `[Peer]` section for another router in this router's config
+    pub fn router_peer(&self, allowed_ips: &[IpNet]) -> String {
+        let mut lines: Vec<String> = Vec::new();
+        lines.push("[Peer]".to_string());
+        lines.push(format!("# {}", self.name()));
+        lines.push(format!("PublicKey = {}", self.public_key()));
+        lines.push(format!("Endpoint = {}", self.external_address()));
+
+        lines.push(format!("AllowedIPs = {}",
+            allowed_ips
+                .iter()
+                .map(|ip| format!("{}", ip))
+                .collect::<Vec<String>>()
+                .join(", ")));
+
+        lines.join("\n")
+    }
+}
+
+
+
+
+/// What a client routes through its router
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Tunnel {
+    /// Only the wireguard network
+    #[default]
+    Split,
+    /// Everything, including the default route
+    Full
+}
+
+
+impl Tunnel {
+    pub fn is_split(&self) -> bool {
+        *self == Tunnel::Split
+    }
 }
 
 
+impl FromStr for Tunnel {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Tunnel> {
+        match s {
+            "split" => Ok(Tunnel::Split),
+            "full" => Ok(Tunnel::Full),
+            _ => bail!(ErrorKind::InvalidArgument("tunnel".to_string(),
+                                                  s.to_string()))
+        }
+    }
+}
 
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct EndPoint {
     name: String,
+    /// Groups the client is in, for filtering and for settings shared by
+    /// every member
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    groups: Vec<String>,
     private_key: Option<String>,
     public_key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    preshared_key: Option<String>,
     external_address: Option<AddrPort>,
-    internal_address: Ipv4Addr,
-    allowed_ips: Vec<Ipv4Net>,
-    persistent_keepalive: Option<usize>
+    #[serde(rename = "internal_address", with = "::one_or_many")]
+    internal_addresses: Vec<IpAddr>,
+    allowed_ips: Vec<IpNet>,
+    persistent_keepalive: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    primary_router: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    secondary_router: Option<String>,
+    #[serde(default, skip_serializing_if = "Tunnel::is_split")]
+    tunnel: Tunnel,
+    /// Networks which are never routed through the router
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    excluded_routes: Vec<IpNet>,
+    /// After this, the client is left out of router configs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+    /// Disabled clients are left out of router configs
+    #[serde(default, skip_serializing_if = "::std::ops::Not::not")]
+    disabled: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    previous_keys: Vec<PreviousKey>,
+    #[serde(rename = "interface", default,
+            skip_serializing_if = "InterfaceSettings::is_empty")]
+    settings: InterfaceSettings,
+    #[serde(default, skip_serializing_if = "Target::is_empty")]
+    target: Target
 }
 
 
 impl EndPoint {
-    pub fn new<S: Into<String>>(name: S, internal_address: Ipv4Addr)
-        -> EndPoint {
+    pub fn new<S: Into<String>>(name: S, internal_address: IpAddr)
+        -> Result<EndPoint> {
 
-        let (private_key, public_key) = gen_keys();
-        EndPoint {
+        let (private_key, public_key) = KeyPair::generate()?.into_keys();
+        Ok(EndPoint {
             name: name.into(),
+            groups: Vec::new(),
             private_key: Some(private_key),
-            public_key: public_key,
+            public_key,
+            preshared_key: None,
             external_address: None,
-            internal_address: internal_address,
+            internal_addresses: vec![internal_address],
             allowed_ips: Vec::new(),
-            persistent_keepalive: None
-        }
+            persistent_keepalive: None,
+            primary_router: None,
+            secondary_router: None,
+            tunnel: Tunnel::Split,
+            excluded_routes: Vec::new(),
+            expires_at: None,
+            disabled: false,
+            previous_keys: Vec::new(),
+            settings: InterfaceSettings::new(),
+            target: Target::new()
+        })
     }
 
     pub fn builder_external_address(
@@ -156,35 +292,79 @@ impl EndPoint {
         self
     }
 
+    pub fn builder_push_internal_address(
+        mut self,
+        internal_address: IpAddr
+    ) -> EndPoint {
+        self.internal_addresses.push(internal_address);
+        self
+    }
+
     pub fn builder_push_allowed_ips(
         mut self,
-        allowed_ip: Ipv4Net
+        allowed_ip: IpNet
     ) -> EndPoint {
         self.allowed_ips.push(allowed_ip);
         self
     }
 
-    pub fn builder_persistent_keepalive(mut self, keepalive: Option<usize>)
+    pub fn builder_persistent_keepalive(mut self, keepalive: Option<u16>)
         -> EndPoint {
         self.persistent_keepalive = keepalive;
         self
     }
 
+    pub fn builder_push_group(mut self, group: &str) -> EndPoint {
+        self.groups.push(group.to_string());
+        self
+    }
+
     pub fn set_external_address(&mut self, external_address: Option<AddrPort>) {
 
         self.external_address = external_address;
     }
 
-    pub fn set_internal_address(&mut self, internal_address: Ipv4Addr) {
+    pub fn set_internal_address(&mut self, internal_address: IpAddr) {
 
-        self.internal_address = internal_address;
+        self.internal_addresses = vec![internal_address];
     }
 
-    pub fn push_allowed_ip(&mut self, allowed_ip: Ipv4Net) {
+    pub fn push_internal_address(&mut self, internal_address: IpAddr) {
+        self.internal_addresses.push(internal_address);
+    }
+
+    pub fn set_internal_addresses(&mut self, internal_addresses: Vec<IpAddr>) {
+        self.internal_addresses = internal_addresses;
+    }
+
+    /// Remove an internal address, returning false if the endpoint did not
+    /// have it
+    pub fn remove_internal_address(&mut self, internal_address: &IpAddr)
+        -> bool {
+
+        let len = self.internal_addresses.len();
+        self.internal_addresses.retain(|address| address != internal_address);
+        self.internal_addresses.len() != len
+    }
+
+    pub fn push_allowed_ip(&mut self, allowed_ip: IpNet) {
         self.allowed_ips.push(allowed_ip);
     }
 
-    pub fn set_persistent_keepalive(&mut self, keepalive: Option<usize>) {
+    pub fn set_allowed_ips(&mut self, allowed_ips: Vec<IpNet>) {
+        self.allowed_ips = allowed_ips;
+    }
+
+    /// Remove an explicitly configured allowed ip, returning false if the
+    /// endpoint did not have it. Once every allowed ip is removed, the
+    /// endpoint falls back to its internal addresses.
+    pub fn remove_allowed_ip(&mut self, allowed_ip: &IpNet) -> bool {
+        let len = self.allowed_ips.len();
+        self.allowed_ips.retain(|ip| ip != allowed_ip);
+        self.allowed_ips.len() != len
+    }
+
+    pub fn set_persistent_keepalive(&mut self, keepalive: Option<u16>) {
         self.persistent_keepalive = keepalive;
     }
 
@@ -196,27 +376,122 @@ impl EndPoint {
         self.public_key = public_key;
     }
 
+    pub fn set_preshared_key(&mut self, preshared_key: Option<String>) {
+        self.preshared_key = preshared_key;
+    }
+
+    /// Set the router this client routes through, `None` for the first
+    /// router in the configuration
+    pub fn set_primary_router(&mut self, primary_router: Option<String>) {
+        self.primary_router = primary_router;
+    }
+
+    /// Set the router this client can fail over to
+    pub fn set_secondary_router(&mut self, secondary_router: Option<String>) {
+        self.secondary_router = secondary_router;
+    }
+
+    pub fn set_tunnel(&mut self, tunnel: Tunnel) {
+        self.tunnel = tunnel;
+    }
+
+    pub fn set_excluded_routes(&mut self, excluded_routes: Vec<IpNet>) {
+        self.excluded_routes = excluded_routes;
+    }
+
+    pub fn set_groups(&mut self, groups: Vec<String>) {
+        self.groups = groups;
+    }
+
+    pub fn set_expires_at(&mut self, expires_at: Option<DateTime<Utc>>) {
+        self.expires_at = expires_at;
+    }
+
+    pub fn set_disabled(&mut self, disabled: bool) {
+        self.disabled = disabled;
+    }
+
+    /// Replace the endpoint's keys with newly generated ones, recording the
+    /// old public key in `previous_keys` as rotated at `now`. Endpoints with a user supplied
+    /// public key can't be rotated, as we don't hold their private key.
+    pub fn rotate_keys(&mut self, now: &DateTime<Utc>) -> Result<()> {
+        if self.private_key.is_none() {
+            bail!(ErrorKind::Key(format!(
+                "{} has a user supplied public key, and must be rotated by \
+                 setting a new public key", self.name)));
+        }
+
+        let (private_key, public_key) = KeyPair::generate()?.into_keys();
+        let previous = ::std::mem::replace(&mut self.public_key, public_key);
+        self.previous_keys.push(PreviousKey::new(previous, *now));
+        self.private_key = Some(private_key);
+        Ok(())
+    }
+
     pub fn name(&self) -> &str { &self.name }
+    pub fn groups(&self) -> &[String] { &self.groups }
+    pub fn in_group(&self, group: &str) -> bool {
+        self.groups.iter().any(|g| g == group)
+    }
     pub fn private_key(&self) -> Option<&str> {
-        self.private_key.as_ref().map(|s| s.as_str())
+        self.private_key.as_deref()
     }
     pub fn public_key(&self) -> &str { &self.public_key }
+    pub fn preshared_key(&self) -> Option<&str> {
+        self.preshared_key.as_deref()
+    }
     pub fn external_address(&self) -> Option<&AddrPort> {
         self.external_address.as_ref()
     }
-    pub fn internal_address(&self) -> &Ipv4Addr { &self.internal_address }
-    pub fn allowed_ips(&self) -> Vec<Ipv4Net> {
+    pub fn internal_addresses(&self) -> &[IpAddr] { &self.internal_addresses }
+    /// The allowed ips as configured, without falling back to the internal
+    /// addresses
+    pub fn explicit_allowed_ips(&self) -> &[IpNet] { &self.allowed_ips }
+    /// The allowed ips, or the internal addresses' host networks if none are
+    /// configured
+    pub fn allowed_ips(&self) -> Vec<IpNet> {
         if !self.allowed_ips.is_empty() {
             self.allowed_ips.clone()
         }
         else {
-            vec![Ipv4Net::new(self.internal_address().clone(), 32)
-                .expect("Failed to make Ipv4Net for allowed_ips()")]
+            self.internal_addresses()
+                .iter()
+                .map(host_net)
+                .collect()
         }
     }
-    pub fn persistent_keepalive(&self) -> Option<usize> {
-        self.persistent_keepalive.clone()
+    pub fn persistent_keepalive(&self) -> Option<u16> {
+        self.persistent_keepalive
+    }
+    pub fn primary_router(&self) -> Option<&str> {
+        self.primary_router.as_deref()
+    }
+    pub fn secondary_router(&self) -> Option<&str> {
+        self.secondary_router.as_deref()
+    }
+    pub fn tunnel(&self) -> Tunnel { self.tunnel }
+    pub fn excluded_routes(&self) -> &[IpNet] { &self.excluded_routes }
+    pub fn expires_at(&self) -> Option<&DateTime<Utc>> {
+        self.expires_at.as_ref()
+    }
+    pub fn is_disabled(&self) -> bool { self.disabled }
+    pub fn is_expired(&self, now: &DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= *now)
+    }
+    /// Whether the client belongs in router configs at `now`
+    pub fn is_active(&self, now: &DateTime<Utc>) -> bool {
+        !self.disabled && !self.is_expired(now)
+    }
+    pub fn previous_keys(&self) -> &[PreviousKey] { &self.previous_keys }
+    /// This endpoint's own interface settings. See
+    /// `Configuration::client_settings` for the settings which apply once
+    /// configuration-wide defaults are taken into account.
+    pub fn settings(&self) -> &InterfaceSettings { &self.settings }
+    pub fn settings_mut(&mut self) -> &mut InterfaceSettings {
+        &mut self.settings
     }
+    pub fn target(&self) -> &Target { &self.target }
+    pub fn target_mut(&mut self) -> &mut Target { &mut self.target }
 
     pub fn interface(&self) -> String {
         let mut lines: Vec<String> = Vec::new();
@@ -224,17 +499,35 @@ impl EndPoint {
         lines.push(format!("# name: {}", self.name()));
         lines.push(format!("PrivateKey = {}", self.private_key()
             .unwrap_or("USER_SUPPLIED")));
-        if let Some(external_address) = self.external_address() {
-            lines.push(format!("ListenPort = {}", external_address.port()));
+        let listen_port = self.target().listen_port()
+            .or_else(|| self.external_address().map(|address| address.port()));
+        if let Some(listen_port) = listen_port {
+            lines.push(format!("ListenPort = {}", listen_port));
         }
         lines.join("\n")
     }
 
+    /// The `[Interface]` section for `wg-quick`, including addresses and
+    /// the given interface settings
+    pub fn wg_quick_interface(&self, settings: &InterfaceSettings) -> String {
+        let addresses: Vec<IpNet> = self.internal_addresses()
+            .iter()
+            .map(host_net)
+            .collect();
+
+        let mut lines = vec![self.interface()];
+        lines.extend(settings.wg_quick_lines(&addresses));
+        lines.join("\n")
+    }
+
     pub fn peer(&self) -> String {
         let mut lines: Vec<String> = Vec::new();
         lines.push("[Peer]".to_string());
         lines.push(format!("# {}", self.name()));
         lines.push(format!("PublicKey = {}", self.public_key()));
+        if let Some(preshared_key) = self.preshared_key() {
+            lines.push(format!("PresharedKey = {}", preshared_key));
+        }
         if let Some(external_address) = self.external_address() {
             lines.push(format!("Endpoint = {}", external_address));
         }
@@ -248,4 +541,29 @@ impl EndPoint {
     
         lines.join("\n")
     }
+
+    /// The `[Peer]` section for this endpoint in the config of `of`, when
+    /// the two peer directly in a mesh. Preshared keys are only shared with
+    /// the router, so none is given here.
+    pub fn mesh_peer(&self, of: &EndPoint) -> String {
+        let mut lines: Vec<String> = Vec::new();
+        lines.push("[Peer]".to_string());
+        lines.push(format!("# {}", self.name()));
+        lines.push(format!("PublicKey = {}", self.public_key()));
+        if let Some(external_address) = self.external_address() {
+            lines.push(format!("Endpoint = {}", external_address));
+            if let Some(keepalive) = of.persistent_keepalive() {
+                lines.push(format!("PersistentKeepalive = {}", keepalive));
+            }
+        }
+
+        lines.push(format!("AllowedIPs = {}",
+            self.allowed_ips()
+                .iter()
+                .map(|ip| format!("{}", ip))
+                .collect::<Vec<String>>()
+                .join(", ")));
+
+        lines.join("\n")
+    }
 }
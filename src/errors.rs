@@ -0,0 +1,80 @@
+use diagnostic::Diagnostic;
+use ipnet::IpNet;
+use std::io;
+use std::path::PathBuf;
+
+
+error_chain! {
+    errors {
+        Io(path: PathBuf, error: io::Error) {
+            description("i/o error")
+            display("{}: {}", path.display(), error)
+        }
+
+        Yaml(path: PathBuf, message: String, line: Option<usize>,
+             column: Option<usize>) {
+            description("failed to parse configuration")
+            display("{}", match (line, column) {
+                (&Some(line), &Some(column)) =>
+                    format!("{}:{}:{}: {}", path.display(), line, column,
+                        message),
+                _ => format!("{}: {}", path.display(), message)
+            })
+        }
+
+        Key(reason: String) {
+            description("key error")
+            display("key error: {}", reason)
+        }
+
+        AddressParse(value: String) {
+            description("invalid address")
+            display("invalid address or subnet: {}", value)
+        }
+
+        InvalidArgument(argument: String, value: String) {
+            description("invalid argument")
+            display("invalid {}: {}", argument, value)
+        }
+
+        ClientNotFound(name: String) {
+            description("client not found")
+            display("could not find client {}", name)
+        }
+
+        ClientExists(name: String) {
+            description("client already exists")
+            display("client {} already exists", name)
+        }
+
+        RouterNotFound(name: String) {
+            description("router not found")
+            display("could not find router {}", name)
+        }
+
+        RouterExists(name: String) {
+            description("router already exists")
+            display("router {} already exists", name)
+        }
+
+        NoFreeAddress(subnet: IpNet, prefix_len: u8) {
+            description("no free address")
+            display("no free /{} left in {}", prefix_len, subnet)
+        }
+
+        InvalidConfiguration(diagnostics: Vec<Diagnostic>) {
+            description("invalid configuration")
+            display("configuration is invalid")
+        }
+
+        Import(source: String, reason: String) {
+            description("import error")
+            display("{}: {}", source, reason)
+        }
+
+        QrCode(reason: String) {
+            description("qr code error")
+            display("could not create qr code: {}", reason)
+        }
+    }
+}
@@ -0,0 +1,272 @@
+//! Forwarding and NAT on routers, for the generated linux scripts.
+
+use chrono::{DateTime, Utc};
+use cidr::host_net;
+use configuration::Configuration;
+use endpoint::{Router, Tunnel};
+use errors::*;
+use ipnet::IpNet;
+use script::quote;
+use std::str::FromStr;
+
+
+/// Which linux firewall the generated rules are for
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Backend {
+    Iptables,
+    Nftables
+}
+
+
+impl FromStr for Backend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Backend> {
+        match s {
+            "iptables" => Ok(Backend::Iptables),
+            "nftables" => Ok(Backend::Nftables),
+            _ => bail!(ErrorKind::InvalidArgument("firewall".to_string(),
+                                                  s.to_string()))
+        }
+    }
+}
+
+
+/// The policy for forwarded traffic which isn't to or from wireguard
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardPolicy {
+    Accept,
+    Drop
+}
+
+
+impl FromStr for ForwardPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ForwardPolicy> {
+        match s {
+            "accept" => Ok(ForwardPolicy::Accept),
+            "drop" => Ok(ForwardPolicy::Drop),
+            _ => bail!(ErrorKind::InvalidArgument("forward policy".to_string(),
+                                                  s.to_string()))
+        }
+    }
+}
+
+
+/// How a router forwards and masquerades client traffic
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Forwarding {
+    /// The interface masqueraded traffic leaves by. Without one, anything
+    /// not leaving by wireguard is masqueraded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    egress_interface: Option<String>,
+    /// Masquerade every client, or none. Unset, only full tunnel clients
+    /// are masqueraded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    masquerade: Option<bool>,
+    /// Unset, the forward policy is left alone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    forward_policy: Option<ForwardPolicy>
+}
+
+
+impl Forwarding {
+    pub fn new() -> Forwarding {
+        Forwarding::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.egress_interface.is_none()
+            && self.masquerade.is_none()
+            && self.forward_policy.is_none()
+    }
+
+    pub fn set_egress_interface(&mut self, egress_interface: Option<String>) {
+        self.egress_interface = egress_interface;
+    }
+    pub fn set_masquerade(&mut self, masquerade: Option<bool>) {
+        self.masquerade = masquerade;
+    }
+    pub fn set_forward_policy(&mut self,
+                              forward_policy: Option<ForwardPolicy>) {
+        self.forward_policy = forward_policy;
+    }
+
+    pub fn egress_interface(&self) -> Option<&str> {
+        self.egress_interface.as_deref()
+    }
+    pub fn masquerade(&self) -> Option<bool> { self.masquerade }
+    pub fn forward_policy(&self) -> Option<ForwardPolicy> {
+        self.forward_policy
+    }
+}
+
+
+/// The client networks a router masquerades
+fn masquerade_nets(configuration: &Configuration, router: &Router,
+                   now: &DateTime<Utc>) -> Vec<IpNet> {
+
+    let masquerade = router.forwarding().masquerade();
+    configuration.router_clients(router, now)
+        .iter()
+        .filter(|client| match masquerade {
+            Some(masquerade) => masquerade,
+            None => client.tunnel() == Tunnel::Full
+        })
+        .flat_map(|client| client.allowed_ips())
+        .collect()
+}
+
+
+/// Whether a router uses IPv4 and IPv6
+fn families(configuration: &Configuration, router: &Router,
+            now: &DateTime<Utc>) -> (bool, bool) {
+    let mut nets: Vec<IpNet> = configuration.router_routes(router, now);
+    nets.extend(router.internal_addresses().iter().map(host_net));
+    (nets.iter().any(|net| net.addr().is_ipv4()),
+     nets.iter().any(|net| net.addr().is_ipv6()))
+}
+
+
+/// Commands which turn on forwarding for each address family the router
+/// uses
+pub fn sysctl_commands(configuration: &Configuration, router: &Router,
+                       now: &DateTime<Utc>) -> Vec<String> {
+
+    let (ipv4, ipv6) = families(configuration, router, now);
+    let mut lines: Vec<String> = Vec::new();
+    if ipv4 {
+        lines.push("sysctl -w net.ipv4.ip_forward=1".to_string());
+    }
+    if ipv6 {
+        lines.push("sysctl -w net.ipv6.conf.all.forwarding=1".to_string());
+    }
+    lines
+}
+
+
+/// Append a rule unless it is already there, so the script can be run again
+fn append_rule(iptables: &str, rule: &str) -> String {
+    format!("{0} -C {1} 2>/dev/null || {0} -A {1}", iptables, rule)
+}
+
+
+fn iptables_commands(configuration: &Configuration, router: &Router,
+                     now: &DateTime<Utc>) -> Vec<String> {
+
+    let forwarding = router.forwarding();
+    let interface = quote(router.target().linux_interface());
+    let mut lines: Vec<String> = Vec::new();
+
+    let (ipv4, ipv6) = families(configuration, router, now);
+    let mut commands: Vec<&str> = Vec::new();
+    if ipv4 {
+        commands.push("iptables");
+    }
+    if ipv6 {
+        commands.push("ip6tables");
+    }
+
+    for iptables in commands {
+        match forwarding.forward_policy() {
+            Some(ForwardPolicy::Accept) =>
+                lines.push(format!("{} -P FORWARD ACCEPT", iptables)),
+            Some(ForwardPolicy::Drop) => {
+                lines.push(format!("{} -P FORWARD DROP", iptables));
+                lines.push(append_rule(iptables,
+                    &format!("FORWARD -i {} -j ACCEPT", interface)));
+                lines.push(append_rule(iptables,
+                    &format!("FORWARD -o {} -m conntrack --ctstate \
+                              RELATED,ESTABLISHED -j ACCEPT", interface)));
+            },
+            None => {}
+        }
+    }
+
+    let egress = match forwarding.egress_interface() {
+        Some(egress) => format!("-o {}", quote(egress)),
+        None => format!("! -o {}", interface)
+    };
+    for net in masquerade_nets(configuration, router, now) {
+        let iptables = match net {
+            IpNet::V4(_) => "iptables -t nat",
+            IpNet::V6(_) => "ip6tables -t nat"
+        };
+        lines.push(append_rule(iptables,
+            &format!("POSTROUTING -s {} {} -j MASQUERADE", net, egress)));
+    }
+
+    lines
+}
+
+
+fn nftables_commands(configuration: &Configuration, router: &Router,
+                     now: &DateTime<Utc>) -> Vec<String> {
+
+    let forwarding = router.forwarding();
+    let interface = router.target().linux_interface();
+    let masquerade_nets = masquerade_nets(configuration, router, now);
+
+    // Creating the table first means deleting it can't fail on the first
+    // run, so the script can be run again without stacking rules.
+    let mut lines: Vec<String> = vec![
+        "nft -f - <<'EOF'".to_string(),
+        "table inet wireguard".to_string(),
+        "delete table inet wireguard".to_string(),
+        "table inet wireguard {".to_string()
+    ];
+
+    if let Some(policy) = forwarding.forward_policy() {
+        lines.push("    chain forward {".to_string());
+        lines.push(format!("        type filter hook forward priority 0; \
+                            policy {};",
+            match policy {
+                ForwardPolicy::Accept => "accept",
+                ForwardPolicy::Drop => "drop"
+            }));
+        if policy == ForwardPolicy::Drop {
+            lines.push(format!("        iifname \"{}\" accept", interface));
+            lines.push(format!("        oifname \"{}\" ct state \
+                                related,established accept", interface));
+        }
+        lines.push("    }".to_string());
+    }
+
+    if !masquerade_nets.is_empty() {
+        let egress = match forwarding.egress_interface() {
+            Some(egress) => format!("oifname \"{}\"", egress),
+            None => format!("oifname != \"{}\"", interface)
+        };
+        lines.push("    chain postrouting {".to_string());
+        lines.push("        type nat hook postrouting priority 100;"
+            .to_string());
+        for net in masquerade_nets {
+            let family = match net {
+                IpNet::V4(_) => "ip",
+                IpNet::V6(_) => "ip6"
+            };
+            lines.push(format!("        {} saddr {} {} masquerade",
+                family, net, egress));
+        }
+        lines.push("    }".to_string());
+    }
+
+    lines.push("}".to_string());
+    lines.push("EOF".to_string());
+    lines
+}
+
+
+/// Commands which set up forwarding and NAT on a router
+pub fn router_commands(configuration: &Configuration, router: &Router,
+                       backend: Backend, now: &DateTime<Utc>) -> Vec<String> {
+
+    let mut lines = sysctl_commands(configuration, router, now);
+    lines.extend(match backend {
+        Backend::Iptables => iptables_commands(configuration, router, now),
+        Backend::Nftables => nftables_commands(configuration, router, now)
+    });
+    lines
+}
@@ -0,0 +1,40 @@
+//! Settings shared by every client in a group.
+
+use interface::InterfaceSettings;
+use ipnet::IpNet;
+
+
+/// Settings which apply to every member of a group. Clients are put in
+/// groups with `EndPoint::set_groups`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Group {
+    /// Interface settings for members which don't set their own
+    #[serde(rename = "interface", default,
+            skip_serializing_if = "InterfaceSettings::is_empty")]
+    settings: InterfaceSettings,
+    /// Networks members route through their router, on top of the
+    /// wireguard network
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    routes: Vec<IpNet>
+}
+
+
+impl Group {
+    pub fn new() -> Group {
+        Group::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.settings.is_empty() && self.routes.is_empty()
+    }
+
+    pub fn set_routes(&mut self, routes: Vec<IpNet>) {
+        self.routes = routes;
+    }
+
+    pub fn settings(&self) -> &InterfaceSettings { &self.settings }
+    pub fn settings_mut(&mut self) -> &mut InterfaceSettings {
+        &mut self.settings
+    }
+    pub fn routes(&self) -> &[IpNet] { &self.routes }
+}
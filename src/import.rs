@@ -0,0 +1,427 @@
+//! Import existing `wg-quick` and `wg setconf` config files.
+//!
+//! Node names are read from the `# name: ...` interface comments and
+//! `# ...` peer comments which this tool writes itself.
+
+use addrport::AddrPort;
+use cidr::{first_host, host_net};
+use configuration::Configuration;
+use diagnostic::Diagnostic;
+use endpoint::{EndPoint, Router};
+use errors::*;
+use interface::InterfaceSettings;
+use ipnet::IpNet;
+use keys;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+
+/// One `[Interface]` or `[Peer]` section of a config file
+#[derive(Clone, Debug, Default)]
+pub struct Section {
+    name: Option<String>,
+    entries: Vec<(String, String)>
+}
+
+
+impl Section {
+    /// The name from the comment at the top of the section, if any
+    pub fn name(&self) -> Option<&str> { self.name.as_deref() }
+
+    /// Every `(key, value)` in the section, in order. Keys are as written.
+    pub fn entries(&self) -> &[(String, String)] { &self.entries }
+
+    /// The last value for a key, which is matched case insensitively
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.iter()
+            .rev()
+            .find(|entry| entry.0.eq_ignore_ascii_case(key))
+            .map(|entry| entry.1.as_str())
+    }
+
+    /// Every value for a key, with comma-delimited values split up
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        self.entries.iter()
+            .filter(|entry| entry.0.eq_ignore_ascii_case(key))
+            .flat_map(|entry| entry.1.split(','))
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+            .collect()
+    }
+}
+
+
+/// A parsed wireguard config file
+#[derive(Clone, Debug)]
+pub struct WgConfig {
+    source: String,
+    interface: Section,
+    peers: Vec<Section>
+}
+
+
+impl WgConfig {
+    /// Parse the text of a config file. `source` names the file in errors.
+    pub fn parse<S: Into<String>>(source: S, text: &str) -> Result<WgConfig> {
+        let source = source.into();
+        let mut interface: Option<Section> = None;
+        let mut peers: Vec<Section> = Vec::new();
+        // The section lines are being added to, with true for a peer.
+        let mut current: Option<bool> = None;
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            let error = |reason: String| -> Error {
+                ErrorKind::Import(source.clone(),
+                    format!("line {}: {}", number + 1, reason)).into()
+            };
+
+            let section = match current {
+                Some(true) => peers.last_mut(),
+                Some(false) => interface.as_mut(),
+                None => None
+            };
+
+            if line.is_empty() {
+                continue;
+            }
+            else if line.starts_with('#') {
+                // A comment before the first key names the section.
+                if let Some(section) = section {
+                    if section.entries.is_empty() && section.name.is_none() {
+                        let name = line.trim_start_matches('#').trim();
+                        let name = name.strip_prefix("name:")
+                            .unwrap_or(name)
+                            .trim();
+                        if !name.is_empty() {
+                            section.name = Some(name.to_string());
+                        }
+                    }
+                }
+            }
+            else if line.eq_ignore_ascii_case("[Interface]") {
+                if interface.is_some() {
+                    return Err(error("more than one [Interface]".to_string()));
+                }
+                interface = Some(Section::default());
+                current = Some(false);
+            }
+            else if line.eq_ignore_ascii_case("[Peer]") {
+                peers.push(Section::default());
+                current = Some(true);
+            }
+            else if line.starts_with('[') {
+                return Err(error(format!("unknown section {}", line)));
+            }
+            else {
+                let section = section.ok_or_else(||
+                    error("key outside of a section".to_string()))?;
+                let (key, value) = line.split_once('=')
+                    .ok_or_else(|| error(format!("expected key = value, got {}",
+                                                 line)))?;
+                // Trailing comments aren't part of the value.
+                let value = value.split('#').next().unwrap_or("");
+                section.entries.push((key.trim().to_string(),
+                                      value.trim().to_string()));
+            }
+        }
+
+        let interface = interface.ok_or_else(||
+            Error::from(ErrorKind::Import(source.clone(),
+                "no [Interface] section".to_string())))?;
+
+        Ok(WgConfig {
+            source,
+            interface,
+            peers
+        })
+    }
+
+    /// Read and parse a config file
+    pub fn open(path: &Path) -> Result<WgConfig> {
+        let text = ::std::fs::read_to_string(path)
+            .map_err(|e| ErrorKind::Io(path.to_path_buf(), e))?;
+        WgConfig::parse(path.display().to_string(), &text)
+    }
+
+    pub fn source(&self) -> &str { &self.source }
+    pub fn interface(&self) -> &Section { &self.interface }
+    pub fn peers(&self) -> &[Section] { &self.peers }
+
+    /// The name of this node, from its `# name:` comment, or else from the
+    /// file name
+    pub fn name(&self) -> String {
+        match self.interface.name() {
+            Some(name) => name.to_string(),
+            None => Path::new(&self.source)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_else(|| self.source.clone())
+        }
+    }
+
+    /// The public key for this config's private key
+    pub fn public_key(&self) -> Result<Option<String>> {
+        match self.interface.get("PrivateKey") {
+            Some(private_key) => Ok(Some(keys::public_key(private_key)
+                .map_err(|e| self.error(e.to_string()))?)),
+            None => Ok(None)
+        }
+    }
+
+    fn error<S: Into<String>>(&self, reason: S) -> Error {
+        ErrorKind::Import(self.source.clone(), reason.into()).into()
+    }
+
+    fn parse_all<T: FromStr>(&self, section: &Section, key: &str)
+        -> Result<Vec<T>> {
+
+        section.get_all(key)
+            .into_iter()
+            .map(|value| value.parse()
+                .map_err(|_| self.error(format!("invalid {}: {}", key, value))))
+            .collect()
+    }
+
+    fn parse_one<T: FromStr>(&self, section: &Section, key: &str)
+        -> Result<Option<T>> {
+
+        match section.get(key) {
+            Some(value) => value.parse()
+                .map(Some)
+                .map_err(|_| self.error(format!("invalid {}: {}", key, value))),
+            None => Ok(None)
+        }
+    }
+
+    /// The interface addresses. wg-quick allows a prefix length, which is
+    /// not kept.
+    fn addresses(&self) -> Result<Vec<IpAddr>> {
+        self.interface.get_all("Address")
+            .into_iter()
+            .map(|value| value.parse::<IpNet>()
+                .map(|net| net.addr())
+                .or_else(|_| value.parse::<IpAddr>())
+                .map_err(|_| self.error(format!("invalid Address: {}", value))))
+            .collect()
+    }
+
+    /// The wg-quick settings of the interface, with a warning for each key
+    /// which isn't understood
+    fn settings(&self, client: Option<&str>, diagnostics: &mut Vec<Diagnostic>)
+        -> Result<InterfaceSettings> {
+
+        let mut settings = InterfaceSettings::new();
+        settings.set_dns(self.interface.get_all("DNS")
+            .into_iter()
+            .map(|dns| dns.to_string())
+            .collect());
+        settings.set_mtu(self.parse_one(&self.interface, "MTU")?);
+        settings.set_table(self.interface.get("Table")
+            .map(|table| table.to_string()));
+
+        let commands = |key| -> Vec<String> {
+            self.interface.entries()
+                .iter()
+                .filter(|entry| entry.0.eq_ignore_ascii_case(key))
+                .map(|entry| entry.1.clone())
+                .collect()
+        };
+        settings.set_pre_up(commands("PreUp"));
+        settings.set_post_up(commands("PostUp"));
+        settings.set_pre_down(commands("PreDown"));
+        settings.set_post_down(commands("PostDown"));
+
+        for (key, _) in self.interface.entries() {
+            if !INTERFACE_KEYS.iter()
+                    .any(|known| known.eq_ignore_ascii_case(key)) {
+                diagnostics.push(Diagnostic::warning(client, key.as_str(),
+                    format!("{} is not supported and was not imported",
+                        key)));
+            }
+        }
+
+        Ok(settings)
+    }
+}
+
+
+const INTERFACE_KEYS: &[&str] = &[
+    "PrivateKey", "ListenPort", "Address", "DNS", "MTU", "Table", "PreUp",
+    "PostUp", "PreDown", "PostDown"
+];
+
+
+/// Internal addresses for a peer known only from the router's side: its
+/// single address allowed ips, or else the first host of each allowed
+/// network.
+fn guess_internal_addresses(allowed_ips: &[IpNet]) -> Vec<IpAddr> {
+    let hosts: Vec<IpAddr> = allowed_ips.iter()
+        .filter(|net| net.prefix_len() == net.max_prefix_len())
+        .map(|net| net.addr())
+        .collect();
+    if !hosts.is_empty() {
+        return hosts;
+    }
+
+    let mut addresses: Vec<IpAddr> = Vec::new();
+    for net in allowed_ips {
+        if !addresses.iter()
+                .any(|address| address.is_ipv4() == net.addr().is_ipv4()) {
+            addresses.push(first_host(net));
+        }
+    }
+    addresses
+}
+
+
+/// Build a configuration from a router's config file and, optionally, the
+/// config files of its clients.
+///
+/// Clients are the router's peers. Client configs are matched to them by
+/// key, and supply private keys, addresses and wg-quick settings. The
+/// router's external address is taken from `external_address`, or else
+/// from a client config's `Endpoint` for the router. `internal_addresses`
+/// are only needed when the router config has no `Address`.
+///
+/// Anything in the files which can't be represented is returned as a
+/// warning.
+pub fn import(
+    router: &WgConfig,
+    clients: &[WgConfig],
+    external_address: Option<AddrPort>,
+    internal_addresses: Option<Vec<IpAddr>>
+) -> Result<(Configuration, Vec<Diagnostic>)> {
+
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+
+    let router_public_key = router.public_key()?
+        .ok_or_else(|| router.error("the router config has no PrivateKey"))?;
+
+    let internal_addresses = match internal_addresses {
+        Some(addresses) => addresses,
+        None => router.addresses()?
+    };
+    if internal_addresses.is_empty() {
+        return Err(router.error("the router config has no Address, an \
+                                 internal address must be given"));
+    }
+
+    // Client configs have the router's endpoint, and their own keepalive.
+    let mut router_endpoint: Option<AddrPort> = None;
+    let mut keepalives: Vec<(String, u16)> = Vec::new();
+    for client in clients {
+        let public_key = client.public_key()?;
+        for peer in client.peers() {
+            if peer.get("PublicKey") != Some(router_public_key.as_str()) {
+                continue;
+            }
+            if router_endpoint.is_none() {
+                router_endpoint = client.parse_one(peer, "Endpoint")?;
+            }
+            let keepalive = client.parse_one(peer, "PersistentKeepalive")?;
+            if let (Some(ref public_key), Some(keepalive)) =
+                    (&public_key, keepalive) {
+                keepalives.push((public_key.clone(), keepalive));
+            }
+        }
+    }
+
+    let external_address = match external_address.or(router_endpoint) {
+        Some(external_address) => external_address,
+        None => return Err(router.error("the router's external address is not \
+                                         in any client config, and must be \
+                                         given"))
+    };
+    // Clients keep connecting to the port they already use, such as one
+    // forwarded to the router, which may listen on another.
+    let listen_port = router.parse_one(router.interface(), "ListenPort")?
+        .filter(|port| *port != external_address.port());
+
+    let router_name = router.name();
+    let mut new_router = Router::new(router_name.as_str(),
+                                     internal_addresses[0], external_address)?;
+    for address in &internal_addresses[1..] {
+        new_router.push_internal_address(*address);
+    }
+    new_router.set_private_key(router.interface().get("PrivateKey").unwrap())
+        .map_err(|e| router.error(e.to_string()))?;
+    *new_router.settings_mut() = router.settings(None, &mut diagnostics)?;
+    new_router.target_mut().set_listen_port(listen_port);
+
+    let mut configuration = Configuration::new(new_router);
+
+    let mut matched = vec![false; clients.len()];
+    for (i, peer) in router.peers().iter().enumerate() {
+        let public_key = peer.get("PublicKey")
+            .ok_or_else(|| router.error(format!("peer {} has no PublicKey",
+                                                i + 1)))?;
+
+        let client = clients.iter()
+            .position(|client| client.public_key().ok().and_then(|key| key)
+                .as_deref() == Some(public_key));
+        if let Some(client) = client {
+            matched[client] = true;
+        }
+        let client = client.map(|client| &clients[client]);
+
+        let name = peer.name()
+            .map(|name| name.to_string())
+            .or_else(|| client.map(|client| client.name()))
+            .unwrap_or_else(|| format!("peer-{}", i + 1));
+
+        let allowed_ips: Vec<IpNet> = router.parse_all(peer, "AllowedIPs")?;
+        let addresses = match client {
+            Some(client) => client.addresses()?,
+            None => Vec::new()
+        };
+        let addresses = if addresses.is_empty() {
+            guess_internal_addresses(&allowed_ips)
+        }
+        else {
+            addresses
+        };
+        if addresses.is_empty() {
+            return Err(router.error(format!("peer {} has no AllowedIPs",
+                                            name)));
+        }
+
+        let mut endpoint = EndPoint::new(name.as_str(), addresses[0])?;
+        for address in &addresses[1..] {
+            endpoint.push_internal_address(*address);
+        }
+        endpoint.set_public_key(public_key.to_string());
+        endpoint.set_private_key(client.and_then(|client|
+            client.interface().get("PrivateKey").map(|key| key.to_string())));
+        endpoint.set_preshared_key(peer.get("PresharedKey")
+            .map(|key| key.to_string()));
+        endpoint.set_external_address(router.parse_one(peer, "Endpoint")?);
+
+        // Leave allowed ips empty when they are just the internal
+        // addresses, as that is the default.
+        let host_nets: Vec<IpNet> = addresses.iter().map(host_net).collect();
+        if allowed_ips != host_nets {
+            endpoint.set_allowed_ips(allowed_ips);
+        }
+
+        endpoint.set_persistent_keepalive(keepalives.iter()
+            .find(|keepalive| keepalive.0 == public_key)
+            .map(|keepalive| keepalive.1));
+
+        if let Some(client) = client {
+            *endpoint.settings_mut() =
+                client.settings(Some(&name), &mut diagnostics)?;
+        }
+
+        configuration.push_client(endpoint)?;
+    }
+
+    for (client, matched) in clients.iter().zip(matched) {
+        if !matched {
+            return Err(client.error("does not match any peer of the router"));
+        }
+    }
+
+    Ok((configuration, diagnostics))
+}
@@ -0,0 +1,126 @@
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+
+/// Interface settings which only `wg-quick` (and the tools built on it)
+/// understand, as opposed to `wg setconf`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct InterfaceSettings {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    dns: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mtu: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    table: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pre_up: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    post_up: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pre_down: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    post_down: Vec<String>
+}
+
+
+impl InterfaceSettings {
+    pub fn new() -> InterfaceSettings {
+        InterfaceSettings::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dns.is_empty()
+            && self.mtu.is_none()
+            && self.table.is_none()
+            && self.pre_up.is_empty()
+            && self.post_up.is_empty()
+            && self.pre_down.is_empty()
+            && self.post_down.is_empty()
+    }
+
+    /// These settings, with anything unset taken from `defaults`
+    pub fn or(&self, defaults: &InterfaceSettings) -> InterfaceSettings {
+        fn or_vec(a: &[String], b: &[String]) -> Vec<String> {
+            if a.is_empty() { b.to_vec() } else { a.to_vec() }
+        }
+
+        InterfaceSettings {
+            dns: or_vec(&self.dns, &defaults.dns),
+            mtu: self.mtu.or(defaults.mtu),
+            table: self.table.clone().or_else(|| defaults.table.clone()),
+            pre_up: or_vec(&self.pre_up, &defaults.pre_up),
+            post_up: or_vec(&self.post_up, &defaults.post_up),
+            pre_down: or_vec(&self.pre_down, &defaults.pre_down),
+            post_down: or_vec(&self.post_down, &defaults.post_down)
+        }
+    }
+
+    pub fn set_dns(&mut self, dns: Vec<String>) { self.dns = dns; }
+    pub fn set_mtu(&mut self, mtu: Option<u16>) { self.mtu = mtu; }
+    pub fn set_table(&mut self, table: Option<String>) { self.table = table; }
+    pub fn set_pre_up(&mut self, commands: Vec<String>) {
+        self.pre_up = commands;
+    }
+    pub fn set_post_up(&mut self, commands: Vec<String>) {
+        self.post_up = commands;
+    }
+    pub fn set_pre_down(&mut self, commands: Vec<String>) {
+        self.pre_down = commands;
+    }
+    pub fn set_post_down(&mut self, commands: Vec<String>) {
+        self.post_down = commands;
+    }
+
+    /// DNS servers and search domains
+    pub fn dns(&self) -> &[String] { &self.dns }
+    pub fn mtu(&self) -> Option<u16> { self.mtu }
+    pub fn table(&self) -> Option<&str> { self.table.as_deref() }
+    pub fn pre_up(&self) -> &[String] { &self.pre_up }
+    pub fn post_up(&self) -> &[String] { &self.post_up }
+    pub fn pre_down(&self) -> &[String] { &self.pre_down }
+    pub fn post_down(&self) -> &[String] { &self.post_down }
+
+    /// The DNS entries which are server addresses
+    pub fn dns_servers(&self) -> Vec<IpAddr> {
+        self.dns.iter().filter_map(|dns| dns.parse().ok()).collect()
+    }
+
+    /// The DNS entries which are search domains
+    pub fn dns_search(&self) -> Vec<&str> {
+        self.dns.iter()
+            .filter(|dns| dns.parse::<IpAddr>().is_err())
+            .map(|dns| dns.as_str())
+            .collect()
+    }
+
+    /// The `[Interface]` lines wg-quick adds on top of those `wg setconf`
+    /// understands.
+    pub fn wg_quick_lines(&self, addresses: &[IpNet]) -> Vec<String> {
+        let mut lines: Vec<String> = Vec::new();
+        if !addresses.is_empty() {
+            lines.push(format!("Address = {}",
+                addresses.iter()
+                    .map(|address| address.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")));
+        }
+        if !self.dns.is_empty() {
+            lines.push(format!("DNS = {}", self.dns.join(", ")));
+        }
+        if let Some(mtu) = self.mtu {
+            lines.push(format!("MTU = {}", mtu));
+        }
+        if let Some(ref table) = self.table {
+            lines.push(format!("Table = {}", table));
+        }
+        for (key, commands) in &[("PreUp", &self.pre_up),
+                                 ("PostUp", &self.post_up),
+                                 ("PreDown", &self.pre_down),
+                                 ("PostDown", &self.post_down)] {
+            for command in commands.iter() {
+                lines.push(format!("{} = {}", key, command));
+            }
+        }
+        lines
+    }
+}
@@ -0,0 +1,179 @@
+use base64::Engine;
+use errors::*;
+use base64::engine::general_purpose::STANDARD;
+use chrono::{DateTime, Utc};
+use rand_core::{OsRng, RngCore};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+
+const KEY_LEN: usize = 32;
+
+
+/// Clamp a curve25519 scalar the same way `wg genkey` does.
+fn clamp(mut key: [u8; KEY_LEN]) -> [u8; KEY_LEN] {
+    key[0] &= 248;
+    key[31] &= 127;
+    key[31] |= 64;
+    key
+}
+
+
+fn decode_key(key: &str) -> Result<[u8; KEY_LEN]> {
+    let bytes = STANDARD.decode(key.trim())
+        .map_err(|e| ErrorKind::Key(format!("malformed base64 key: {}", e)))?;
+    if bytes.len() != KEY_LEN {
+        bail!(ErrorKind::Key(format!("expected a {} byte key, got {} bytes",
+            KEY_LEN, bytes.len())));
+    }
+    let mut key = [0u8; KEY_LEN];
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
+
+
+fn random_key() -> Result<[u8; KEY_LEN]> {
+    let mut key = [0u8; KEY_LEN];
+    OsRng.try_fill_bytes(&mut key)
+        .map_err(|e| ErrorKind::Key(format!("failed to get random bytes: {}",
+                                            e)))?;
+    Ok(key)
+}
+
+
+fn encode_key(key: &[u8; KEY_LEN]) -> String {
+    STANDARD.encode(key)
+}
+
+
+/// True if `key` is a well-formed base64 wireguard key.
+pub fn is_valid_key(key: &str) -> bool {
+    decode_key(key).is_ok()
+}
+
+
+/// Derive the base64 public key for a base64 private key, as `wg pubkey`
+/// would.
+pub fn public_key(private_key: &str) -> Result<String> {
+    let secret = StaticSecret::from(decode_key(private_key)?);
+    Ok(encode_key(PublicKey::from(&secret).as_bytes()))
+}
+
+
+/// Generate a new base64 preshared key, equivalent to `wg genpsk`.
+pub fn generate_preshared_key() -> Result<String> {
+    Ok(encode_key(&random_key()?))
+}
+
+
+/// A wireguard private/public key pair, both base64 encoded.
+#[derive(Clone, Debug)]
+pub struct KeyPair {
+    private_key: String,
+    public_key: String
+}
+
+
+impl KeyPair {
+    /// Generate a new key pair, equivalent to `wg genkey | wg pubkey`.
+    pub fn generate() -> Result<KeyPair> {
+        let private_key = encode_key(&clamp(random_key()?));
+        KeyPair::from_private_key(&private_key)
+    }
+
+    /// Rebuild a key pair from an existing base64 private key.
+    pub fn from_private_key(private_key: &str) -> Result<KeyPair> {
+        Ok(KeyPair {
+            public_key: public_key(private_key)?,
+            private_key: private_key.trim().to_string()
+        })
+    }
+
+    pub fn private_key(&self) -> &str { &self.private_key }
+    pub fn public_key(&self) -> &str { &self.public_key }
+
+    pub fn into_keys(self) -> (String, String) {
+        (self.private_key, self.public_key)
+    }
+}
+
+
+/// A public key which has been rotated out, and when that happened.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PreviousKey {
+    public_key: String,
+    rotated_at: DateTime<Utc>
+}
+
+
+impl PreviousKey {
+    pub fn new<S: Into<String>>(public_key: S, rotated_at: DateTime<Utc>)
+        -> PreviousKey {
+
+        PreviousKey {
+            public_key: public_key.into(),
+            rotated_at
+        }
+    }
+
+    pub fn public_key(&self) -> &str { &self.public_key }
+    pub fn rotated_at(&self) -> &DateTime<Utc> { &self.rotated_at }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Private/public pairs produced by `wg genkey` and `wg pubkey`.
+    const WG_VECTORS: &[(&str, &str)] = &[
+        ("ADsIErTzl7FaGDI614/MM6Y4YL+edr6v1ls314Fx4Vc=",
+         "560oUL8qMUbEFcQRys3tm/IbO8DPz96Oy6xrVlPuIjk="),
+        ("6AXhGpbF36uRQNK3kt8SIwd1WJSGrfsdEnj89SArfls=",
+         "QEtcp4V4c79HH1aCGpZy237k96HU0thzHD66100upTQ="),
+        ("8EzIJ2g/8xq24d5dvLXTJjNhJKyjQ8Yzg0E5mWhKKFs=",
+         "TwUOO10hyrzdwGZAZoFS5yfPsaVVnVYEJWTtLMD+d2M=")
+    ];
+
+    #[test]
+    fn public_key_matches_wg_pubkey() {
+        for &(private_key, expected) in WG_VECTORS {
+            assert_eq!(public_key(private_key).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn public_key_matches_rfc7748() {
+        // RFC 7748 section 6.1, Alice and Bob.
+        assert_eq!(
+            public_key("dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=").unwrap(),
+            "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo=");
+        assert_eq!(
+            public_key("XasIfmJKikt54X+Lg4AO5m87sSkmGLb9HC+LJ/+I4Os=").unwrap(),
+            "3p7bfXt9wbTTW2HC7OQ1Nz+DQ8hbeGdNrfx+FG+IK08=");
+    }
+
+    #[test]
+    fn generated_keys_are_clamped() {
+        let key_pair = KeyPair::generate().unwrap();
+        let private_key = decode_key(key_pair.private_key()).unwrap();
+        assert_eq!(private_key[0] & 7, 0);
+        assert_eq!(private_key[31] & 128, 0);
+        assert_eq!(private_key[31] & 64, 64);
+        assert_eq!(public_key(key_pair.private_key()).unwrap(),
+                   key_pair.public_key());
+    }
+
+    #[test]
+    fn preshared_keys_are_unique() {
+        let a = generate_preshared_key().unwrap();
+        let b = generate_preshared_key().unwrap();
+        assert!(is_valid_key(&a));
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn invalid_private_key() {
+        assert!(public_key("not a key").is_err());
+        assert!(public_key("AAAA").is_err());
+    }
+}
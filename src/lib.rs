@@ -0,0 +1,54 @@
+//! Configuration management for hub-and-spoke wireguard networks.
+//!
+//! A `Configuration` holds one or more `Router`s and the `EndPoint` clients
+//! which connect to them. Configurations are loaded from and saved to yaml,
+//! and can render wireguard configs and setup scripts for every node.
+
+#![recursion_limit = "1024"]
+extern crate base64;
+extern crate chrono;
+#[macro_use] extern crate error_chain;
+extern crate image;
+extern crate ipnet;
+extern crate qrcode;
+extern crate rand_core;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate serde_json;
+extern crate serde_yaml;
+extern crate uuid;
+extern crate x25519_dalek;
+
+
+pub mod acl;
+pub mod addrport;
+pub mod cidr;
+pub mod configuration;
+pub mod diagnostic;
+pub mod endpoint;
+pub mod errors;
+pub mod firewall;
+pub mod group;
+pub mod import;
+pub mod interface;
+pub mod keys;
+pub mod networkd;
+pub mod nmconnection;
+pub mod output;
+mod one_or_many;
+mod private_file;
+pub mod qr;
+pub mod routeros;
+pub mod script;
+pub mod target;
+pub mod uci;
+pub mod update;
+
+
+pub use addrport::AddrPort;
+pub use configuration::{Configuration, Topology};
+pub use diagnostic::{Diagnostic, Severity};
+pub use endpoint::{EndPoint, Router, Tunnel};
+pub use errors::{Error, ErrorKind, Result};
+pub use interface::InterfaceSettings;
+pub use keys::KeyPair;
@@ -1,53 +1,590 @@
-#![allow(dead_code)]
+extern crate chrono;
 extern crate clap;
-extern crate ipnet;
+#[macro_use] extern crate error_chain;
 extern crate prettytable;
-extern crate serde;
-#[macro_use] extern crate serde_derive;
-extern crate serde_yaml;
+extern crate wireguard_configure;
 
 
-mod addrport;
-mod configuration;
-mod endpoint;
-
-
-use addrport::AddrPort;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
 use clap::{Arg, App, SubCommand};
-use configuration::Configuration;
-use endpoint::{EndPoint, Router};
-use ipnet::Ipv4Net;
-use prettytable::{Table, cell::Cell, row::Row};
-use std::net::Ipv4Addr;
+use wireguard_configure::{AddrPort, Configuration, EndPoint, Router};
+use wireguard_configure::errors::*;
+use wireguard_configure::keys::generate_preshared_key;
+use wireguard_configure::{acl, import, networkd, nmconnection, output, qr,
+                          routeros, script, uci, update};
+use wireguard_configure::update::{ClientUpdate, GroupUpdate, ListUpdate,
+                                  RouterUpdate, SettingsUpdate, TargetUpdate};
+use wireguard_configure::output::{Kind, Node, Output, Status};
+use prettytable::{Cell, Row, Table};
+use std::fmt::Display;
+use std::net::IpAddr;
 use std::path::Path;
 use std::process::exit;
+use std::str::FromStr;
 
 
-fn example_configuration() -> Configuration {
+fn example_configuration() -> Result<Configuration> {
     let router =
         Router::new(
             "vpn-router", "10.0.0.1".parse().unwrap(),
             AddrPort::new("vpn.com", 47654)
-        );
+        )?;
 
     let mut configuration = Configuration::new(router);
 
     configuration.push_client(
-        EndPoint::new("client-a", "10.0.1.1".parse().unwrap())
+        EndPoint::new("client-a", "10.0.1.1".parse().unwrap())?
             .builder_push_allowed_ips("10.0.1.0/24".parse().unwrap())
-            .builder_persistent_keepalive(Some(25)));
+            .builder_persistent_keepalive(Some(25)))?;
 
     configuration.push_client(
-        EndPoint::new("client-b", "10.0.2.1".parse().unwrap())
+        EndPoint::new("client-b", "10.0.2.1".parse().unwrap())?
             .builder_push_allowed_ips("10.0.2.0/24".parse().unwrap())
-            .builder_persistent_keepalive(Some(25)));
+            .builder_persistent_keepalive(Some(25)))?;
+
+    Ok(configuration)
+}
+
+
+/// Parse a comma-delimited list of addresses or subnets
+fn parse_addresses<T: FromStr>(addresses: &str) -> Result<Vec<T>> {
+    addresses.split(',')
+        .map(|address| address.trim().parse()
+            .map_err(|_| ErrorKind::AddressParse(address.to_string()).into()))
+        .collect()
+}
+
+
+/// Parse an optional command line value
+fn parse_arg<T: FromStr>(matches: &clap::ArgMatches, name: &str)
+    -> Result<Option<T>> {
+
+    match matches.value_of(name) {
+        Some(value) => value.parse()
+            .map(Some)
+            .map_err(|_| ErrorKind::InvalidArgument(name.to_string(),
+                                                    value.to_string()).into()),
+        None => Ok(None)
+    }
+}
+
+
+/// Parse an expiry date, which is either an RFC 3339 time, a date, meaning
+/// midnight UTC at the start of that day, or a number of hours, days or
+/// weeks from now such as `30d`
+fn parse_expiry(value: &str) -> Result<DateTime<Utc>> {
+    let invalid = || ErrorKind::InvalidArgument("expiry".to_string(),
+                                                value.to_string());
+
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap().and_utc());
+    }
+
+    // The unit may not be ASCII, so split on a character boundary.
+    let unit = value.chars().last().ok_or_else(invalid)?;
+    let count = &value[..value.len() - unit.len_utf8()];
+    let count: i64 = count.parse().map_err(|_| invalid())?;
+    let duration = match unit {
+        'h' => Duration::try_hours(count),
+        'd' => Duration::try_days(count),
+        'w' => Duration::try_weeks(count),
+        _ => None
+    };
+    match duration {
+        Some(duration) => Ok(Utc::now() + duration),
+        None => bail!(invalid())
+    }
+}
+
+
+/// A node's status for the `--list` table, which is blank for routers
+fn node_status(node: &Node) -> String {
+    match (node.kind(), node.status(), node.expires_at()) {
+        (Kind::Router, _, _) => String::new(),
+        (Kind::Client, Status::Active, Some(expires_at)) =>
+            format!("expires {}", expires_at.format("%Y-%m-%d %H:%M")),
+        (Kind::Client, status, _) => status.as_str().to_string()
+    }
+}
+
+
+/// The process exit code for each kind of error
+fn exit_code(error: &Error) -> i32 {
+    match *error.kind() {
+        ErrorKind::Io(..) => 2,
+        ErrorKind::Yaml(..) => 3,
+        ErrorKind::Key(..) => 4,
+        ErrorKind::AddressParse(..) |
+        ErrorKind::InvalidArgument(..) => 5,
+        ErrorKind::ClientNotFound(..) |
+        ErrorKind::RouterNotFound(..) => 6,
+        ErrorKind::ClientExists(..) |
+        ErrorKind::RouterExists(..) => 7,
+        ErrorKind::NoFreeAddress(..) => 8,
+        ErrorKind::InvalidConfiguration(..) => 9,
+        ErrorKind::QrCode(..) => 10,
+        ErrorKind::Import(..) => 11,
+        _ => 1
+    }
+}
+
+
+/// Parse a comma-delimited list of group names
+fn parse_groups(groups: &str) -> Result<Vec<String>> {
+    groups.split(',')
+        .map(str::trim)
+        .map(|group| if group.is_empty() {
+            Err(ErrorKind::InvalidArgument("groups".to_string(),
+                                           groups.to_string()).into())
+        }
+        else {
+            Ok(group.to_string())
+        })
+        .collect()
+}
+
 
-    configuration
+/// The replace, add and remove arguments named in `args`, each parsed with
+/// `parse`, as a change to a list
+fn list_update<T>(matches: &clap::ArgMatches, args: (&str, &str, &str),
+                  parse: fn(&str) -> Result<Vec<T>>)
+    -> Result<ListUpdate<T>> where T: Clone + PartialEq + Display {
+
+    let (replace, add, remove) = args;
+    let values = |name| -> Result<Vec<T>> {
+        match matches.value_of(name) {
+            Some(values) => parse(values),
+            None => Ok(Vec::new())
+        }
+    };
+
+    let mut update = ListUpdate::new();
+    if let Some(values) = matches.value_of(replace) {
+        update.set_replace(Some(parse(values)?));
+    }
+    update.set_add(values(add)?);
+    update.set_remove(values(remove)?);
+    Ok(update)
+}
+
+
+/// An optional command line value which `--no-<name>` removes
+fn parse_removable<T: FromStr>(matches: &clap::ArgMatches, name: &str)
+    -> Result<Option<Option<T>>> {
+
+    if let Some(value) = parse_arg(matches, name)? {
+        Ok(Some(Some(value)))
+    }
+    else if matches.is_present(format!("no-{}", name)) {
+        Ok(Some(None))
+    }
+    else {
+        Ok(None)
+    }
+}
+
+
+/// Arguments for the `wg-quick` interface settings, shared by every
+/// subcommand which changes them
+fn interface_args() -> Vec<Arg<'static, 'static>> {
+    let hook = |name: &'static str, help: &'static str| {
+        Arg::with_name(name)
+            .long(name)
+            .value_name("COMMAND")
+            .multiple(true)
+            .number_of_values(1)
+            .help(help)
+    };
+
+    vec![
+        Arg::with_name("dns")
+            .long("dns")
+            .value_name("DNS")
+            .help("Replace the DNS servers and search domains with this \
+                   comma-delimited list, or clear them with \"\""),
+        Arg::with_name("mtu")
+            .long("mtu")
+            .value_name("MTU")
+            .help("Set the interface MTU"),
+        Arg::with_name("no-mtu")
+            .long("no-mtu")
+            .conflicts_with("mtu")
+            .help("Remove the interface MTU"),
+        Arg::with_name("table")
+            .long("table")
+            .value_name("TABLE")
+            .help("Set the routing table wg-quick adds routes to, or off"),
+        Arg::with_name("no-table")
+            .long("no-table")
+            .conflicts_with("table")
+            .help("Remove the routing table"),
+        hook("pre-up", "Replace the PreUp commands, may be repeated"),
+        hook("post-up", "Replace the PostUp commands, may be repeated"),
+        hook("pre-down", "Replace the PreDown commands, may be repeated"),
+        hook("post-down", "Replace the PostDown commands, may be repeated"),
+        Arg::with_name("no-hooks")
+            .long("no-hooks")
+            .conflicts_with_all(&["pre-up", "post-up", "pre-down", "post-down"])
+            .help("Remove every PreUp, PostUp, PreDown and PostDown command")
+    ]
+}
+
+
+/// Arguments for where a node's config is deployed on the node itself
+fn target_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("interface-name")
+            .long("interface-name")
+            .value_name("INTERFACE")
+            .help("Set the interface scripts bring up, instead of wg0, or \
+                   utun9 on OS X"),
+        Arg::with_name("no-interface-name")
+            .long("no-interface-name")
+            .conflicts_with("interface-name")
+            .help("Remove the interface name"),
+        Arg::with_name("config-path")
+            .long("config-path")
+            .value_name("PATH")
+            .help("Set where scripts write the config, instead of a file \
+                   named after the interface, or vpn.conf"),
+        Arg::with_name("no-config-path")
+            .long("no-config-path")
+            .conflicts_with("config-path")
+            .help("Remove the config path"),
+        Arg::with_name("listen-port")
+            .long("listen-port")
+            .value_name("PORT")
+            .help("Set the port wireguard listens on, instead of the port of \
+                   the external address"),
+        Arg::with_name("no-listen-port")
+            .long("no-listen-port")
+            .conflicts_with("listen-port")
+            .help("Remove the listen port")
+    ]
+}
+
+
+/// `--output`, which only `--list`, `router-config` and `client-config`
+/// take
+fn output_arg() -> Arg<'static, 'static> {
+    Arg::with_name("output")
+        .long("output")
+        .value_name("OUTPUT")
+        .possible_values(&["json", "yaml", "table", "text"])
+        .help("How the config prints, by default as text")
+}
+
+
+/// The arguments from `target_args`
+fn target_update(matches: &clap::ArgMatches) -> Result<TargetUpdate> {
+    let mut update = TargetUpdate::new();
+    update.set_interface_name(parse_removable(matches, "interface-name")?);
+    update.set_config_path(parse_removable(matches, "config-path")?);
+    update.set_listen_port(parse_removable(matches, "listen-port")?);
+    Ok(update)
+}
+
+
+/// The arguments from `interface_args`
+fn settings_update(matches: &clap::ArgMatches) -> Result<SettingsUpdate> {
+    let mut update = SettingsUpdate::new();
+
+    update.set_dns(matches.value_of("dns").map(|dns| dns.split(',')
+        .map(|dns| dns.trim().to_string())
+        .filter(|dns| !dns.is_empty())
+        .collect()));
+    update.set_mtu(parse_removable(matches, "mtu")?);
+    update.set_table(parse_removable(matches, "table")?);
+
+    let commands = |name| -> Option<Vec<String>> {
+        if matches.is_present("no-hooks") {
+            return Some(Vec::new());
+        }
+        matches.values_of(name)
+            .map(|values| values.map(|value| value.to_string()).collect())
+    };
+    update.set_pre_up(commands("pre-up"));
+    update.set_post_up(commands("post-up"));
+    update.set_pre_down(commands("pre-down"));
+    update.set_post_down(commands("post-down"));
+
+    Ok(update)
+}
+
+
+/// The router a subcommand applies to, which may only be left out when
+/// there is a single router
+fn router_name(configuration: &Configuration, name: Option<&str>)
+    -> Result<String> {
+
+    match name {
+        Some(name) => Ok(configuration.router(name)?.name().to_string()),
+        None if configuration.routers().len() == 1 =>
+            Ok(configuration.routers()[0].name().to_string()),
+        None => bail!("there is more than one router, a router name must be \
+                       given")
+    }
+}
+
+
+/// `update-client`'s arguments
+fn client_update(matches: &clap::ArgMatches) -> Result<ClientUpdate> {
+    let mut update = ClientUpdate::new();
+
+    update.set_groups(list_update(matches,
+        ("set-groups", "add-groups", "remove-groups"), parse_groups)?);
+
+    if let Some(expires) = matches.value_of("expires") {
+        update.set_expires_at(Some(Some(parse_expiry(expires)?)));
+    }
+    else if matches.is_present("no-expires") {
+        update.set_expires_at(Some(None));
+    }
+
+    if matches.is_present("disable") {
+        update.set_disabled(Some(true));
+    }
+    else if matches.is_present("enable") {
+        update.set_disabled(Some(false));
+    }
+
+    update.set_internal_addresses(list_update(matches,
+        ("internal-address",
+         "add-internal-address",
+         "remove-internal-address"), parse_addresses)?);
+    update.set_allowed_ips(list_update(matches,
+        ("allowed-ips", "add-allowed-ips", "remove-allowed-ips"),
+        parse_addresses)?);
+    update.set_external_address(
+        parse_removable(matches, "external-address")?);
+    update.set_persistent_keepalive(
+        parse_removable(matches, "persistent-keepalive")?);
+    update.set_public_key(parse_arg(matches, "public-key")?);
+    update.set_primary_router(parse_arg(matches, "primary-router")?);
+    update.set_secondary_router(
+        parse_removable(matches, "secondary-router")?);
+    update.set_tunnel(parse_arg(matches, "tunnel")?);
+    update.set_excluded_routes(list_update(matches,
+        ("exclude", "add-exclude", "remove-exclude"), parse_addresses)?);
+    update.set_settings(settings_update(matches)?);
+    update.set_target(target_update(matches)?);
+
+    Ok(update)
+}
+
+
+/// `update-router`'s arguments
+fn router_update(matches: &clap::ArgMatches) -> Result<RouterUpdate> {
+    let mut update = RouterUpdate::new();
+
+    if let Some(values) = matches.value_of("master-subnet") {
+        update.set_master_subnets(Some(parse_addresses(values)?));
+    }
+    else if matches.is_present("no-master-subnet") {
+        update.set_master_subnets(Some(Vec::new()));
+    }
+    update.set_topology(parse_arg(matches, "topology")?);
+
+    update.set_internal_addresses(list_update(matches,
+        ("internal-address",
+         "add-internal-address",
+         "remove-internal-address"), parse_addresses)?);
+    update.set_external_address(parse_arg(matches, "external-address")?);
+    update.set_settings(settings_update(matches)?);
+    update.set_target(target_update(matches)?);
+
+    update.set_egress_interface(
+        parse_removable(matches, "egress-interface")?);
+    update.set_masquerade(matches.value_of("masquerade")
+        .map(|masquerade| match masquerade {
+            "all" => Some(true),
+            "none" => Some(false),
+            _ => None
+        }));
+    update.set_forward_policy(parse_removable(matches, "forward-policy")?);
+
+    Ok(update)
+}
+
+
+/// The clients a subcommand applies to: the named client, or with
+/// `--group`, every client in the group
+fn client_names(configuration: &Configuration, matches: &clap::ArgMatches)
+    -> Result<Vec<String>> {
+
+    match matches.value_of("group") {
+        Some(group) => update::group_client_names(configuration, group),
+        None => {
+            let name = matches.value_of("name").unwrap();
+            Ok(vec![configuration.client(name)?.name().to_string()])
+        }
+    }
+}
+
+
+/// The `--output` of `router-config` or `client-config`, unless it is the
+/// default text, which can't be combined with scripts, QR codes or other
+/// formats
+fn config_output(matches: &clap::ArgMatches) -> Result<Option<Output>> {
+    let output = match parse_arg(matches, "output")? {
+        Some(Output::Text) | None => return Ok(None),
+        Some(output) => output
+    };
+    for &argument in &["linux-script", "osx-script", "qr", "qr-png", "qr-svg",
+                       "output-dir"] {
+        if matches.is_present(argument) {
+            bail!(ErrorKind::InvalidArgument("output".to_string(),
+                format!("{} (can't be combined with --{})",
+                    matches.value_of("output").unwrap(), argument)));
+        }
+    }
+    match matches.value_of("format") {
+        Some("wg") | Some("wg-quick") | None => Ok(Some(output)),
+        Some(format) => bail!(ErrorKind::InvalidArgument("output".to_string(),
+            format!("{} (can't be combined with --format {})",
+                matches.value_of("output").unwrap(), format)))
+    }
+}
+
+
+/// Print a config as JSON, YAML or tables of the interface and its peers
+fn print_config(output: Output, config: &output::Config) -> Result<()> {
+    if output.is_structured() {
+        println!("{}", output.serialize(config)?);
+        return Ok(());
+    }
+
+    let interface = config.interface();
+    let mut table = Table::new();
+    table.add_row(Row::new(vec![
+        Cell::new("Interface"),
+        Cell::new("Addresses"),
+        Cell::new("Listen Port"),
+        Cell::new("Public Key")
+    ]));
+    table.add_row(Row::new(vec![
+        Cell::new(interface.name()),
+        Cell::new(&join_addresses(interface.addresses())),
+        Cell::new(&interface.listen_port()
+            .map(|port| port.to_string())
+            .unwrap_or_default()),
+        Cell::new(interface.public_key())
+    ]));
+    table.printstd();
+
+    let mut table = Table::new();
+    table.add_row(Row::new(vec![
+        Cell::new("Peer"),
+        Cell::new("Public Key"),
+        Cell::new("Endpoint"),
+        Cell::new("Allowed IPs"),
+        Cell::new("Keepalive")
+    ]));
+    for peer in config.peers() {
+        table.add_row(Row::new(vec![
+            Cell::new(peer.name().unwrap_or("")),
+            Cell::new(peer.public_key()),
+            Cell::new(peer.endpoint().unwrap_or("")),
+            Cell::new(&join_addresses(peer.allowed_ips())),
+            Cell::new(&peer.persistent_keepalive()
+                .map(|keepalive| keepalive.to_string())
+                .unwrap_or_default())
+        ]));
+    }
+    table.printstd();
+    Ok(())
+}
+
+
+/// Print networkd units, or write them into `--output-dir`. The netdev
+/// holds the private key, so systemd-networkd reads it through its group.
+fn print_units(matches: &clap::ArgMatches, units: networkd::Units)
+    -> Result<()> {
+
+    match matches.value_of("output-dir") {
+        Some(directory) => {
+            for path in units.write(Path::new(directory))? {
+                println!("Wrote {}", path.display());
+            }
+            println!("Give them to the systemd-network group with chgrp, \
+                      then run networkctl reload");
+        },
+        None => println!("{}", units)
+    }
+    Ok(())
+}
+
+
+/// `--output-dir` only applies to networkd units
+fn check_output_dir(matches: &clap::ArgMatches) -> Result<()> {
+    match matches.value_of("output-dir") {
+        Some(directory) if matches.value_of("format") != Some("networkd") =>
+            bail!(ErrorKind::InvalidArgument("output-dir".to_string(),
+                format!("{} (only networkd units are written to a \
+                         directory)", directory))),
+        _ => Ok(())
+    }
+}
+
+
+/// The gateways given for routeros commands, which are refused for any
+/// other format
+fn gateways(matches: &clap::ArgMatches) -> Result<Vec<IpAddr>> {
+    match matches.value_of("gateway") {
+        Some(gateways) if matches.value_of("format") != Some("routeros") =>
+            bail!(ErrorKind::InvalidArgument("gateway".to_string(),
+                format!("{} (only routeros commands use a gateway)",
+                        gateways))),
+        Some(gateways) => parse_addresses(gateways),
+        None => Ok(Vec::new())
+    }
+}
+
+
+fn join_addresses<T: ToString>(addresses: &[T]) -> String {
+    addresses.iter()
+        .map(|address| address.to_string())
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+
+/// Print the configs to redeploy after pruning clients or rotating keys
+fn print_redeploy(redeploy: &update::Redeploy) {
+    if redeploy.is_empty() {
+        return;
+    }
+    println!("Redeploy these configs:");
+    for router in redeploy.routers() {
+        println!("  router-config {}", router);
+    }
+    for client in redeploy.clients() {
+        println!("  client-config {}", client);
+    }
 }
 
 
 
 fn main () {
+    if let Err(error) = run() {
+        if let ErrorKind::InvalidConfiguration(ref diagnostics) = *error.kind() {
+            for diagnostic in diagnostics {
+                eprintln!("{}", diagnostic);
+            }
+        }
+        eprintln!("Error: {}", error);
+        for cause in error.iter().skip(1) {
+            eprintln!("Caused by: {}", cause);
+        }
+        exit(exit_code(&error));
+    }
+}
+
+
+fn run() -> Result<()> {
     let matches =
         App::new("wireguard-configure")
             .version("0.0.1")
@@ -65,6 +602,54 @@ fn main () {
                 .long("list")
                 .conflicts_with("example")
                 .help("List clients in this configuration"))
+            .arg(Arg::with_name("group")
+                .long("group")
+                .value_name("GROUP")
+                .requires("list")
+                .help("Only list the clients in this group"))
+            .arg(output_arg()
+                .requires("list")
+                .help("How --list prints, by default a table"))
+            .subcommand(
+                SubCommand::with_name("check")
+                    .about("Check the configuration for errors"))
+            .subcommand(
+                SubCommand::with_name("firewall")
+                    .about("Print an nftables ruleset for a router which \
+                            only lets clients reach each other as the acl \
+                            allows")
+                .arg(Arg::with_name("name")
+                    .value_name("NAME")
+                    .help("Name of router to print the ruleset for, which \
+                           may be left out when there is only one")))
+            .subcommand(
+                SubCommand::with_name("import")
+                    .about("Create the configuration from existing wg-quick \
+                            or wg setconf files")
+                .arg(Arg::with_name("router")
+                    .value_name("ROUTER_CONF")
+                    .required(true)
+                    .help("The router's config file"))
+                .arg(Arg::with_name("clients")
+                    .value_name("CLIENT_CONF")
+                    .multiple(true)
+                    .help("Config files of the router's clients, which \
+                           supply their private keys and addresses"))
+                .arg(Arg::with_name("external-address")
+                    .short("e")
+                    .long("external-address")
+                    .value_name("ADDRESS:PORT")
+                    .help("Address clients reach the router on, if no \
+                           client config has it"))
+                .arg(Arg::with_name("internal-address")
+                    .short("i")
+                    .long("internal-address")
+                    .value_name("INTERNAL_ADDRESS")
+                    .help("Comma-delimited internal addresses of the router, \
+                           if its config has no Address"))
+                .arg(Arg::with_name("force")
+                    .long("force")
+                    .help("Overwrite an existing configuration")))
             .subcommand(
                 SubCommand::with_name("add-client")
                     .about("Add a client to the configuration")
@@ -84,8 +669,21 @@ fn main () {
                     .short("i")
                     .long("internal-address")
                     .value_name("INTERNAL_ADDRESS")
-                    .required(true)
-                    .help("Internal address for the new client"))
+                    .help("A comma-delimited list of internal addresses for \
+                           the new client. Allocated from the master subnet \
+                           when omitted"))
+                .arg(Arg::with_name("subnet")
+                    .long("subnet")
+                    .value_name("PREFIX_LEN")
+                    .conflicts_with("internal-address")
+                    .help("Allocate a free IPv4 subnet of this size for the \
+                           new client, instead of a single address"))
+                .arg(Arg::with_name("subnet6")
+                    .long("subnet6")
+                    .value_name("PREFIX_LEN")
+                    .conflicts_with("internal-address")
+                    .help("Allocate a free IPv6 subnet of this size for the \
+                           new client, instead of a single address"))
                 .arg(Arg::with_name("persistent-keepalive")
                     .short("p")
                     .long("persistent-keepalive")
@@ -95,21 +693,387 @@ fn main () {
                     .short("a")
                     .long("allowed-ips")
                     .value_name("ALLOWED_IPS")
-                    .help("An comma-delimited list of subnets for this client")))
+                    .help("An comma-delimited list of subnets for this client"))
+                .arg(Arg::with_name("psk")
+                    .long("psk")
+                    .help("Generate a preshared key for this client"))
+                .arg(Arg::with_name("primary-router")
+                    .long("primary-router")
+                    .value_name("ROUTER")
+                    .help("Router the client routes through, instead of the \
+                           first router"))
+                .arg(Arg::with_name("secondary-router")
+                    .long("secondary-router")
+                    .value_name("ROUTER")
+                    .help("Router the client can fail over to"))
+                .arg(Arg::with_name("tunnel")
+                    .long("tunnel")
+                    .value_name("TUNNEL")
+                    .possible_values(&["split", "full"])
+                    .help("Route only the wireguard network through the \
+                           router, or everything"))
+                .arg(Arg::with_name("exclude")
+                    .long("exclude")
+                    .value_name("SUBNETS")
+                    .help("A comma-delimited list of subnets which are never \
+                           routed through the router"))
+                .arg(Arg::with_name("groups")
+                    .long("groups")
+                    .value_name("GROUPS")
+                    .help("A comma-delimited list of groups to put the client \
+                           in"))
+                .arg(Arg::with_name("expires")
+                    .long("expires")
+                    .value_name("WHEN")
+                    .help("When the client expires: an RFC 3339 time, a date, \
+                           or a duration such as 12h, 30d or 2w")))
+            .subcommand(
+                SubCommand::with_name("add-router")
+                    .about("Add another router to the configuration")
+                .arg(Arg::with_name("name")
+                    .short("n")
+                    .long("name")
+                    .value_name("NAME")
+                    .required(true)
+                    .help("Name for the new router"))
+                .arg(Arg::with_name("external-address")
+                    .short("e")
+                    .long("external-address")
+                    .value_name("ADDRESS:PORT")
+                    .required(true)
+                    .help("Address clients reach the router on"))
+                .arg(Arg::with_name("internal-address")
+                    .short("i")
+                    .long("internal-address")
+                    .value_name("INTERNAL_ADDRESS")
+                    .help("A comma-delimited list of internal addresses for \
+                           the new router. Allocated from the master subnet \
+                           when omitted")))
+            .subcommand(
+                SubCommand::with_name("remove-router")
+                    .about("Remove a router from the configuration")
+                .arg(Arg::with_name("name")
+                    .value_name("NAME")
+                    .required(true)
+                    .help("Name of router to remove")))
             .subcommand(
                 SubCommand::with_name("remove-client")
                     .about("Remove a client from the configuration")
+                .arg(Arg::with_name("name")
+                    .value_name("NAME")
+                    .required_unless("group")
+                    .help("Name of client to remove"))
+                .arg(Arg::with_name("group")
+                    .long("group")
+                    .value_name("GROUP")
+                    .conflicts_with("name")
+                    .help("Remove every client in this group instead")))
+            .subcommand(
+                SubCommand::with_name("update-client")
+                    .about("Change an existing client")
+                .arg(Arg::with_name("name")
+                    .value_name("NAME")
+                    .required_unless("group")
+                    .help("Name of client to change"))
+                .arg(Arg::with_name("group")
+                    .long("group")
+                    .value_name("GROUP")
+                    .conflicts_with_all(&["name", "internal-address",
+                                          "add-internal-address",
+                                          "remove-internal-address",
+                                          "allowed-ips", "add-allowed-ips",
+                                          "remove-allowed-ips",
+                                          "external-address", "public-key"])
+                    .help("Change every client in this group instead"))
+                .arg(Arg::with_name("set-groups")
+                    .long("set-groups")
+                    .value_name("GROUPS")
+                    .help("Replace the client's groups with this \
+                           comma-delimited list"))
+                .arg(Arg::with_name("add-groups")
+                    .long("add-groups")
+                    .value_name("GROUPS")
+                    .help("Put the client in these comma-delimited groups"))
+                .arg(Arg::with_name("remove-groups")
+                    .long("remove-groups")
+                    .value_name("GROUPS")
+                    .help("Take the client out of these comma-delimited \
+                           groups"))
+                .arg(Arg::with_name("expires")
+                    .long("expires")
+                    .value_name("WHEN")
+                    .help("When the client expires: an RFC 3339 time, a date, \
+                           or a duration such as 12h, 30d or 2w"))
+                .arg(Arg::with_name("no-expires")
+                    .long("no-expires")
+                    .conflicts_with("expires")
+                    .help("Remove the client's expiry"))
+                .arg(Arg::with_name("disable")
+                    .long("disable")
+                    .help("Leave the client out of router configs"))
+                .arg(Arg::with_name("enable")
+                    .long("enable")
+                    .conflicts_with("disable")
+                    .help("Put a disabled client back in router configs"))
+                .arg(Arg::with_name("internal-address")
+                    .long("internal-address")
+                    .value_name("INTERNAL_ADDRESS")
+                    .help("Replace the client's internal addresses with this \
+                           comma-delimited list"))
+                .arg(Arg::with_name("add-internal-address")
+                    .long("add-internal-address")
+                    .value_name("INTERNAL_ADDRESS")
+                    .help("Add these comma-delimited internal addresses"))
+                .arg(Arg::with_name("remove-internal-address")
+                    .long("remove-internal-address")
+                    .value_name("INTERNAL_ADDRESS")
+                    .help("Remove these comma-delimited internal addresses"))
+                .arg(Arg::with_name("allowed-ips")
+                    .long("allowed-ips")
+                    .value_name("ALLOWED_IPS")
+                    .help("Replace the client's allowed ips with this \
+                           comma-delimited list"))
+                .arg(Arg::with_name("add-allowed-ips")
+                    .long("add-allowed-ips")
+                    .value_name("ALLOWED_IPS")
+                    .help("Add these comma-delimited subnets to the client's \
+                           allowed ips"))
+                .arg(Arg::with_name("remove-allowed-ips")
+                    .long("remove-allowed-ips")
+                    .value_name("ALLOWED_IPS")
+                    .help("Remove these comma-delimited subnets from the \
+                           client's allowed ips"))
+                .arg(Arg::with_name("external-address")
+                    .long("external-address")
+                    .value_name("ADDRESS:PORT")
+                    .help("Address other nodes reach this client on"))
+                .arg(Arg::with_name("no-external-address")
+                    .long("no-external-address")
+                    .conflicts_with("external-address")
+                    .help("Remove the client's external address"))
+                .arg(Arg::with_name("persistent-keepalive")
+                    .long("persistent-keepalive")
+                    .value_name("PERSISTENT_KEEPALIVE")
+                    .help("Set the client's persistent keepalive"))
+                .arg(Arg::with_name("no-persistent-keepalive")
+                    .long("no-persistent-keepalive")
+                    .conflicts_with("persistent-keepalive")
+                    .help("Remove the client's persistent keepalive"))
+                .arg(Arg::with_name("public-key")
+                    .long("public-key")
+                    .value_name("PUBLIC_KEY")
+                    .help("Use the given public key, and forget the client's \
+                           private key"))
+                .arg(Arg::with_name("primary-router")
+                    .long("primary-router")
+                    .value_name("ROUTER")
+                    .help("Route the client through this router"))
+                .arg(Arg::with_name("secondary-router")
+                    .long("secondary-router")
+                    .value_name("ROUTER")
+                    .help("Router the client can fail over to"))
+                .arg(Arg::with_name("no-secondary-router")
+                    .long("no-secondary-router")
+                    .conflicts_with("secondary-router")
+                    .help("Remove the client's secondary router"))
+                .arg(Arg::with_name("tunnel")
+                    .long("tunnel")
+                    .value_name("TUNNEL")
+                    .possible_values(&["split", "full"])
+                    .help("Route only the wireguard network through the \
+                           router, or everything"))
+                .arg(Arg::with_name("exclude")
+                    .long("exclude")
+                    .value_name("SUBNETS")
+                    .help("Replace the client's excluded routes with this \
+                           comma-delimited list"))
+                .arg(Arg::with_name("add-exclude")
+                    .long("add-exclude")
+                    .value_name("SUBNETS")
+                    .help("Add these comma-delimited subnets to the client's \
+                           excluded routes"))
+                .arg(Arg::with_name("remove-exclude")
+                    .long("remove-exclude")
+                    .value_name("SUBNETS")
+                    .help("Remove these comma-delimited subnets from the \
+                           client's excluded routes"))
+                .args(&interface_args())
+                .args(&target_args()))
+            .subcommand(
+                SubCommand::with_name("update-router")
+                    .about("Change a router")
+                .arg(Arg::with_name("name")
+                    .value_name("NAME")
+                    .help("Name of router to change, which may be left out \
+                           when there is only one"))
+                .arg(Arg::with_name("internal-address")
+                    .long("internal-address")
+                    .value_name("INTERNAL_ADDRESS")
+                    .help("Replace the router's internal addresses with this \
+                           comma-delimited list"))
+                .arg(Arg::with_name("add-internal-address")
+                    .long("add-internal-address")
+                    .value_name("INTERNAL_ADDRESS")
+                    .help("Add these comma-delimited internal addresses"))
+                .arg(Arg::with_name("remove-internal-address")
+                    .long("remove-internal-address")
+                    .value_name("INTERNAL_ADDRESS")
+                    .help("Remove these comma-delimited internal addresses"))
+                .arg(Arg::with_name("external-address")
+                    .long("external-address")
+                    .value_name("ADDRESS:PORT")
+                    .help("Address clients reach the router on"))
+                .arg(Arg::with_name("master-subnet")
+                    .long("master-subnet")
+                    .value_name("MASTER_SUBNET")
+                    .help("Replace the master subnets with this \
+                           comma-delimited list"))
+                .arg(Arg::with_name("no-master-subnet")
+                    .long("no-master-subnet")
+                    .conflicts_with("master-subnet")
+                    .help("Remove the master subnets"))
+                .arg(Arg::with_name("topology")
+                    .long("topology")
+                    .value_name("TOPOLOGY")
+                    .possible_values(&["hub-and-spoke", "mesh"])
+                    .help("Whether clients peer only with the router, or \
+                           also directly with each other"))
+                .arg(Arg::with_name("egress-interface")
+                    .long("egress-interface")
+                    .value_name("INTERFACE")
+                    .help("Interface masqueraded traffic leaves the router \
+                           by"))
+                .arg(Arg::with_name("no-egress-interface")
+                    .long("no-egress-interface")
+                    .conflicts_with("egress-interface")
+                    .help("Masquerade anything not leaving by wireguard"))
+                .arg(Arg::with_name("masquerade")
+                    .long("masquerade")
+                    .value_name("MASQUERADE")
+                    .possible_values(&["all", "none", "full-tunnel"])
+                    .help("Which clients the router masquerades"))
+                .arg(Arg::with_name("forward-policy")
+                    .long("forward-policy")
+                    .value_name("POLICY")
+                    .possible_values(&["accept", "drop"])
+                    .help("Policy for forwarded traffic not to or from \
+                           wireguard"))
+                .arg(Arg::with_name("no-forward-policy")
+                    .long("no-forward-policy")
+                    .conflicts_with("forward-policy")
+                    .help("Leave the forward policy alone"))
+                .args(&target_args())
+                .args(&interface_args()))
+            .subcommand(
+                SubCommand::with_name("update-client-defaults")
+                    .about("Change the wg-quick interface settings for every \
+                            client which doesn't set its own")
+                .args(&interface_args()))
+            .subcommand(
+                SubCommand::with_name("update-group")
+                    .about("Change the settings shared by every client in a \
+                            group")
+                .arg(Arg::with_name("name")
+                    .value_name("NAME")
+                    .required(true)
+                    .help("Name of group to change"))
+                .arg(Arg::with_name("routes")
+                    .long("routes")
+                    .value_name("SUBNETS")
+                    .help("Replace the networks members route through their \
+                           router with this comma-delimited list"))
+                .arg(Arg::with_name("add-routes")
+                    .long("add-routes")
+                    .value_name("SUBNETS")
+                    .help("Add these comma-delimited subnets to the group's \
+                           routes"))
+                .arg(Arg::with_name("remove-routes")
+                    .long("remove-routes")
+                    .value_name("SUBNETS")
+                    .help("Remove these comma-delimited subnets from the \
+                           group's routes"))
+                .args(&interface_args()))
+            .subcommand(
+                SubCommand::with_name("remove-group")
+                    .about("Remove a group's settings, and take every client \
+                            out of it")
+                .arg(Arg::with_name("name")
+                    .value_name("NAME")
+                    .required(true)
+                    .help("Name of group to remove")))
+            .subcommand(
+                SubCommand::with_name("prune")
+                    .about("Remove every client which has expired")
+                .arg(Arg::with_name("disable")
+                    .long("disable")
+                    .help("Disable expired clients instead of removing them")))
+            .subcommand(
+                SubCommand::with_name("rotate-keys")
+                    .about("Generate new keys, keeping a record of the old \
+                            public keys")
+                .arg(Arg::with_name("name")
+                    .value_name("NAME")
+                    .required_unless_one(&["router", "all", "group"])
+                    .help("Name of client to rotate keys for"))
+                .arg(Arg::with_name("group")
+                    .long("group")
+                    .value_name("GROUP")
+                    .conflicts_with("name")
+                    .help("Rotate keys for every client in this group"))
+                .arg(Arg::with_name("router")
+                    .long("router")
+                    .value_name("ROUTER")
+                    .min_values(0)
+                    .max_values(1)
+                    .help("Rotate a router's keys. The name may be left out \
+                           when there is only one router"))
+                .arg(Arg::with_name("all")
+                    .long("all")
+                    .conflicts_with_all(&["name", "router", "group"])
+                    .help("Rotate keys for every router and client")))
+            .subcommand(
+                SubCommand::with_name("regenerate-psk")
+                    .about("Generate a new preshared key for a client")
                 .arg(Arg::with_name("name")
                     .value_name("NAME")
                     .required(true)
-                    .help("Name of client to remove")))
+                    .help("Name of client to generate a preshared key for"))
+                .arg(Arg::with_name("remove")
+                    .long("remove")
+                    .help("Remove the client's preshared key instead")))
             .subcommand(
                 SubCommand::with_name("router-config")
                     .about("Dump router config")
+                .arg(Arg::with_name("name")
+                    .value_name("NAME")
+                    .help("Name of router to dump configuration for, which \
+                           may be left out when there is only one"))
                 .arg(Arg::with_name("linux-script")
                     .short("l")
                     .long("linux-script")
-                    .help("Dump as bash script for linux")))
+                    .help("Dump as bash script for linux"))
+                .arg(Arg::with_name("firewall")
+                    .long("firewall")
+                    .value_name("FIREWALL")
+                    .possible_values(&["iptables", "nftables"])
+                    .default_value("iptables")
+                    .help("Firewall the linux script's forwarding and NAT \
+                           rules are for"))
+                .arg(Arg::with_name("format")
+                    .short("f")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .possible_values(&["wg", "wg-quick", "networkd",
+                                       "routeros"])
+                    .default_value("wg")
+                    .help("Config file format"))
+                .arg(Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("DIR")
+                    .help("Write the networkd units into this directory, \
+                           such as /etc/systemd/network"))
+                .arg(output_arg())
+                .args(&target_args()))
             .subcommand(
                 SubCommand::with_name("client-config")
                     .about("Dump client config")
@@ -125,189 +1089,563 @@ fn main () {
                     .short("o")
                     .long("osx-script")
                     .conflicts_with("linux-script")
-                    .help("Dump as bash script for Mac OS X")))
+                    .help("Dump as bash script for Mac OS X"))
+                .arg(Arg::with_name("failover")
+                    .long("failover")
+                    .help("Route through the client's secondary router \
+                           instead of its primary"))
+                .arg(Arg::with_name("qr")
+                    .long("qr")
+                    .conflicts_with_all(&["linux-script", "osx-script"])
+                    .help("Print the wg-quick config as a QR code"))
+                .arg(Arg::with_name("qr-png")
+                    .long("qr-png")
+                    .value_name("FILE")
+                    .conflicts_with_all(&["linux-script", "osx-script"])
+                    .help("Write the wg-quick config as a QR code PNG"))
+                .arg(Arg::with_name("qr-svg")
+                    .long("qr-svg")
+                    .value_name("FILE")
+                    .conflicts_with_all(&["linux-script", "osx-script"])
+                    .help("Write the wg-quick config as a QR code SVG"))
+                .arg(Arg::with_name("format")
+                    .short("f")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .possible_values(&["wg", "wg-quick", "networkd",
+                                       "nmconnection", "uci", "routeros"])
+                    .default_value("wg")
+                    .help("Config file format"))
+                .arg(Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("DIR")
+                    .help("Write the networkd units into this directory, \
+                           such as /etc/systemd/network"))
+                .arg(Arg::with_name("gateway")
+                    .long("gateway")
+                    .value_name("ADDRESSES")
+                    .help("Comma-delimited default gateways, one for each \
+                           address family, which a full tunnel's routeros \
+                           commands route the peers' endpoints through"))
+                .arg(output_arg())
+                .args(&target_args()))
             .get_matches();
 
     let filename = matches.value_of("config").unwrap();
 
     if matches.is_present("example") {
-        let configuration = example_configuration();
+        let configuration = example_configuration()?;
 
-        configuration.save(Path::new(filename));
+        configuration.save(Path::new(filename))?;
 
         println!("Configuration saved to file");
     }
 
     else if matches.is_present("list") {
-        let configuration = Configuration::open(Path::new(filename));
+        let configuration = Configuration::open(Path::new(filename))?;
+        let output = parse_arg(&matches, "output")?.unwrap_or(Output::Table);
+        let listing = output::listing(&configuration, matches.value_of("group"),
+                                      &Utc::now());
 
-        let mut table = Table::new();
+        match output {
+            Output::Json | Output::Yaml =>
+                println!("{}", output.serialize(&listing)?),
+            Output::Table => {
+                let mut table = Table::new();
 
-        table.add_row(Row::new(vec![
-            Cell::new("Name"),
-            Cell::new("Internal Address"),
-            Cell::new("Allowed IPs")
-        ]));
+                table.add_row(Row::new(vec![
+                    Cell::new("Name"),
+                    Cell::new("Internal Address"),
+                    Cell::new("Allowed IPs"),
+                    Cell::new("Groups"),
+                    Cell::new("Status")
+                ]));
 
-        table.add_row(Row::new(vec![
-            Cell::new(configuration.router().name()),
-            Cell::new(&format!("{}", configuration.router().internal_address())),
-            Cell::new("")
-        ]));
+                for node in listing.nodes() {
+                    table.add_row(Row::new(vec![
+                        Cell::new(node.name()),
+                        Cell::new(&join_addresses(node.internal_addresses())),
+                        Cell::new(&join_addresses(node.allowed_ips())),
+                        Cell::new(&node.groups().join(",")),
+                        Cell::new(&node_status(node))
+                    ]));
+                }
+
+                table.printstd();
+            },
+            // One tab-separated line per node, for cut and awk.
+            Output::Text => {
+                for node in listing.nodes() {
+                    println!("{}\t{}\t{}\t{}\t{}",
+                        node.name(),
+                        join_addresses(node.internal_addresses()),
+                        join_addresses(node.allowed_ips()),
+                        node.groups().join(","),
+                        node_status(node));
+                }
+            }
+        }
+    }
+
+    else if matches.subcommand_matches("check").is_some() {
+        let configuration = Configuration::open(Path::new(filename))?;
+
+        let now = Utc::now();
+        let diagnostics = configuration.validate(&now);
+        if diagnostics.iter().any(|diagnostic| diagnostic.is_error()) {
+            bail!(ErrorKind::InvalidConfiguration(diagnostics));
+        }
+
+        for diagnostic in &diagnostics {
+            println!("{}", diagnostic);
+        }
+        if diagnostics.is_empty() {
+            println!("Configuration is valid");
+        }
+    }
+
+    else if let Some(matches) = matches.subcommand_matches("firewall") {
+        let configuration = Configuration::open(Path::new(filename))?;
+        let name = router_name(&configuration, matches.value_of("name"))?;
+
+        println!("{}", acl::nftables_ruleset(&configuration,
+            configuration.router(&name)?)?);
+    }
 
-        for client in configuration.clients() {
-            table.add_row(Row::new(vec![
-                Cell::new(client.name()),
-                Cell::new(&format!("{}", client.internal_address())),
-                Cell::new(
-                    &client.allowed_ips().iter()
-                        .map(|ip| format!("{}", ip))
-                        .collect::<Vec<String>>()
-                        .join(","))
-            ]));
+    else if let Some(matches) = matches.subcommand_matches("import") {
+        if Path::new(filename).exists() && !matches.is_present("force") {
+            bail!("{} already exists, pass --force to overwrite it", filename);
         }
 
-        table.printstd();
+        let router = import::WgConfig::open(
+            Path::new(matches.value_of("router").unwrap()))?;
+        let clients = matches.values_of("clients")
+            .map(|clients| clients
+                .map(|client| import::WgConfig::open(Path::new(client)))
+                .collect::<Result<Vec<import::WgConfig>>>())
+            .unwrap_or_else(|| Ok(Vec::new()))?;
+
+        let internal_addresses = match matches.value_of("internal-address") {
+            Some(addresses) => Some(parse_addresses(addresses)?),
+            None => None
+        };
+
+        let (configuration, diagnostics) = import::import(&router, &clients,
+            parse_arg(matches, "external-address")?, internal_addresses)?;
+        for diagnostic in diagnostics {
+            eprintln!("{}", diagnostic);
+        }
+
+        configuration.save(Path::new(filename))?;
+
+        println!("Imported router {} with {} clients",
+            configuration.routers()[0].name(), configuration.clients().len());
+    }
+
+    else if let Some(matches) = matches.subcommand_matches("add-router") {
+        let name = matches.value_of("name").unwrap();
+
+        let mut configuration = Configuration::open(Path::new(filename))?;
+        let internal_addresses = match matches.value_of("internal-address") {
+            Some(addresses) => Some(parse_addresses(addresses)?),
+            None => None
+        };
+        let router = update::new_router(&configuration, name,
+            internal_addresses,
+            parse_arg(matches, "external-address")?.unwrap())?;
+        let internal_addresses = router.internal_addresses().to_vec();
+        configuration.push_router(router)?;
+
+        configuration.save(Path::new(filename))?;
+
+        println!("Router added with internal address {}",
+            join_addresses(&internal_addresses));
+    }
+
+    else if let Some(matches) = matches.subcommand_matches("remove-router") {
+        let name = matches.value_of("name").unwrap();
+
+        let mut configuration = Configuration::open(Path::new(filename))?;
+        configuration.remove_router_by_name(name)?;
+
+        configuration.save(Path::new(filename))?;
+        println!("Router {} removed", name);
     }
 
     else if let Some(matches) = matches.subcommand_matches("remove-client") {
+        let mut configuration = Configuration::open(Path::new(filename))?;
+        let names = client_names(&configuration, matches)?;
+        for name in &names {
+            configuration.remove_client_by_name(name)?;
+        }
+
+        configuration.save(Path::new(filename))?;
+        for name in &names {
+            println!("Client {} removed", name);
+        }
+    }
+
+    else if let Some(matches) = matches.subcommand_matches("update-client") {
+        let mut configuration = Configuration::open(Path::new(filename))?;
+        let names = client_names(&configuration, matches)?;
+        update::update_clients(&mut configuration, &names,
+                               &client_update(matches)?)?;
+
+        configuration.save(Path::new(filename))?;
+        for name in &names {
+            println!("Client {} updated", name);
+        }
+    }
+
+    else if let Some(matches) = matches.subcommand_matches("update-router") {
+        let mut configuration = Configuration::open(Path::new(filename))?;
+        let name = router_name(&configuration, matches.value_of("name"))?;
+        if !router_update(matches)?.apply(&mut configuration, &name)? {
+            bail!("nothing to change for router {}", name);
+        }
+
+        configuration.save(Path::new(filename))?;
+        println!("Router {} updated", name);
+    }
+
+    else if let Some(matches) =
+        matches.subcommand_matches("update-client-defaults") {
+
+        let mut configuration = Configuration::open(Path::new(filename))?;
+        if !settings_update(matches)?
+            .apply(configuration.client_defaults_mut()) {
+            bail!("nothing to change for the client defaults");
+        }
+
+        configuration.save(Path::new(filename))?;
+        println!("Client defaults updated");
+    }
+
+    else if let Some(matches) = matches.subcommand_matches("update-group") {
         let name = matches.value_of("name").unwrap();
 
-        let mut configuration = Configuration::open(Path::new(filename));
-        if !configuration.remove_client_by_name(name) {
-            eprintln!("Failed to find and remove client {}", name);
-            exit(1);
+        let mut configuration = Configuration::open(Path::new(filename))?;
+        let mut update = GroupUpdate::new();
+        update.set_routes(list_update(matches,
+            ("routes", "add-routes", "remove-routes"), parse_addresses)?);
+        update.set_settings(settings_update(matches)?);
+        if !update.apply(configuration.group_mut(name))? {
+            bail!("nothing to change for group {}", name);
         }
 
-        configuration.save(Path::new(filename));
-        println!("Client {} removed", name);
+        configuration.save(Path::new(filename))?;
+        println!("Group {} updated", name);
     }
 
-    else if let Some(matches) = matches.subcommand_matches("add-client") {
+    else if let Some(matches) = matches.subcommand_matches("remove-group") {
         let name = matches.value_of("name").unwrap();
 
-        let mut configuration = Configuration::open(Path::new(filename));
-        if configuration.clients().iter().any(|client| client.name() == name) {
-            eprintln!("Client {} already exists", name);
-            exit(1);
+        let mut configuration = Configuration::open(Path::new(filename))?;
+        if !configuration.has_group(name) {
+            bail!("there is no group {}", name);
+        }
+        configuration.remove_group(name)?;
+
+        configuration.save(Path::new(filename))?;
+        println!("Group {} removed", name);
+    }
+
+    else if let Some(matches) = matches.subcommand_matches("prune") {
+        let mut configuration = Configuration::open(Path::new(filename))?;
+        let disable = matches.is_present("disable");
+
+        let now = Utc::now();
+        let pruned = configuration.prune(&now, disable);
+        if pruned.is_empty() {
+            println!("No expired clients to prune");
+            return Ok(());
+        }
+
+        configuration.save(Path::new(filename))?;
+        for client in &pruned {
+            if disable {
+                println!("Client {} disabled", client.name());
+            }
+            else {
+                println!("Client {} removed", client.name());
+            }
         }
+        print_redeploy(&update::prune_redeploy(&configuration, &pruned, &now));
+    }
+
+    else if let Some(matches) = matches.subcommand_matches("rotate-keys") {
+        let mut configuration = Configuration::open(Path::new(filename))?;
+
+        let all = matches.is_present("all");
+
+        let routers: Vec<String> =
+            if all {
+                configuration.routers()
+                    .iter()
+                    .map(|router| router.name().to_string())
+                    .collect()
+            }
+            else if matches.is_present("router") {
+                vec![router_name(&configuration, matches.value_of("router"))?]
+            }
+            else {
+                Vec::new()
+            };
 
-        let internal_address: Ipv4Addr = matches.value_of("internal-address")
-            .unwrap()
-            .parse()
-            .expect("Invalid internal address");
+        let names: Vec<String> =
+            if all {
+                update::rotatable_clients(&configuration)
+            }
+            else if matches.is_present("name") || matches.is_present("group") {
+                client_names(&configuration, matches)?
+            }
+            else {
+                Vec::new()
+            };
+
+        let now = Utc::now();
+        let redeploy =
+            update::rotate_keys(&mut configuration, &routers, &names, &now)?;
+
+        configuration.save(Path::new(filename))?;
+
+        for router in &routers {
+            println!("Keys rotated for router {}", router);
+        }
+        for name in &names {
+            println!("Keys rotated for client {}", name);
+        }
+        print_redeploy(&redeploy);
+        if all {
+            for client in configuration.clients() {
+                if client.private_key().is_none() {
+                    println!("Client {} has a user supplied public key and \
+                              was not rotated", client.name());
+                }
+            }
+        }
+    }
+
+    else if let Some(matches) = matches.subcommand_matches("regenerate-psk") {
+        let name = matches.value_of("name").unwrap();
+
+        let mut configuration = Configuration::open(Path::new(filename))?;
+        let client = configuration.client_mut(name)?;
+        if matches.is_present("remove") {
+            client.set_preshared_key(None);
+        }
+        else {
+            client.set_preshared_key(Some(generate_preshared_key()?));
+        }
+
+        configuration.save(Path::new(filename))?;
+        if matches.is_present("remove") {
+            println!("Preshared key removed for client {}", name);
+        }
+        else {
+            println!("Preshared key regenerated for client {}", name);
+        }
+    }
+
+    else if let Some(matches) = matches.subcommand_matches("add-client") {
+        let name = matches.value_of("name").unwrap();
 
-        let mut endpoint = EndPoint::new(name, internal_address);
+        let mut configuration = Configuration::open(Path::new(filename))?;
+        let internal_addresses = match matches.value_of("internal-address") {
+            Some(addresses) => Some(parse_addresses(addresses)?),
+            None => None
+        };
+        let mut endpoint = update::new_client(&configuration, name,
+            internal_addresses, parse_arg(matches, "subnet")?,
+            parse_arg(matches, "subnet6")?)?;
+        let internal_addresses = endpoint.internal_addresses().to_vec();
 
         if let Some(public_key) = matches.value_of("public-key") {
             endpoint.set_private_key(None);
             endpoint.set_public_key(public_key.to_string());
         }
 
-        if let Some(keepalive) = matches.value_of("persistent-keepalive") {
-            let keepalive: usize =
-                keepalive.parse().expect("Invalid persistent keepalive");
+        if let Some(keepalive) = parse_arg(matches, "persistent-keepalive")? {
             endpoint.set_persistent_keepalive(Some(keepalive));
         }
 
         if let Some(allowed_ips) = matches.value_of("allowed-ips") {
-            for allowed_ip in allowed_ips.split(",") {
-                let allowed_ip: Ipv4Net = allowed_ip.parse()
-                    .expect("Invalid allowed ip");
+            for allowed_ip in parse_addresses(allowed_ips)? {
                 endpoint.push_allowed_ip(allowed_ip);
             }
         }
 
-        configuration.push_client(endpoint);
+        if matches.is_present("psk") {
+            endpoint.set_preshared_key(Some(generate_preshared_key()?));
+        }
+
+        if let Some(router) = matches.value_of("primary-router") {
+            endpoint.set_primary_router(Some(router.to_string()));
+        }
+        if let Some(router) = matches.value_of("secondary-router") {
+            endpoint.set_secondary_router(Some(router.to_string()));
+        }
+
+        if let Some(tunnel) = parse_arg(matches, "tunnel")? {
+            endpoint.set_tunnel(tunnel);
+        }
+        if let Some(excluded_routes) = matches.value_of("exclude") {
+            endpoint.set_excluded_routes(parse_addresses(excluded_routes)?);
+        }
+        if let Some(groups) = matches.value_of("groups") {
+            endpoint.set_groups(parse_groups(groups)?);
+        }
+        if let Some(expires) = matches.value_of("expires") {
+            endpoint.set_expires_at(Some(parse_expiry(expires)?));
+        }
+
+        configuration.push_client(endpoint)?;
 
-        configuration.save(Path::new(filename));
+        configuration.save(Path::new(filename))?;
 
-        println!("Client added");
+        println!("Client added with internal address {}",
+            join_addresses(&internal_addresses));
     }
 
     else if let Some(matches) = matches.subcommand_matches("router-config") {
-        let configuration = Configuration::open(Path::new(filename));
+        let mut configuration = Configuration::open(Path::new(filename))?;
+        let name = router_name(&configuration, matches.value_of("name"))?;
+        check_output_dir(matches)?;
+        // Overrides only apply to this output, and aren't saved.
+        target_update(matches)?.apply_valid(
+            configuration.router_mut(&name)?.target_mut(), None,
+            &format!("routers.{}.", name))?;
+        let now = Utc::now();
 
-        if matches.is_present("linux-script") {
-            println!("cat > vpn.conf <<EOF");
+        if let Some(output) = config_output(matches)? {
+            print_config(output, &output::router_config(&configuration,
+                                                        &name, &now)?)?;
         }
-
-        println!("{}", configuration.router().interface());
-
-        for client in configuration.clients() {
-            println!("{}", client.peer());
+        else if matches.is_present("linux-script") {
+            let backend = parse_arg(matches, "firewall")?.unwrap();
+            println!("{}", script::router_linux_script(&configuration, &name,
+                                                       backend, &now)?);
         }
-
-        if matches.is_present("linux-script") {
-            println!("EOF");
-            println!("ip link add dev wg0 type wireguard");
-            println!("ip address add dev wg0 {}/32",
-                configuration.router().internal_address());
-            println!("wg setconf wg0 vpn.conf");
-            println!("ip link set up dev wg0");
-
-            configuration.clients()
-                .iter()
-                .flat_map(|client| client.allowed_ips())
-                .for_each(|allowed_ip|
-                        println!("ip route add {} dev wg0", allowed_ip));
+        else if matches.value_of("format") == Some("wg-quick") {
+            println!("{}", configuration.router_wg_quick_config(&name, &now)?);
+        }
+        else if matches.value_of("format") == Some("networkd") {
+            print_units(matches, networkd::router_units(&configuration,
+                                                        &name, &now)?)?;
+        }
+        else if matches.value_of("format") == Some("routeros") {
+            println!("{}", routeros::router_commands(&configuration, &name,
+                                                     &now)?);
+        }
+        else {
+            println!("{}", configuration.router_config(&name, &now)?);
         }
     }
 
     else if let Some(matches) = matches.subcommand_matches("client-config") {
-        let configuration = Configuration::open(Path::new(filename));
+        let mut configuration = Configuration::open(Path::new(filename))?;
         let name = matches.value_of("name").unwrap();
+        check_output_dir(matches)?;
+        let gateways = gateways(matches)?;
 
-        let client = configuration.client_by_name(name)
-            .expect(&format!("Could not find client {}", name));
-
-        if matches.is_present("linux-script") || matches.is_present("osx-script") {
-            println!("cat > vpn.conf <<EOF");
+        if matches.is_present("failover") {
+            configuration.fail_over(name)?;
         }
+        // Overrides only apply to this output, and aren't saved.
+        target_update(matches)?.apply_valid(
+            configuration.client_mut(name)?.target_mut(), Some(name), "")?;
+        let now = Utc::now();
 
-        println!("{}", configuration.client_config(name).unwrap());
-
-        if matches.is_present("linux-script") {
-            println!("EOF");
-            println!("ip link add dev wg0 type wireguard");
-            println!("ip address add dev wg0 {}/32",
-                client.internal_address());
-            println!("wg setconf wg0 vpn.conf");
-            println!("ip link set up dev wg0");
-
-            println!("ip route add {} dev wg0",
-                configuration.router().internal_address());
+        if let Some(output) = config_output(matches)? {
+            print_config(output, &output::client_config(&configuration,
+                                                        name, &now)?)?;
+        }
+        // QR codes are always of the wg-quick config, as that is what the
+        // mobile apps import.
+        else if matches.is_present("qr")
+            || matches.is_present("qr-png")
+            || matches.is_present("qr-svg") {
 
-            configuration.all_allowed_ips()
-                .iter()
-                .for_each(|allowed_ip|
-                    println!("ip route add {} dev wg0",
-                        allowed_ip));
+            let config = configuration.client_wg_quick_config(name, &now)?;
+            if let Some(path) = matches.value_of("qr-png") {
+                qr::write_png(&config, Path::new(path))?;
+                println!("QR code written to {}", path);
+            }
+            if let Some(path) = matches.value_of("qr-svg") {
+                qr::write_svg(&config, Path::new(path))?;
+                println!("QR code written to {}", path);
+            }
+            if matches.is_present("qr") {
+                println!("{}", qr::terminal(&config)?);
+            }
+        }
+        else if matches.is_present("linux-script") {
+            println!("{}", script::client_linux_script(&configuration, name,
+                                                       &now)?);
         }
         else if matches.is_present("osx-script") {
-            println!("EOF");
-            println!("sudo wireguard-go utun9");
-            println!("sudo wg setconf utun9 vpn.conf");
-            println!("sudo ifconfig utun9 inet {} 255.255.255.255 {}",
-                client.internal_address(),
-                configuration.router().internal_address());
-
-            configuration.all_allowed_ips()
-                .iter()
-                .for_each(|allowed_ip|
-                    if allowed_ip.prefix_len() == 32 {
-                        println!("sudo route add {} -interface utun9",
-                            allowed_ip.addr());
-                    }
-                    else {
-                        println!("sudo route add -net {} -interface utun9",
-                            allowed_ip);
-                    });
+            println!("{}", script::client_osx_script(&configuration, name,
+                                                     &now)?);
+        }
+        else if matches.value_of("format") == Some("wg-quick") {
+            println!("{}", configuration.client_wg_quick_config(name, &now)?);
+        }
+        else if matches.value_of("format") == Some("networkd") {
+            print_units(matches, networkd::client_units(&configuration,
+                                                        name, &now)?)?;
+        }
+        else if matches.value_of("format") == Some("nmconnection") {
+            println!("{}", nmconnection::client_keyfile(&configuration,
+                                                        name, &now)?);
+        }
+        else if matches.value_of("format") == Some("uci") {
+            println!("{}", uci::client_config(&configuration, name, &now)?);
+        }
+        else if matches.value_of("format") == Some("routeros") {
+            println!("{}", routeros::client_commands(&configuration, name,
+                                                     &gateways, &now)?);
+        }
+        else {
+            println!("{}", configuration.client_config(name, &now)?);
         }
     }
 
     else {
-        eprintln!("No command!");
-        exit(1)
+        bail!("no command given");
     }
-}
\ No newline at end of file
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_expiry, parse_groups};
+    use wireguard_configure::errors::ErrorKind;
+
+    #[test]
+    fn expiry() {
+        assert!(parse_expiry("2030-01-02").is_ok());
+        assert!(parse_expiry("2030-01-02T03:04:05Z").is_ok());
+        assert!(parse_expiry("36h").is_ok());
+        assert!(parse_expiry("2w").is_ok());
+        assert!(parse_expiry("").is_err());
+        assert!(parse_expiry("d").is_err());
+        assert!(parse_expiry("5y").is_err());
+        assert!(parse_expiry("5é").is_err());
+        assert!(parse_expiry("é").is_err());
+    }
+
+    #[test]
+    fn groups() {
+        assert_eq!(parse_groups("staff, ops").unwrap(),
+                   vec!["staff".to_string(), "ops".to_string()]);
+        for groups in &["", "staff,,ops", "staff, "] {
+            match *parse_groups(groups).unwrap_err().kind() {
+                ErrorKind::InvalidArgument(ref name, _) =>
+                    assert_eq!(name, "groups"),
+                ref kind => panic!("unexpected error {:?}", kind)
+            }
+        }
+    }
+}
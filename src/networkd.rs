@@ -0,0 +1,253 @@
+//! systemd-networkd units: a `.netdev` which creates the wireguard interface
+//! with its key and peers, and a `.network` which gives it addresses and
+//! routes.
+//!
+//! The peers are read back out of the node's wireguard config, so they are
+//! always the same as those `wg setconf` would be given.
+
+use chrono::{DateTime, Utc};
+use cidr::host_net;
+use configuration::Configuration;
+use endpoint::Tunnel;
+use errors::*;
+use import::WgConfig;
+use interface::InterfaceSettings;
+use ipnet::IpNet;
+use private_file;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// The routing table and firewall mark full tunnels use, as in `wg-quick`
+const FULL_TUNNEL_TABLE: u32 = 51820;
+
+
+/// The two unit files for one node's interface
+#[derive(Clone, Debug)]
+pub struct Units {
+    interface: String,
+    netdev: String,
+    network: String
+}
+
+
+impl Units {
+    pub fn interface(&self) -> &str { &self.interface }
+    pub fn netdev(&self) -> &str { &self.netdev }
+    pub fn network(&self) -> &str { &self.network }
+
+    pub fn netdev_file_name(&self) -> String {
+        format!("{}.netdev", self.interface)
+    }
+    pub fn network_file_name(&self) -> String {
+        format!("{}.network", self.interface)
+    }
+
+    /// Write both units into a directory, such as `/etc/systemd/network`,
+    /// readable only by their owner and group as the netdev holds the
+    /// private key. Returns the paths written.
+    pub fn write(&self, directory: &Path) -> Result<Vec<PathBuf>> {
+        let files = [(self.netdev_file_name(), &self.netdev),
+                     (self.network_file_name(), &self.network)];
+        let mut paths = Vec::new();
+        for &(ref file_name, contents) in &files {
+            let path = directory.join(file_name);
+            private_file::write(&path, format!("{}\n", contents).as_bytes(),
+                                0o640)
+                .map_err(|e| ErrorKind::Io(path.clone(), e))?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+}
+
+
+impl ::std::fmt::Display for Units {
+    /// Both units, each headed by its file name
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "# {}\n{}\n\n# {}\n{}",
+            self.netdev_file_name(), self.netdev,
+            self.network_file_name(), self.network)
+    }
+}
+
+
+/// The `.netdev` unit for a wireguard config, with the peers as they are in
+/// the config
+fn netdev(name: &str, interface: &str, config: &WgConfig,
+          settings: &InterfaceSettings, firewall_mark: Option<u32>)
+    -> String {
+
+    let mut lines: Vec<String> = vec![
+        "[NetDev]".to_string(),
+        format!("Name={}", interface),
+        "Kind=wireguard".to_string(),
+        format!("Description=wireguard-configure {}", name)
+    ];
+    if let Some(mtu) = settings.mtu() {
+        lines.push(format!("MTUBytes={}", mtu));
+    }
+
+    lines.push("".to_string());
+    lines.push("[WireGuard]".to_string());
+    for key in &["PrivateKey", "ListenPort"] {
+        if let Some(value) = config.interface().get(key) {
+            lines.push(format!("{}={}", key, value));
+        }
+    }
+    if let Some(firewall_mark) = firewall_mark {
+        lines.push(format!("FirewallMark={:#x}", firewall_mark));
+    }
+
+    for peer in config.peers() {
+        lines.push("".to_string());
+        lines.push("[WireGuardPeer]".to_string());
+        if let Some(name) = peer.name() {
+            lines.push(format!("# {}", name));
+        }
+        for (key, value) in peer.entries() {
+            if key.eq_ignore_ascii_case("AllowedIPs") {
+                continue;
+            }
+            lines.push(format!("{}={}", key, value));
+        }
+        // One network per line, as the list may be given more than once.
+        for allowed_ip in peer.get_all("AllowedIPs") {
+            lines.push(format!("AllowedIPs={}", allowed_ip));
+        }
+    }
+
+    lines.join("\n")
+}
+
+
+/// The `[Match]` and `[Network]` sections of a `.network` unit
+fn network(interface: &str, addresses: &[IpAddr],
+           settings: &InterfaceSettings) -> Vec<String> {
+    let mut lines: Vec<String> = vec![
+        "[Match]".to_string(),
+        format!("Name={}", interface),
+        "".to_string(),
+        "[Network]".to_string()
+    ];
+    for address in addresses {
+        lines.push(format!("Address={}", host_net(address)));
+    }
+    for server in settings.dns_servers() {
+        lines.push(format!("DNS={}", server));
+    }
+    let search = settings.dns_search();
+    if !search.is_empty() {
+        lines.push(format!("Domains={}", search.join(" ")));
+    }
+    lines
+}
+
+
+/// A `[Route]` section for each network, in the given table if any
+fn routes(lines: &mut Vec<String>, nets: &[IpNet], table: Option<&str>) {
+    for net in nets {
+        lines.push("".to_string());
+        lines.push("[Route]".to_string());
+        lines.push(format!("Destination={}", net));
+        if let Some(table) = table {
+            lines.push(format!("Table={}", table));
+        }
+    }
+}
+
+
+/// The table routes go in for an interface's `Table` setting, or `None`
+/// for the main table. `off` adds no routes at all, as in `wg-quick`.
+fn route_table(settings: &InterfaceSettings) -> Option<Option<&str>> {
+    match settings.table() {
+        Some("off") => None,
+        Some("auto") | None => Some(None),
+        Some(table) => Some(Some(table))
+    }
+}
+
+
+/// The units for the named router, routing each of its clients' networks
+/// and the other routers' networks over the interface
+pub fn router_units(configuration: &Configuration, name: &str,
+                    now: &DateTime<Utc>) -> Result<Units> {
+
+    let router = configuration.router(name)?;
+    let interface = router.target().linux_interface();
+    let config = WgConfig::parse(name,
+        &configuration.router_config(name, now)?)?;
+    let settings = router.settings();
+
+    let mut lines = network(interface, router.internal_addresses(), settings);
+    if let Some(table) = route_table(settings) {
+        routes(&mut lines, &configuration.router_routes(router, now), table);
+    }
+
+    Ok(Units {
+        interface: interface.to_string(),
+        netdev: netdev(name, interface, &config, settings, None),
+        network: lines.join("\n")
+    })
+}
+
+
+/// The units for the named client. A full tunnel is routed in its own table
+/// which traffic marked by wireguard skips, as `wg-quick` does, so the
+/// tunnel itself still goes out of the default route.
+pub fn client_units(configuration: &Configuration, name: &str,
+                    now: &DateTime<Utc>) -> Result<Units> {
+
+    let client = configuration.client(name)?;
+    let interface = client.target().linux_interface();
+    let config = WgConfig::parse(name,
+        &configuration.client_config(name, now)?)?;
+    let settings = configuration.client_settings(client);
+
+    let mut nets: Vec<IpNet> = Vec::new();
+    let primary = configuration.primary_router(client)?;
+    let secondary = configuration.secondary_router(client)?;
+    for router in Some(primary).into_iter().chain(secondary) {
+        nets.extend(router.internal_addresses().iter().map(host_net));
+    }
+    nets.extend(configuration.client_allowed_ips(client));
+
+    let mut lines = network(interface, client.internal_addresses(),
+        &settings);
+    let full = client.tunnel() == Tunnel::Full;
+    match route_table(&settings) {
+        Some(table) if full => {
+            let table = table.map(|table| table.to_string())
+                .unwrap_or_else(|| FULL_TUNNEL_TABLE.to_string());
+            routes(&mut lines, &nets, Some(&table));
+            lines.extend(vec![
+                "".to_string(),
+                "[RoutingPolicyRule]".to_string(),
+                "Table=main".to_string(),
+                "SuppressPrefixLength=0".to_string(),
+                "Family=both".to_string(),
+                "Priority=32764".to_string(),
+                "".to_string(),
+                "[RoutingPolicyRule]".to_string(),
+                format!("FirewallMark={:#x}", FULL_TUNNEL_TABLE),
+                "InvertRule=yes".to_string(),
+                format!("Table={}", table),
+                "Family=both".to_string(),
+                "Priority=32765".to_string()
+            ]);
+        },
+        Some(table) => routes(&mut lines, &nets, table),
+        None => ()
+    }
+    let firewall_mark = if full && route_table(&settings).is_some() {
+        Some(FULL_TUNNEL_TABLE)
+    }
+    else {
+        None
+    };
+
+    Ok(Units {
+        interface: interface.to_string(),
+        netdev: netdev(name, interface, &config, &settings, firewall_mark),
+        network: lines.join("\n")
+    })
+}
@@ -0,0 +1,142 @@
+//! NetworkManager keyfiles, which `nmcli connection load` reads from
+//! `/etc/NetworkManager/system-connections`.
+//!
+//! As with the networkd units, the peers are read back out of the client's
+//! wireguard config so they are always the same as those `wg setconf` would
+//! be given.
+
+use chrono::{DateTime, Utc};
+use configuration::Configuration;
+use errors::*;
+use import::WgConfig;
+use std::net::IpAddr;
+use uuid::Uuid;
+
+
+/// The namespace connection UUIDs are derived in
+const UUID_NAMESPACE: Uuid =
+    Uuid::from_u128(0x1d1546fd_91d0_463e_8039_4d9a984bdab0);
+
+
+/// A connection UUID taken from the client's name, so NetworkManager sees
+/// the same connection each time its keyfile is regenerated, including
+/// after its keys are rotated
+pub fn connection_uuid(name: &str) -> String {
+    Uuid::new_v5(&UUID_NAMESPACE, name.as_bytes()).to_string()
+}
+
+
+/// A keyfile list, which is `;` delimited with a trailing `;`
+fn list<T: ToString>(items: &[T]) -> String {
+    items.iter()
+        .map(|item| format!("{};", item.to_string()))
+        .collect()
+}
+
+
+/// The `[ipv4]` or `[ipv6]` section, with the addresses and DNS servers of
+/// that family. A family without addresses is disabled.
+fn ip_section(section: &str, addresses: &[&IpAddr], dns: &[&IpAddr],
+              dns_search: &[&str], route_table: Option<u32>) -> Vec<String> {
+    let mut lines: Vec<String> = vec![
+        "".to_string(),
+        format!("[{}]", section)
+    ];
+    if addresses.is_empty() {
+        lines.push("method=disabled".to_string());
+        return lines;
+    }
+
+    lines.push("method=manual".to_string());
+    for (i, address) in addresses.iter().enumerate() {
+        let prefix_len = if address.is_ipv4() { 32 } else { 128 };
+        lines.push(format!("address{}={}/{}", i + 1, address, prefix_len));
+    }
+    if !dns.is_empty() {
+        lines.push(format!("dns={}", list(dns)));
+    }
+    if !dns_search.is_empty() {
+        lines.push(format!("dns-search={}", list(dns_search)));
+    }
+    if let Some(route_table) = route_table {
+        lines.push(format!("route-table={}", route_table));
+    }
+    lines
+}
+
+
+/// The keyfile for the named client. NetworkManager routes each peer's
+/// allowed IPs itself, including a full tunnel's default route.
+pub fn client_keyfile(configuration: &Configuration, name: &str,
+                      now: &DateTime<Utc>) -> Result<String> {
+
+    let client = configuration.client(name)?;
+    let config = WgConfig::parse(name,
+        &configuration.client_config(name, now)?)?;
+    let settings = configuration.client_settings(client);
+
+    let mut lines: Vec<String> = vec![
+        "[connection]".to_string(),
+        format!("id={}", name),
+        format!("uuid={}", connection_uuid(name)),
+        "type=wireguard".to_string(),
+        format!("interface-name={}", client.target().linux_interface()),
+        "".to_string(),
+        "[wireguard]".to_string()
+    ];
+    if let Some(private_key) = config.interface().get("PrivateKey") {
+        lines.push(format!("private-key={}", private_key));
+    }
+    if let Some(listen_port) = config.interface().get("ListenPort") {
+        lines.push(format!("listen-port={}", listen_port));
+    }
+    if let Some(mtu) = settings.mtu() {
+        lines.push(format!("mtu={}", mtu));
+    }
+    // Only numbered tables can be given to NetworkManager.
+    let route_table = match settings.table() {
+        Some("off") => {
+            lines.push("peer-routes=false".to_string());
+            None
+        },
+        Some(table) => table.parse().ok(),
+        None => None
+    };
+
+    for peer in config.peers() {
+        let public_key = peer.get("PublicKey").unwrap_or("");
+        lines.push("".to_string());
+        lines.push(format!("[wireguard-peer.{}]", public_key));
+        if let Some(endpoint) = peer.get("Endpoint") {
+            lines.push(format!("endpoint={}", endpoint));
+        }
+        if let Some(preshared_key) = peer.get("PresharedKey") {
+            lines.push(format!("preshared-key={}", preshared_key));
+            lines.push("preshared-key-flags=0".to_string());
+        }
+        if let Some(keepalive) = peer.get("PersistentKeepalive") {
+            lines.push(format!("persistent-keepalive={}", keepalive));
+        }
+        lines.push(format!("allowed-ips={}",
+            list(&peer.get_all("AllowedIPs"))));
+    }
+
+    let (v4, v6): (Vec<&IpAddr>, Vec<&IpAddr>) = client.internal_addresses()
+        .iter()
+        .partition(|address| address.is_ipv4());
+    let dns_servers = settings.dns_servers();
+    let (dns4, dns6): (Vec<&IpAddr>, Vec<&IpAddr>) = dns_servers.iter()
+        .partition(|address| address.is_ipv4());
+    // Search domains go with whichever family is in use.
+    let dns_search = settings.dns_search();
+    let (search4, search6): (&[&str], &[&str]) = if v4.is_empty() {
+        (&[], &dns_search)
+    }
+    else {
+        (&dns_search, &[])
+    };
+    lines.extend(ip_section("ipv4", &v4, &dns4, search4, route_table));
+    lines.extend(ip_section("ipv6", &v6, &dns6, search6, route_table));
+
+    Ok(lines.join("\n"))
+}
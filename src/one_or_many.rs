@@ -0,0 +1,36 @@
+//! Serde helpers for fields which hold a list of values, but which are
+//! written as a single scalar (or `~`) when there are fewer than two values.
+//! This keeps configurations written before those fields became lists
+//! loading unchanged.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>)
+}
+
+
+pub fn serialize<T, S>(values: &[T], serializer: S) -> Result<S::Ok, S::Error>
+    where T: Serialize, S: Serializer {
+
+    match values.len() {
+        0 => serializer.serialize_none(),
+        1 => values[0].serialize(serializer),
+        _ => values.serialize(serializer)
+    }
+}
+
+
+pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Vec<T>, D::Error>
+    where T: Deserialize<'de>, D: Deserializer<'de> {
+
+    Ok(match Option::<OneOrMany<T>>::deserialize(deserializer)? {
+        None => Vec::new(),
+        Some(OneOrMany::One(value)) => vec![value],
+        Some(OneOrMany::Many(values)) => values
+    })
+}
@@ -0,0 +1,365 @@
+//! Machine-readable output for `--list`, `router-config` and
+//! `client-config`, as JSON or YAML.
+//!
+//! The schema is versioned by `SCHEMA_VERSION`. Within a version, fields are
+//! only ever added, and every field is always present, with `null` for
+//! anything unset, so output can be fed straight to `jq`.
+
+use chrono::{DateTime, Utc};
+use cidr::host_net;
+use configuration::Configuration;
+use endpoint::EndPoint;
+use errors::*;
+use import::{Section, WgConfig};
+use ipnet::IpNet;
+use serde::Serialize;
+use serde_json;
+use serde_yaml;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+
+/// The version of the schema below, which changes if a field is ever
+/// removed or changes meaning
+pub const SCHEMA_VERSION: u32 = 1;
+
+
+/// How read commands print their results
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Output {
+    Json,
+    Yaml,
+    Table,
+    Text
+}
+
+
+impl Output {
+    /// Whether this is one of the serialized formats
+    pub fn is_structured(&self) -> bool {
+        *self == Output::Json || *self == Output::Yaml
+    }
+
+    /// Serialize a value as JSON or YAML
+    pub fn serialize<T: Serialize>(&self, value: &T) -> Result<String> {
+        match *self {
+            Output::Json => serde_json::to_string_pretty(value)
+                .map_err(|e| e.to_string().into()),
+            Output::Yaml => serde_yaml::to_string(value)
+                .map_err(|e| e.to_string().into()),
+            Output::Table | Output::Text =>
+                bail!("only json and yaml output can be serialized")
+        }
+    }
+}
+
+
+impl FromStr for Output {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Output> {
+        match s {
+            "json" => Ok(Output::Json),
+            "yaml" => Ok(Output::Yaml),
+            "table" => Ok(Output::Table),
+            "text" => Ok(Output::Text),
+            _ => bail!(ErrorKind::InvalidArgument("output".to_string(),
+                                                  s.to_string()))
+        }
+    }
+}
+
+
+/// Whether a node is a router or a client
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Kind {
+    Router,
+    Client
+}
+
+
+/// Whether a node is in router configs
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    Active,
+    Disabled,
+    Expired
+}
+
+
+impl Status {
+    pub fn of(client: &EndPoint, now: &DateTime<Utc>) -> Status {
+        if client.is_disabled() {
+            Status::Disabled
+        }
+        else if client.is_expired(now) {
+            Status::Expired
+        }
+        else {
+            Status::Active
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Status::Active => "active",
+            Status::Disabled => "disabled",
+            Status::Expired => "expired"
+        }
+    }
+}
+
+
+/// A router or client, as listed by `--list`
+#[derive(Clone, Debug, Serialize)]
+pub struct Node {
+    name: String,
+    kind: Kind,
+    public_key: String,
+    internal_addresses: Vec<IpAddr>,
+    /// Empty for routers
+    allowed_ips: Vec<IpNet>,
+    endpoint: Option<String>,
+    persistent_keepalive: Option<u16>,
+    groups: Vec<String>,
+    status: Status,
+    expires_at: Option<DateTime<Utc>>
+}
+
+
+impl Node {
+    pub fn name(&self) -> &str { &self.name }
+    pub fn kind(&self) -> Kind { self.kind }
+    pub fn public_key(&self) -> &str { &self.public_key }
+    pub fn internal_addresses(&self) -> &[IpAddr] { &self.internal_addresses }
+    pub fn allowed_ips(&self) -> &[IpNet] { &self.allowed_ips }
+    pub fn endpoint(&self) -> Option<&str> { self.endpoint.as_deref() }
+    pub fn persistent_keepalive(&self) -> Option<u16> {
+        self.persistent_keepalive
+    }
+    pub fn groups(&self) -> &[String] { &self.groups }
+    pub fn status(&self) -> Status { self.status }
+    pub fn expires_at(&self) -> Option<&DateTime<Utc>> {
+        self.expires_at.as_ref()
+    }
+}
+
+
+/// Everything `--list` shows
+#[derive(Clone, Debug, Serialize)]
+pub struct Listing {
+    version: u32,
+    nodes: Vec<Node>
+}
+
+
+impl Listing {
+    pub fn nodes(&self) -> &[Node] { &self.nodes }
+}
+
+
+/// The routers then the clients of a configuration, or only the members of
+/// a group
+pub fn listing(configuration: &Configuration, group: Option<&str>,
+               now: &DateTime<Utc>) -> Listing {
+
+    let mut nodes: Vec<Node> = Vec::new();
+    if group.is_none() {
+        nodes.extend(configuration.routers().iter().map(|router| Node {
+            name: router.name().to_string(),
+            kind: Kind::Router,
+            public_key: router.public_key().to_string(),
+            internal_addresses: router.internal_addresses().to_vec(),
+            allowed_ips: Vec::new(),
+            endpoint: Some(router.external_address().to_string()),
+            persistent_keepalive: None,
+            groups: Vec::new(),
+            status: Status::Active,
+            expires_at: None
+        }));
+    }
+
+    let clients = match group {
+        Some(group) => configuration.group_members(group),
+        None => configuration.clients().iter().collect()
+    };
+    nodes.extend(clients.iter().map(|client| Node {
+        name: client.name().to_string(),
+        kind: Kind::Client,
+        public_key: client.public_key().to_string(),
+        internal_addresses: client.internal_addresses().to_vec(),
+        allowed_ips: client.allowed_ips(),
+        endpoint: client.external_address().map(|address| address.to_string()),
+        persistent_keepalive: client.persistent_keepalive(),
+        groups: configuration.client_group_names(client)
+            .iter()
+            .map(|group| group.to_string())
+            .collect(),
+        status: Status::of(client, now),
+        expires_at: client.expires_at().cloned()
+    }));
+
+    Listing {
+        version: SCHEMA_VERSION,
+        nodes
+    }
+}
+
+
+/// The node's own side of a config
+#[derive(Clone, Debug, Serialize)]
+pub struct Interface {
+    /// The linux interface the config is brought up on
+    name: String,
+    /// `null` for clients whose private key is kept by the user
+    private_key: Option<String>,
+    public_key: String,
+    listen_port: Option<u16>,
+    addresses: Vec<IpNet>,
+    dns: Vec<String>,
+    mtu: Option<u16>
+}
+
+
+impl Interface {
+    pub fn name(&self) -> &str { &self.name }
+    pub fn private_key(&self) -> Option<&str> { self.private_key.as_deref() }
+    pub fn public_key(&self) -> &str { &self.public_key }
+    pub fn listen_port(&self) -> Option<u16> { self.listen_port }
+    pub fn addresses(&self) -> &[IpNet] { &self.addresses }
+    pub fn dns(&self) -> &[String] { &self.dns }
+    pub fn mtu(&self) -> Option<u16> { self.mtu }
+}
+
+
+/// One peer of a config
+#[derive(Clone, Debug, Serialize)]
+pub struct Peer {
+    name: Option<String>,
+    public_key: String,
+    preshared_key: Option<String>,
+    endpoint: Option<String>,
+    persistent_keepalive: Option<u16>,
+    allowed_ips: Vec<IpNet>
+}
+
+
+impl Peer {
+    pub fn name(&self) -> Option<&str> { self.name.as_deref() }
+    pub fn public_key(&self) -> &str { &self.public_key }
+    pub fn preshared_key(&self) -> Option<&str> {
+        self.preshared_key.as_deref()
+    }
+    pub fn endpoint(&self) -> Option<&str> { self.endpoint.as_deref() }
+    pub fn persistent_keepalive(&self) -> Option<u16> {
+        self.persistent_keepalive
+    }
+    pub fn allowed_ips(&self) -> &[IpNet] { &self.allowed_ips }
+}
+
+
+/// A router or client config, as `router-config` and `client-config` show it
+#[derive(Clone, Debug, Serialize)]
+pub struct Config {
+    version: u32,
+    name: String,
+    kind: Kind,
+    interface: Interface,
+    peers: Vec<Peer>
+}
+
+
+impl Config {
+    pub fn name(&self) -> &str { &self.name }
+    pub fn kind(&self) -> Kind { self.kind }
+    pub fn interface(&self) -> &Interface { &self.interface }
+    pub fn peers(&self) -> &[Peer] { &self.peers }
+}
+
+
+fn peer(section: &Section) -> Result<Peer> {
+    Ok(Peer {
+        name: section.name().map(|name| name.to_string()),
+        public_key: section.get("PublicKey").unwrap_or("").to_string(),
+        preshared_key: section.get("PresharedKey")
+            .map(|key| key.to_string()),
+        endpoint: section.get("Endpoint").map(|endpoint| endpoint.to_string()),
+        persistent_keepalive: section.get("PersistentKeepalive")
+            .and_then(|keepalive| keepalive.parse().ok()),
+        allowed_ips: section.get_all("AllowedIPs")
+            .iter()
+            .map(|net| net.parse()
+                .map_err(|_| ErrorKind::AddressParse(net.to_string()).into()))
+            .collect::<Result<Vec<IpNet>>>()?
+    })
+}
+
+
+/// The listen port and peers of a config, read back out of the wireguard
+/// config so they are always the same as `wg setconf` would be given
+fn parse(name: &str, config: &str) -> Result<(Option<u16>, Vec<Peer>)> {
+    let config = WgConfig::parse(name, config)?;
+    let listen_port = config.interface().get("ListenPort")
+        .and_then(|port| port.parse().ok());
+    let peers = config.peers().iter()
+        .map(peer)
+        .collect::<Result<Vec<Peer>>>()?;
+    Ok((listen_port, peers))
+}
+
+
+/// The named router's config
+pub fn router_config(configuration: &Configuration, name: &str,
+                     now: &DateTime<Utc>) -> Result<Config> {
+
+    let router = configuration.router(name)?;
+    let (listen_port, peers) = parse(name,
+        &configuration.router_config(name, now)?)?;
+    Ok(Config {
+        version: SCHEMA_VERSION,
+        name: name.to_string(),
+        kind: Kind::Router,
+        interface: Interface {
+            name: router.target().linux_interface().to_string(),
+            private_key: Some(router.private_key().to_string()),
+            public_key: router.public_key().to_string(),
+            listen_port,
+            addresses: router.internal_addresses().iter()
+                .map(host_net)
+                .collect(),
+            dns: router.settings().dns().to_vec(),
+            mtu: router.settings().mtu()
+        },
+        peers
+    })
+}
+
+
+/// The named client's config
+pub fn client_config(configuration: &Configuration, name: &str,
+                     now: &DateTime<Utc>) -> Result<Config> {
+
+    let client = configuration.client(name)?;
+    let settings = configuration.client_settings(client);
+    let (listen_port, peers) = parse(name,
+        &configuration.client_config(name, now)?)?;
+    Ok(Config {
+        version: SCHEMA_VERSION,
+        name: name.to_string(),
+        kind: Kind::Client,
+        interface: Interface {
+            name: client.target().linux_interface().to_string(),
+            private_key: client.private_key().map(|key| key.to_string()),
+            public_key: client.public_key().to_string(),
+            listen_port,
+            addresses: client.internal_addresses().iter()
+                .map(host_net)
+                .collect(),
+            dns: settings.dns().to_vec(),
+            mtu: settings.mtu()
+        },
+        peers
+    })
+}
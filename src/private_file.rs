@@ -0,0 +1,28 @@
+//! Writing files which hold private keys.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+
+/// Write a file with `mode`, including when it already exists, so no one
+/// else can read the keys in it
+#[cfg(unix)]
+pub(crate) fn write(path: &Path, contents: &[u8], mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)?;
+    file.set_permissions(fs::Permissions::from_mode(mode))?;
+    file.write_all(contents)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn write(path: &Path, contents: &[u8], _mode: u32)
+    -> io::Result<()> {
+    fs::File::create(path)?.write_all(contents)
+}
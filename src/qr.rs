@@ -0,0 +1,56 @@
+//! QR codes of client configs, for the wireguard mobile apps.
+
+use errors::*;
+use image::{ImageFormat, Luma};
+use private_file;
+use qrcode::QrCode;
+use qrcode::render::{svg, unicode};
+use std::io::Cursor;
+use std::path::Path;
+
+
+fn encode(text: &str) -> Result<QrCode> {
+    QrCode::new(text.as_bytes())
+        .map_err(|e| ErrorKind::QrCode(e.to_string()).into())
+}
+
+
+/// Render `text` as a QR code of unicode half blocks, light on dark, for
+/// printing to a terminal
+pub fn terminal(text: &str) -> Result<String> {
+    Ok(encode(text)?
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .build())
+}
+
+
+/// Render `text` as an SVG QR code
+pub fn svg(text: &str) -> Result<String> {
+    Ok(encode(text)?
+        .render::<svg::Color>()
+        .min_dimensions(256, 256)
+        .build())
+}
+
+
+/// Write `text` as a PNG QR code to `path`
+pub fn write_png(text: &str, path: &Path) -> Result<()> {
+    let mut png = Cursor::new(Vec::new());
+    encode(text)?
+        .render::<Luma<u8>>()
+        .min_dimensions(256, 256)
+        .build()
+        .write_to(&mut png, ImageFormat::Png)
+        .map_err(|e| ErrorKind::QrCode(format!("{}: {}", path.display(), e)))?;
+    private_file::write(path, png.get_ref(), 0o600)
+        .map_err(|e| ErrorKind::Io(path.to_path_buf(), e).into())
+}
+
+
+/// Write `text` as an SVG QR code to `path`
+pub fn write_svg(text: &str, path: &Path) -> Result<()> {
+    private_file::write(path, svg(text)?.as_bytes(), 0o600)
+        .map_err(|e| ErrorKind::Io(path.to_path_buf(), e).into())
+}
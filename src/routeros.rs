@@ -0,0 +1,211 @@
+//! MikroTik RouterOS 7 commands, which can be pasted into a terminal or run
+//! with `/import`.
+//!
+//! As with the networkd units, the peers are read back out of the node's
+//! wireguard config so they are always the same as those `wg setconf`
+//! would be given.
+
+use addrport::AddrPort;
+use chrono::{DateTime, Utc};
+use cidr::host_net;
+use configuration::Configuration;
+use endpoint::Tunnel;
+use errors::*;
+use import::WgConfig;
+use ipnet::IpNet;
+use script::{peer_endpoints, split_default_routes};
+use std::net::IpAddr;
+
+
+/// Quote a value for RouterOS, unless it is made up only of characters
+/// which never need quoting. Inside quotes `\`, `"`, `$` and `?` are
+/// escaped, and control characters are written in hex.
+pub fn quote(value: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "._:/,-".contains(c);
+    if !value.is_empty() && value.chars().all(safe) {
+        return value.to_string();
+    }
+
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '\\' | '"' | '$' | '?' => {
+                quoted.push('\\');
+                quoted.push(c);
+            },
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                quoted.push_str(&format!("\\{:02X}", c as u32));
+            },
+            c => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+
+/// A command with `name=value` arguments, each value quoted as needed
+fn command(path: &str, arguments: &[(&str, String)]) -> String {
+    let mut words = vec![path.to_string()];
+    words.extend(arguments.iter()
+        .map(|&(name, ref value)| format!("{}={}", name, quote(value))));
+    words.join(" ")
+}
+
+
+/// The interface and peer commands for a wireguard config
+fn wireguard_commands(lines: &mut Vec<String>, interface: &str,
+                      config: &WgConfig, mtu: Option<u16>) {
+    let mut arguments = vec![("name", interface.to_string())];
+    if let Some(private_key) = config.interface().get("PrivateKey") {
+        arguments.push(("private-key", private_key.to_string()));
+    }
+    if let Some(listen_port) = config.interface().get("ListenPort") {
+        arguments.push(("listen-port", listen_port.to_string()));
+    }
+    if let Some(mtu) = mtu {
+        arguments.push(("mtu", mtu.to_string()));
+    }
+    lines.push(command("/interface wireguard add", &arguments));
+
+    for peer in config.peers() {
+        let mut arguments = vec![("interface", interface.to_string())];
+        if let Some(public_key) = peer.get("PublicKey") {
+            arguments.push(("public-key", public_key.to_string()));
+        }
+        if let Some(preshared_key) = peer.get("PresharedKey") {
+            arguments.push(("preshared-key", preshared_key.to_string()));
+        }
+        if let Some(endpoint) = peer.get("Endpoint")
+            .and_then(|endpoint| endpoint.parse::<AddrPort>().ok()) {
+            arguments.push(("endpoint-address", endpoint.address()
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string()));
+            arguments.push(("endpoint-port", endpoint.port().to_string()));
+        }
+        if let Some(keepalive) = peer.get("PersistentKeepalive") {
+            arguments.push(("persistent-keepalive",
+                format!("{}s", keepalive)));
+        }
+        arguments.push(("allowed-address",
+            peer.get_all("AllowedIPs").join(",")));
+        if let Some(name) = peer.name() {
+            arguments.push(("comment", name.to_string()));
+        }
+        lines.push(command("/interface wireguard peers add", &arguments));
+    }
+}
+
+
+/// The address commands for an interface, in `/ip` or `/ipv6`
+fn address_commands(lines: &mut Vec<String>, interface: &str,
+                    addresses: &[IpAddr]) {
+    for address in addresses {
+        let (path, mut arguments) = match *address {
+            IpAddr::V4(_) => ("/ip address add", Vec::new()),
+            IpAddr::V6(_) => ("/ipv6 address add",
+                              vec![("advertise", "no".to_string())])
+        };
+        arguments.insert(0, ("address", host_net(address).to_string()));
+        arguments.insert(1, ("interface", interface.to_string()));
+        lines.push(command(path, &arguments));
+    }
+}
+
+
+/// The commands routing each network over an interface
+fn route_commands(lines: &mut Vec<String>, interface: &str, nets: &[IpNet]) {
+    for net in nets {
+        let path = match *net {
+            IpNet::V4(_) => "/ip route add",
+            IpNet::V6(_) => "/ipv6 route add"
+        };
+        lines.push(command(path, &[("dst-address", net.to_string()),
+                                   ("gateway", interface.to_string())]));
+    }
+}
+
+
+/// The commands which configure the named router
+pub fn router_commands(configuration: &Configuration, name: &str,
+                       now: &DateTime<Utc>) -> Result<String> {
+
+    let router = configuration.router(name)?;
+    let interface = router.target().linux_interface();
+    let config = WgConfig::parse(name,
+        &configuration.router_config(name, now)?)?;
+
+    let mut lines: Vec<String> = Vec::new();
+    wireguard_commands(&mut lines, interface, &config,
+        router.settings().mtu());
+    address_commands(&mut lines, interface, router.internal_addresses());
+    route_commands(&mut lines, interface,
+        &configuration.router_routes(router, now));
+    Ok(lines.join("\n"))
+}
+
+
+/// The commands which configure the named client. A full tunnel's default
+/// route is split in two, as in the scripts, and each peer's endpoint is
+/// routed around the tunnel through the gateway of its address family,
+/// which must be given as it isn't known here.
+pub fn client_commands(configuration: &Configuration, name: &str,
+                       gateways: &[IpAddr], now: &DateTime<Utc>)
+    -> Result<String> {
+
+    let client = configuration.client(name)?;
+    let interface = client.target().linux_interface();
+    let config = WgConfig::parse(name,
+        &configuration.client_config(name, now)?)?;
+    let settings = configuration.client_settings(client);
+
+    let mut lines: Vec<String> = Vec::new();
+    wireguard_commands(&mut lines, interface, &config, settings.mtu());
+    address_commands(&mut lines, interface, client.internal_addresses());
+
+    let mut nets: Vec<IpNet> = Vec::new();
+    let primary = configuration.primary_router(client)?;
+    let secondary = configuration.secondary_router(client)?;
+    for router in Some(primary).into_iter().chain(secondary) {
+        nets.extend(router.internal_addresses().iter().map(host_net));
+    }
+    nets.extend(split_default_routes(configuration.client_allowed_ips(client)));
+    route_commands(&mut lines, interface, &nets);
+
+    if client.tunnel() == Tunnel::Full {
+        for endpoint in peer_endpoints(configuration, client, now)? {
+            endpoint_route_command(&mut lines, endpoint, gateways)?;
+        }
+    }
+
+    Ok(lines.join("\n"))
+}
+
+
+/// The command routing a peer's endpoint through a gateway, rather than
+/// the tunnel. RouterOS routes can't be given a host name, so the endpoint
+/// has to be an address.
+fn endpoint_route_command(lines: &mut Vec<String>, endpoint: &AddrPort,
+                          gateways: &[IpAddr]) -> Result<()> {
+    let address: IpAddr = endpoint.address()
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .map_err(|_| format!("endpoint {} must be an address to be routed \
+                              around a full tunnel", endpoint))?;
+    let gateway = gateways.iter()
+        .find(|gateway| gateway.is_ipv4() == address.is_ipv4())
+        .ok_or_else(|| ErrorKind::InvalidArgument("gateway".to_string(),
+            format!("none given for endpoint {}", endpoint)))?;
+    let path = match address {
+        IpAddr::V4(_) => "/ip route add",
+        IpAddr::V6(_) => "/ipv6 route add"
+    };
+    lines.push(command(path, &[("dst-address", host_net(&address).to_string()),
+                               ("gateway", gateway.to_string())]));
+    Ok(())
+}
@@ -0,0 +1,266 @@
+//! Shell scripts which write out a wireguard configuration and bring up the
+//! interface, addresses and routes for it.
+
+use addrport::AddrPort;
+use chrono::{DateTime, Utc};
+use cidr::host_net;
+use configuration::Configuration;
+use endpoint::{EndPoint, Tunnel};
+use errors::*;
+use firewall::{self, Backend};
+use ipnet::IpNet;
+use std::net::{IpAddr, Ipv6Addr};
+use target::Target;
+
+
+/// Quote a value for the shell, unless it is made up only of characters
+/// which never need quoting
+pub fn quote(value: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric()
+        || "@%+=:,./_-".contains(c);
+    if !value.is_empty() && value.chars().all(safe) {
+        value.to_string()
+    }
+    else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
+
+
+/// The linux command to add an address to an interface for the given
+/// address family
+fn linux_address_command(interface: &str, address: &IpAddr) -> String {
+    match *address {
+        IpAddr::V4(_) => format!("ip address add dev {} {}",
+            quote(interface), host_net(address)),
+        IpAddr::V6(_) => format!("ip -6 address add dev {} {}",
+            quote(interface), host_net(address))
+    }
+}
+
+
+/// The linux command to route a network over an interface for the given
+/// address family
+fn linux_route_command(interface: &str, net: &IpNet) -> String {
+    match *net {
+        IpNet::V4(_) => format!("ip route add {} dev {}",
+            net, quote(interface)),
+        IpNet::V6(_) => format!("ip -6 route add {} dev {}",
+            net, quote(interface))
+    }
+}
+
+
+/// Split default routes in two, so they take priority over the existing
+/// default route without replacing it
+pub fn split_default_routes(nets: Vec<IpNet>) -> Vec<IpNet> {
+    nets.into_iter()
+        .flat_map(|net| {
+            if net.prefix_len() == 0 {
+                net.subnets(1).unwrap().collect()
+            }
+            else {
+                vec![net]
+            }
+        })
+        .collect()
+}
+
+
+/// The external addresses of every peer of a client. A full tunnel client
+/// must keep reaching these outside of the tunnel.
+pub fn peer_endpoints<'a>(configuration: &'a Configuration, client: &EndPoint,
+                          now: &DateTime<Utc>) -> Result<Vec<&'a AddrPort>> {
+
+    let mut endpoints = vec![configuration.primary_router(client)?
+        .external_address()];
+    if let Some(secondary) = configuration.secondary_router(client)? {
+        endpoints.push(secondary.external_address());
+    }
+    endpoints.extend(configuration.direct_peers(client, now)
+        .iter()
+        .filter_map(|peer| peer.external_address()));
+    Ok(endpoints)
+}
+
+
+/// An IPv6 address in an `AddrPort`, which is written in brackets
+fn ipv6_address(endpoint: &AddrPort) -> Option<Ipv6Addr> {
+    endpoint.address()
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()
+}
+
+
+/// Write a config out with a heredoc. The delimiter is quoted so nothing in
+/// the config, such as hook commands, is expanded.
+fn heredoc(lines: &mut Vec<String>, target: &Target, config: String) {
+    lines.push(format!("cat > {} <<'EOF'",
+        quote(&target.script_config_path())));
+    lines.push(config);
+    lines.push("EOF".to_string());
+}
+
+
+/// The commands which bring up a linux interface with its config and
+/// addresses
+fn linux_interface_commands(lines: &mut Vec<String>, target: &Target,
+                            addresses: &[IpAddr]) {
+    let interface = target.linux_interface();
+    lines.push(format!("ip link add dev {} type wireguard",
+        quote(interface)));
+    for address in addresses {
+        lines.push(linux_address_command(interface, address));
+    }
+    lines.push(format!("wg setconf {} {}",
+        quote(interface), quote(&target.script_config_path())));
+    lines.push(format!("ip link set up dev {}", quote(interface)));
+}
+
+
+/// A bash script which configures the named router on linux, with
+/// forwarding and NAT rules for the given firewall
+pub fn router_linux_script(configuration: &Configuration, name: &str,
+                           backend: Backend, now: &DateTime<Utc>)
+    -> Result<String> {
+
+    let router = configuration.router(name)?;
+    let interface = router.target().linux_interface();
+
+    let mut lines: Vec<String> = Vec::new();
+    heredoc(&mut lines, router.target(),
+        configuration.router_config(name, now)?);
+    linux_interface_commands(&mut lines, router.target(),
+        router.internal_addresses());
+
+    configuration.router_routes(router, now)
+        .iter()
+        .for_each(|allowed_ip|
+            lines.push(linux_route_command(interface, allowed_ip)));
+
+    lines.extend(firewall::router_commands(configuration, router, backend,
+        now));
+
+    Ok(lines.join("\n"))
+}
+
+
+/// A bash script which configures the named client on linux
+pub fn client_linux_script(configuration: &Configuration, name: &str,
+                           now: &DateTime<Utc>) -> Result<String> {
+
+    let client = configuration.client(name)?;
+    let interface = client.target().linux_interface();
+
+    let mut lines: Vec<String> = Vec::new();
+    heredoc(&mut lines, client.target(),
+        configuration.client_config(name, now)?);
+    linux_interface_commands(&mut lines, client.target(),
+        client.internal_addresses());
+
+    let primary = configuration.primary_router(client)?;
+    let secondary = configuration.secondary_router(client)?;
+    for router in Some(primary).into_iter().chain(secondary) {
+        for address in router.internal_addresses() {
+            lines.push(linux_route_command(interface, &host_net(address)));
+        }
+    }
+
+    if client.tunnel() == Tunnel::Full {
+        for endpoint in peer_endpoints(configuration, client, now)? {
+            lines.push(match ipv6_address(endpoint) {
+                // IPv6 gateways are usually link-local, so need the device
+                // too.
+                Some(address) =>
+                    format!("ip -6 route add {} $(ip -6 route show default \
+                             | awk '{{print \"via\", $3, \"dev\", $5; \
+                             exit}}')", address),
+                None =>
+                    format!("ip route add $(getent ahostsv4 {} | awk \
+                             '{{print $1; exit}}') via $(ip route show \
+                             default | awk '{{print $3; exit}}')",
+                        quote(endpoint.address()))
+            });
+        }
+    }
+
+    split_default_routes(configuration.client_allowed_ips(client))
+        .iter()
+        .for_each(|allowed_ip|
+            lines.push(linux_route_command(interface, allowed_ip)));
+
+    Ok(lines.join("\n"))
+}
+
+
+/// A bash script which configures the named client on Mac OS X
+pub fn client_osx_script(configuration: &Configuration, name: &str,
+                         now: &DateTime<Utc>) -> Result<String> {
+
+    let client = configuration.client(name)?;
+    let interface = quote(client.target().osx_interface()?);
+
+    let mut lines: Vec<String> = Vec::new();
+    heredoc(&mut lines, client.target(),
+        configuration.client_config(name, now)?);
+
+    lines.push(format!("sudo wireguard-go {}", interface));
+    lines.push(format!("sudo wg setconf {} {}",
+        interface, quote(&client.target().script_config_path())));
+
+    let router_v4 = configuration.primary_router(client)?
+        .internal_addresses()
+        .iter()
+        .find(|address| address.is_ipv4());
+    for address in client.internal_addresses() {
+        lines.push(match *address {
+            IpAddr::V4(_) =>
+                format!("sudo ifconfig {} inet {} 255.255.255.255 {}",
+                    interface,
+                    address,
+                    router_v4.unwrap_or(address)),
+            IpAddr::V6(_) =>
+                format!("sudo ifconfig {} inet6 {} prefixlen 128",
+                    interface,
+                    address)
+        });
+    }
+
+    if client.tunnel() == Tunnel::Full {
+        for endpoint in peer_endpoints(configuration, client, now)? {
+            lines.push(match ipv6_address(endpoint) {
+                Some(address) =>
+                    format!("sudo route add -inet6 -host {} $(route -n get \
+                             -inet6 default | awk '/gateway/ {{print $2}}')",
+                        address),
+                None =>
+                    format!("sudo route add -inet -host $(dscacheutil -q \
+                             host -a name {} | awk '/ip_address/ {{print $2; \
+                             exit}}') $(route -n get default | awk \
+                             '/gateway/ {{print $2}}')",
+                        quote(endpoint.address()))
+            });
+        }
+    }
+
+    split_default_routes(configuration.client_allowed_ips(client))
+        .iter()
+        .for_each(|allowed_ip| {
+            let family = match *allowed_ip {
+                IpNet::V4(_) => "-inet",
+                IpNet::V6(_) => "-inet6"
+            };
+            if allowed_ip.prefix_len() == allowed_ip.max_prefix_len() {
+                lines.push(format!("sudo route add {} {} -interface {}",
+                    family, allowed_ip.addr(), interface));
+            }
+            else {
+                lines.push(format!("sudo route add {} -net {} -interface {}",
+                    family, allowed_ip, interface));
+            }
+        });
+
+    Ok(lines.join("\n"))
+}
@@ -0,0 +1,130 @@
+//! Where a router or client's config is deployed on the node itself.
+
+use diagnostic::Diagnostic;
+use errors::*;
+
+/// The interface linux scripts bring up when none is set
+pub const LINUX_INTERFACE: &str = "wg0";
+/// The interface OS X scripts bring up when none is set
+pub const OSX_INTERFACE: &str = "utun9";
+/// Where scripts write the config when neither a path nor an interface name
+/// is set
+pub const CONFIG_PATH: &str = "vpn.conf";
+
+
+/// The interface a node's config is brought up on, where scripts write the
+/// config, and the port wireguard listens on. Wireguard always listens on
+/// every address, so only the port can be set.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Target {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    interface_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    config_path: Option<String>,
+    /// Unset, wireguard listens on the port of the external address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    listen_port: Option<u16>
+}
+
+
+impl Target {
+    pub fn new() -> Target {
+        Target::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.interface_name.is_none()
+            && self.config_path.is_none()
+            && self.listen_port.is_none()
+    }
+
+    pub fn set_interface_name(&mut self, interface_name: Option<String>) {
+        self.interface_name = interface_name;
+    }
+    pub fn set_config_path(&mut self, config_path: Option<String>) {
+        self.config_path = config_path;
+    }
+    pub fn set_listen_port(&mut self, listen_port: Option<u16>) {
+        self.listen_port = listen_port;
+    }
+
+    pub fn interface_name(&self) -> Option<&str> {
+        self.interface_name.as_deref()
+    }
+    pub fn config_path(&self) -> Option<&str> { self.config_path.as_deref() }
+    pub fn listen_port(&self) -> Option<u16> { self.listen_port }
+
+    /// The interface to bring up on linux
+    pub fn linux_interface(&self) -> &str {
+        self.interface_name().unwrap_or(LINUX_INTERFACE)
+    }
+
+    /// The interface to bring up on OS X, which must be a `utun` device
+    pub fn osx_interface(&self) -> Result<&str> {
+        match self.interface_name() {
+            Some(name) if !is_valid_osx_interface_name(name) =>
+                bail!("{} is not an OS X interface name, which must be utun \
+                       followed by a number, such as {}", name, OSX_INTERFACE),
+            Some(name) => Ok(name),
+            None => Ok(OSX_INTERFACE)
+        }
+    }
+
+    /// Where scripts write the config: the config path, or a file named
+    /// after the interface if only that is set
+    pub fn script_config_path(&self) -> String {
+        match (self.config_path(), self.interface_name()) {
+            (Some(path), _) => path.to_string(),
+            (None, Some(interface_name)) => format!("{}.conf", interface_name),
+            (None, None) => CONFIG_PATH.to_string()
+        }
+    }
+
+    /// Problems with the interface name, config path or listen port. Fields
+    /// are named after `prefix`, e.g. `routers.vpn-router.`.
+    pub fn validate(&self, client: Option<&str>, prefix: &str)
+        -> Vec<Diagnostic> {
+
+        let mut diagnostics = Vec::new();
+        if let Some(interface_name) = self.interface_name() {
+            if !is_valid_interface_name(interface_name) {
+                diagnostics.push(Diagnostic::error(client,
+                    format!("{}target.interface_name", prefix),
+                    format!("{} is not a valid interface name",
+                            interface_name)));
+            }
+        }
+        if self.config_path() == Some("") {
+            diagnostics.push(Diagnostic::error(client,
+                format!("{}target.config_path", prefix),
+                "config path is empty"));
+        }
+        if self.listen_port() == Some(0) {
+            diagnostics.push(Diagnostic::error(client,
+                format!("{}target.listen_port", prefix),
+                "port 0 is not valid"));
+        }
+        diagnostics
+    }
+}
+
+
+/// Whether a linux interface name is valid: 1 to 15 characters, none of
+/// which are whitespace, `/` or `:`. We also keep out anything which would
+/// need quoting in firewall rules.
+pub fn is_valid_interface_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 15
+        && name != "."
+        && name != ".."
+        && name.chars().all(|c| c.is_ascii_alphanumeric()
+            || c == '_' || c == '-' || c == '.')
+}
+
+
+/// Whether an interface name is an OS X `utun` device, such as `utun9`
+pub fn is_valid_osx_interface_name(name: &str) -> bool {
+    name.strip_prefix("utun")
+        .is_some_and(|number| !number.is_empty()
+            && number.chars().all(|c| c.is_ascii_digit()))
+}
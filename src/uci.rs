@@ -0,0 +1,154 @@
+//! OpenWrt UCI config for site clients, such as branch routers, for
+//! `uci -m import`.
+//!
+//! As with the networkd units, the peers are read back out of the client's
+//! wireguard config so they are always the same as those `wg setconf` would
+//! be given.
+
+use chrono::{DateTime, Utc};
+use cidr::host_net;
+use configuration::Configuration;
+use errors::*;
+use import::WgConfig;
+
+
+/// The longest zone name fw3 accepts
+const MAX_ZONE_NAME: usize = 11;
+
+
+/// A UCI section name for an interface. Section names and types may only
+/// contain letters, digits and `_`, so anything else becomes `_`.
+pub fn section_name(interface: &str) -> String {
+    interface.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+        .collect()
+}
+
+
+/// A firewall zone name for a section, cut down to the length fw3 accepts
+pub fn zone_name(section: &str) -> String {
+    section.chars().take(MAX_ZONE_NAME).collect()
+}
+
+
+/// Quote a UCI value. Values are always quoted, as `uci export` does.
+pub fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+
+fn option(lines: &mut Vec<String>, name: &str, value: &str) {
+    lines.push(format!("\toption {} {}", name, quote(value)));
+}
+
+
+fn list(lines: &mut Vec<String>, name: &str, value: &str) {
+    lines.push(format!("\tlist {} {}", name, quote(value)));
+}
+
+
+/// Split an `Endpoint` into its host, without IPv6 brackets, and port
+fn split_endpoint(endpoint: &str) -> Option<(&str, &str)> {
+    endpoint.rsplit_once(':')
+        .map(|(host, port)|
+            (host.trim_start_matches('[').trim_end_matches(']'), port))
+}
+
+
+/// The `network` and `firewall` packages for the named client. The
+/// interface gets a firewall zone of its own, which forwards to and from
+/// `lan` so the site's networks are reachable over wireguard. netifd names
+/// the device after the interface section, so it is the interface name
+/// made safe for UCI.
+pub fn client_config(configuration: &Configuration, name: &str,
+                     now: &DateTime<Utc>) -> Result<String> {
+
+    let client = configuration.client(name)?;
+    let interface = section_name(client.target().linux_interface());
+    let zone = zone_name(&interface);
+    let config = WgConfig::parse(name,
+        &configuration.client_config(name, now)?)?;
+    let settings = configuration.client_settings(client);
+
+    let mut lines: Vec<String> = vec![
+        "package network".to_string(),
+        "".to_string(),
+        format!("config interface {}", quote(&interface))
+    ];
+    option(&mut lines, "proto", "wireguard");
+    if let Some(private_key) = config.interface().get("PrivateKey") {
+        option(&mut lines, "private_key", private_key);
+    }
+    if let Some(listen_port) = config.interface().get("ListenPort") {
+        option(&mut lines, "listen_port", listen_port);
+    }
+    for address in client.internal_addresses() {
+        list(&mut lines, "addresses", &host_net(address).to_string());
+    }
+    if let Some(mtu) = settings.mtu() {
+        option(&mut lines, "mtu", &mtu.to_string());
+    }
+    for server in settings.dns_servers() {
+        list(&mut lines, "dns", &server.to_string());
+    }
+    for search in settings.dns_search() {
+        list(&mut lines, "dns_search", search);
+    }
+    // Only numbered tables can be given to netifd.
+    let route_allowed_ips = match settings.table() {
+        Some("off") => false,
+        Some(table) => {
+            if table.parse::<u32>().is_ok() {
+                option(&mut lines, "ip4table", table);
+                option(&mut lines, "ip6table", table);
+            }
+            true
+        },
+        None => true
+    };
+
+    for peer in config.peers() {
+        lines.push("".to_string());
+        lines.push(format!("config wireguard_{}", interface));
+        if let Some(name) = peer.name() {
+            option(&mut lines, "description", name);
+        }
+        if let Some(public_key) = peer.get("PublicKey") {
+            option(&mut lines, "public_key", public_key);
+        }
+        if let Some(preshared_key) = peer.get("PresharedKey") {
+            option(&mut lines, "preshared_key", preshared_key);
+        }
+        if let Some((host, port)) = peer.get("Endpoint")
+            .and_then(split_endpoint) {
+            option(&mut lines, "endpoint_host", host);
+            option(&mut lines, "endpoint_port", port);
+        }
+        if let Some(keepalive) = peer.get("PersistentKeepalive") {
+            option(&mut lines, "persistent_keepalive", keepalive);
+        }
+        option(&mut lines, "route_allowed_ips",
+            if route_allowed_ips { "1" } else { "0" });
+        for allowed_ip in peer.get_all("AllowedIPs") {
+            list(&mut lines, "allowed_ips", allowed_ip);
+        }
+    }
+
+    lines.push("".to_string());
+    lines.push("package firewall".to_string());
+    lines.push("".to_string());
+    lines.push("config zone".to_string());
+    option(&mut lines, "name", &zone);
+    list(&mut lines, "network", &interface);
+    for chain in &["input", "output", "forward"] {
+        option(&mut lines, chain, "ACCEPT");
+    }
+    for &(src, dest) in &[("lan", zone.as_str()), (zone.as_str(), "lan")] {
+        lines.push("".to_string());
+        lines.push("config forwarding".to_string());
+        option(&mut lines, "src", src);
+        option(&mut lines, "dest", dest);
+    }
+
+    Ok(lines.join("\n"))
+}
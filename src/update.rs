@@ -0,0 +1,669 @@
+//! Changes to the routers, clients and groups of a configuration, and the
+//! configs which must be redeployed after pruning clients or rotating keys.
+//!
+//! Each update only describes a change; nothing is touched until it is
+//! applied. Optional values which can be removed are `Option<Option<T>>`,
+//! where `Some(None)` removes the value.
+
+use addrport::AddrPort;
+use chrono::{DateTime, Utc};
+use cidr::{first_host, host_net};
+use configuration::{Configuration, Topology};
+use endpoint::{EndPoint, Router, Tunnel};
+use errors::*;
+use firewall::ForwardPolicy;
+use group::Group;
+use interface::InterfaceSettings;
+use ipnet::IpNet;
+use std::fmt::Display;
+use std::net::IpAddr;
+use target::Target;
+
+
+/// Pass the value of `change` to `set`, returning whether there was one
+fn apply<T: Clone, F: FnOnce(T)>(change: &Option<T>, set: F) -> bool {
+    match *change {
+        Some(ref value) => {
+            set(value.clone());
+            true
+        },
+        None => false
+    }
+}
+
+
+/// A change to a list: replace it outright, and then add and remove items
+#[derive(Clone, Debug)]
+pub struct ListUpdate<T> {
+    replace: Option<Vec<T>>,
+    add: Vec<T>,
+    remove: Vec<T>
+}
+
+
+impl<T> Default for ListUpdate<T> {
+    fn default() -> ListUpdate<T> {
+        ListUpdate { replace: None, add: Vec::new(), remove: Vec::new() }
+    }
+}
+
+
+impl<T: Clone + PartialEq + Display> ListUpdate<T> {
+    pub fn new() -> ListUpdate<T> {
+        ListUpdate::default()
+    }
+
+    pub fn set_replace(&mut self, replace: Option<Vec<T>>) {
+        self.replace = replace;
+    }
+    pub fn set_add(&mut self, add: Vec<T>) { self.add = add; }
+    pub fn set_remove(&mut self, remove: Vec<T>) { self.remove = remove; }
+
+    pub fn is_empty(&self) -> bool {
+        self.replace.is_none() && self.add.is_empty() && self.remove.is_empty()
+    }
+
+    /// `list` with the change made, or `None` if there is no change.
+    /// Removing an item which isn't in the list is an error, in which `name`
+    /// describes the list.
+    pub fn apply(&self, name: &str, list: &[T]) -> Result<Option<Vec<T>>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+
+        let mut list = self.replace.clone().unwrap_or_else(|| list.to_vec());
+        for item in &self.add {
+            if !list.contains(item) {
+                list.push(item.clone());
+            }
+        }
+        for item in &self.remove {
+            let len = list.len();
+            list.retain(|existing| existing != item);
+            if list.len() == len {
+                bail!("{} is not one of the current {}", item, name);
+            }
+        }
+        Ok(Some(list))
+    }
+}
+
+
+/// A change to where a node's config is deployed
+#[derive(Clone, Debug, Default)]
+pub struct TargetUpdate {
+    interface_name: Option<Option<String>>,
+    config_path: Option<Option<String>>,
+    listen_port: Option<Option<u16>>
+}
+
+
+impl TargetUpdate {
+    pub fn new() -> TargetUpdate {
+        TargetUpdate::default()
+    }
+
+    pub fn set_interface_name(&mut self,
+                              interface_name: Option<Option<String>>) {
+        self.interface_name = interface_name;
+    }
+    pub fn set_config_path(&mut self, config_path: Option<Option<String>>) {
+        self.config_path = config_path;
+    }
+    pub fn set_listen_port(&mut self, listen_port: Option<Option<u16>>) {
+        self.listen_port = listen_port;
+    }
+
+    /// Make the change, returning whether there was one
+    pub fn apply(&self, target: &mut Target) -> bool {
+        let mut changed = false;
+        changed |= apply(&self.interface_name,
+                         |name| target.set_interface_name(name));
+        changed |= apply(&self.config_path,
+                         |path| target.set_config_path(path));
+        changed |= apply(&self.listen_port,
+                         |port| target.set_listen_port(port));
+        changed
+    }
+
+    /// Make the change for a single config, refusing it if it leaves the
+    /// target invalid, as such a change isn't saved and so isn't validated
+    pub fn apply_valid(&self, target: &mut Target, client: Option<&str>,
+                       prefix: &str) -> Result<()> {
+        self.apply(target);
+        let diagnostics = target.validate(client, prefix);
+        if diagnostics.iter().any(|diagnostic| diagnostic.is_error()) {
+            bail!(ErrorKind::InvalidConfiguration(diagnostics));
+        }
+        Ok(())
+    }
+}
+
+
+/// A change to `wg-quick` interface settings. An empty list of commands
+/// removes a hook.
+#[derive(Clone, Debug, Default)]
+pub struct SettingsUpdate {
+    dns: Option<Vec<String>>,
+    mtu: Option<Option<u16>>,
+    table: Option<Option<String>>,
+    pre_up: Option<Vec<String>>,
+    post_up: Option<Vec<String>>,
+    pre_down: Option<Vec<String>>,
+    post_down: Option<Vec<String>>
+}
+
+
+impl SettingsUpdate {
+    pub fn new() -> SettingsUpdate {
+        SettingsUpdate::default()
+    }
+
+    pub fn set_dns(&mut self, dns: Option<Vec<String>>) { self.dns = dns; }
+    pub fn set_mtu(&mut self, mtu: Option<Option<u16>>) { self.mtu = mtu; }
+    pub fn set_table(&mut self, table: Option<Option<String>>) {
+        self.table = table;
+    }
+    pub fn set_pre_up(&mut self, pre_up: Option<Vec<String>>) {
+        self.pre_up = pre_up;
+    }
+    pub fn set_post_up(&mut self, post_up: Option<Vec<String>>) {
+        self.post_up = post_up;
+    }
+    pub fn set_pre_down(&mut self, pre_down: Option<Vec<String>>) {
+        self.pre_down = pre_down;
+    }
+    pub fn set_post_down(&mut self, post_down: Option<Vec<String>>) {
+        self.post_down = post_down;
+    }
+
+    /// Make the change, returning whether there was one
+    pub fn apply(&self, settings: &mut InterfaceSettings) -> bool {
+        let mut changed = false;
+        changed |= apply(&self.dns, |dns| settings.set_dns(dns));
+        changed |= apply(&self.mtu, |mtu| settings.set_mtu(mtu));
+        changed |= apply(&self.table, |table| settings.set_table(table));
+        changed |= apply(&self.pre_up, |hook| settings.set_pre_up(hook));
+        changed |= apply(&self.post_up, |hook| settings.set_post_up(hook));
+        changed |= apply(&self.pre_down, |hook| settings.set_pre_down(hook));
+        changed |= apply(&self.post_down, |hook| settings.set_post_down(hook));
+        changed
+    }
+}
+
+
+/// A change to a client. Setting the public key drops any private key.
+#[derive(Clone, Debug, Default)]
+pub struct ClientUpdate {
+    groups: ListUpdate<String>,
+    expires_at: Option<Option<DateTime<Utc>>>,
+    disabled: Option<bool>,
+    internal_addresses: ListUpdate<IpAddr>,
+    allowed_ips: ListUpdate<IpNet>,
+    external_address: Option<Option<AddrPort>>,
+    persistent_keepalive: Option<Option<u16>>,
+    public_key: Option<String>,
+    primary_router: Option<String>,
+    secondary_router: Option<Option<String>>,
+    tunnel: Option<Tunnel>,
+    excluded_routes: ListUpdate<IpNet>,
+    settings: SettingsUpdate,
+    target: TargetUpdate
+}
+
+
+impl ClientUpdate {
+    pub fn new() -> ClientUpdate {
+        ClientUpdate::default()
+    }
+
+    pub fn set_groups(&mut self, groups: ListUpdate<String>) {
+        self.groups = groups;
+    }
+    pub fn set_expires_at(&mut self,
+                          expires_at: Option<Option<DateTime<Utc>>>) {
+        self.expires_at = expires_at;
+    }
+    pub fn set_disabled(&mut self, disabled: Option<bool>) {
+        self.disabled = disabled;
+    }
+    pub fn set_internal_addresses(&mut self,
+                                  internal_addresses: ListUpdate<IpAddr>) {
+        self.internal_addresses = internal_addresses;
+    }
+    /// A change to the client's own allowed ips, not the internal addresses
+    /// they fall back to
+    pub fn set_allowed_ips(&mut self, allowed_ips: ListUpdate<IpNet>) {
+        self.allowed_ips = allowed_ips;
+    }
+    pub fn set_external_address(&mut self,
+                                external_address: Option<Option<AddrPort>>) {
+        self.external_address = external_address;
+    }
+    pub fn set_persistent_keepalive(&mut self, keepalive: Option<Option<u16>>) {
+        self.persistent_keepalive = keepalive;
+    }
+    pub fn set_public_key(&mut self, public_key: Option<String>) {
+        self.public_key = public_key;
+    }
+    pub fn set_primary_router(&mut self, primary_router: Option<String>) {
+        self.primary_router = primary_router;
+    }
+    pub fn set_secondary_router(&mut self,
+                                secondary_router: Option<Option<String>>) {
+        self.secondary_router = secondary_router;
+    }
+    pub fn set_tunnel(&mut self, tunnel: Option<Tunnel>) {
+        self.tunnel = tunnel;
+    }
+    pub fn set_excluded_routes(&mut self, excluded_routes: ListUpdate<IpNet>) {
+        self.excluded_routes = excluded_routes;
+    }
+    pub fn set_settings(&mut self, settings: SettingsUpdate) {
+        self.settings = settings;
+    }
+    pub fn set_target(&mut self, target: TargetUpdate) {
+        self.target = target;
+    }
+
+    /// Make the change, returning whether there was one
+    pub fn apply(&self, client: &mut EndPoint) -> Result<bool> {
+        let mut changed = false;
+
+        if let Some(groups) = self.groups.apply("groups", client.groups())? {
+            client.set_groups(groups);
+            changed = true;
+        }
+        changed |= apply(&self.expires_at,
+                         |expires_at| client.set_expires_at(expires_at));
+        changed |= apply(&self.disabled,
+                         |disabled| client.set_disabled(disabled));
+
+        if let Some(internal_addresses) = self.internal_addresses
+            .apply("internal addresses", client.internal_addresses())? {
+            client.set_internal_addresses(internal_addresses);
+            changed = true;
+        }
+        if let Some(allowed_ips) = self.allowed_ips
+            .apply("allowed ips", client.explicit_allowed_ips())? {
+            client.set_allowed_ips(allowed_ips);
+            changed = true;
+        }
+
+        changed |= apply(&self.external_address,
+                         |address| client.set_external_address(address));
+        changed |= apply(&self.persistent_keepalive,
+                         |keepalive|
+                             client.set_persistent_keepalive(keepalive));
+        if let Some(ref public_key) = self.public_key {
+            client.set_private_key(None);
+            client.set_public_key(public_key.clone());
+            changed = true;
+        }
+        changed |= apply(&self.primary_router,
+                         |router| client.set_primary_router(Some(router)));
+        changed |= apply(&self.secondary_router,
+                         |router| client.set_secondary_router(router));
+        changed |= apply(&self.tunnel, |tunnel| client.set_tunnel(tunnel));
+
+        if let Some(excluded_routes) = self.excluded_routes
+            .apply("excluded routes", client.excluded_routes())? {
+            client.set_excluded_routes(excluded_routes);
+            changed = true;
+        }
+
+        changed |= self.settings.apply(client.settings_mut());
+        changed |= self.target.apply(client.target_mut());
+
+        Ok(changed)
+    }
+}
+
+
+/// A change to a router, and to the master subnets and topology, which are
+/// shared by every router
+#[derive(Clone, Debug, Default)]
+pub struct RouterUpdate {
+    master_subnets: Option<Vec<IpNet>>,
+    topology: Option<Topology>,
+    internal_addresses: ListUpdate<IpAddr>,
+    external_address: Option<AddrPort>,
+    settings: SettingsUpdate,
+    target: TargetUpdate,
+    egress_interface: Option<Option<String>>,
+    masquerade: Option<Option<bool>>,
+    forward_policy: Option<Option<ForwardPolicy>>
+}
+
+
+impl RouterUpdate {
+    pub fn new() -> RouterUpdate {
+        RouterUpdate::default()
+    }
+
+    pub fn set_master_subnets(&mut self, master_subnets: Option<Vec<IpNet>>) {
+        self.master_subnets = master_subnets;
+    }
+    pub fn set_topology(&mut self, topology: Option<Topology>) {
+        self.topology = topology;
+    }
+    pub fn set_internal_addresses(&mut self,
+                                  internal_addresses: ListUpdate<IpAddr>) {
+        self.internal_addresses = internal_addresses;
+    }
+    pub fn set_external_address(&mut self,
+                                external_address: Option<AddrPort>) {
+        self.external_address = external_address;
+    }
+    pub fn set_settings(&mut self, settings: SettingsUpdate) {
+        self.settings = settings;
+    }
+    pub fn set_target(&mut self, target: TargetUpdate) {
+        self.target = target;
+    }
+    pub fn set_egress_interface(&mut self,
+                                egress_interface: Option<Option<String>>) {
+        self.egress_interface = egress_interface;
+    }
+    pub fn set_masquerade(&mut self, masquerade: Option<Option<bool>>) {
+        self.masquerade = masquerade;
+    }
+    pub fn set_forward_policy(&mut self,
+                              forward_policy: Option<Option<ForwardPolicy>>) {
+        self.forward_policy = forward_policy;
+    }
+
+    /// Make the change to the named router, returning whether there was one
+    pub fn apply(&self, configuration: &mut Configuration, name: &str)
+        -> Result<bool> {
+
+        let mut changed = false;
+
+        changed |= apply(&self.master_subnets,
+                         |subnets| configuration.set_master_subnets(subnets));
+        changed |= apply(&self.topology,
+                         |topology| configuration.set_topology(topology));
+
+        let router = configuration.router_mut(name)?;
+        if let Some(internal_addresses) = self.internal_addresses
+            .apply("internal addresses", router.internal_addresses())? {
+            router.set_internal_addresses(internal_addresses);
+            changed = true;
+        }
+        changed |= apply(&self.external_address,
+                         |address| router.set_external_address(address));
+        changed |= self.settings.apply(router.settings_mut());
+        changed |= self.target.apply(router.target_mut());
+
+        let forwarding = router.forwarding_mut();
+        changed |= apply(&self.egress_interface,
+                         |interface|
+                             forwarding.set_egress_interface(interface));
+        changed |= apply(&self.masquerade,
+                         |masquerade| forwarding.set_masquerade(masquerade));
+        changed |= apply(&self.forward_policy,
+                         |policy| forwarding.set_forward_policy(policy));
+
+        Ok(changed)
+    }
+}
+
+
+/// A change to a group's settings
+#[derive(Clone, Debug, Default)]
+pub struct GroupUpdate {
+    routes: ListUpdate<IpNet>,
+    settings: SettingsUpdate
+}
+
+
+impl GroupUpdate {
+    pub fn new() -> GroupUpdate {
+        GroupUpdate::default()
+    }
+
+    pub fn set_routes(&mut self, routes: ListUpdate<IpNet>) {
+        self.routes = routes;
+    }
+    pub fn set_settings(&mut self, settings: SettingsUpdate) {
+        self.settings = settings;
+    }
+
+    /// Make the change, returning whether there was one
+    pub fn apply(&self, group: &mut Group) -> Result<bool> {
+        let mut changed = false;
+        if let Some(routes) = self.routes.apply("routes", group.routes())? {
+            group.set_routes(routes);
+            changed = true;
+        }
+        changed |= self.settings.apply(group.settings_mut());
+        Ok(changed)
+    }
+}
+
+
+/// The names of every client in a group, which must have at least one
+pub fn group_client_names(configuration: &Configuration, group: &str)
+    -> Result<Vec<String>> {
+
+    let names: Vec<String> = configuration.group_members(group)
+        .iter()
+        .map(|client| client.name().to_string())
+        .collect();
+    if names.is_empty() {
+        bail!("there are no clients in group {}", group);
+    }
+    Ok(names)
+}
+
+
+/// Make the same change to each of the named clients, every one of which
+/// it must change
+pub fn update_clients(configuration: &mut Configuration, names: &[String],
+                      update: &ClientUpdate) -> Result<()> {
+    for name in names {
+        if !update.apply(configuration.client_mut(name)?)? {
+            bail!("nothing to change for client {}", name);
+        }
+    }
+    Ok(())
+}
+
+
+/// A new router, at the given internal addresses or one allocated from
+/// each master subnet
+pub fn new_router(configuration: &Configuration, name: &str,
+                  internal_addresses: Option<Vec<IpAddr>>,
+                  external_address: AddrPort) -> Result<Router> {
+    if configuration.router_by_name(name).is_some() {
+        bail!(ErrorKind::RouterExists(name.to_string()));
+    }
+
+    let internal_addresses = match internal_addresses {
+        Some(internal_addresses) => internal_addresses,
+        None => {
+            if configuration.master_subnets().is_empty() {
+                bail!("no master_subnet is configured, an internal address \
+                       must be given");
+            }
+            configuration.master_subnets()
+                .iter()
+                .map(|master_subnet|
+                    configuration.allocate_address(master_subnet))
+                .collect::<Result<Vec<IpAddr>>>()?
+        }
+    };
+
+    let mut router =
+        Router::new(name, internal_addresses[0], external_address)?;
+    for internal_address in &internal_addresses[1..] {
+        router.push_internal_address(*internal_address);
+    }
+    Ok(router)
+}
+
+
+/// A new client, at the given internal addresses, or else allocated from
+/// each master subnet: a single address, or with `subnet` or `subnet6`, a
+/// whole subnet of that prefix length
+pub fn new_client(configuration: &Configuration, name: &str,
+                  internal_addresses: Option<Vec<IpAddr>>,
+                  subnet: Option<u8>, subnet6: Option<u8>)
+    -> Result<EndPoint> {
+
+    if configuration.client_by_name(name).is_some() {
+        bail!(ErrorKind::ClientExists(name.to_string()));
+    }
+
+    let mut allocated_subnets: Vec<IpNet> = Vec::new();
+    let internal_addresses = match internal_addresses {
+        Some(internal_addresses) => internal_addresses,
+        None => {
+            if configuration.master_subnets().is_empty() {
+                bail!("no master_subnet is configured, an internal address \
+                       must be given");
+            }
+
+            let mut internal_addresses = Vec::new();
+            for master_subnet in configuration.master_subnets() {
+                let prefix_len = match *master_subnet {
+                    IpNet::V4(_) => subnet,
+                    IpNet::V6(_) => subnet6
+                };
+
+                match prefix_len {
+                    Some(prefix_len) => {
+                        let subnet = configuration
+                            .allocate_subnet(master_subnet, prefix_len)?;
+                        internal_addresses.push(first_host(&subnet));
+                        allocated_subnets.push(subnet);
+                    },
+                    None => {
+                        let address = configuration
+                            .allocate_address(master_subnet)?;
+                        internal_addresses.push(address);
+                        allocated_subnets.push(host_net(&address));
+                    }
+                }
+            }
+            internal_addresses
+        }
+    };
+
+    let mut client = EndPoint::new(name, internal_addresses[0])?;
+    for internal_address in &internal_addresses[1..] {
+        client.push_internal_address(*internal_address);
+    }
+    // Single addresses are already routed by default, but must be listed
+    // alongside any allocated subnets.
+    if allocated_subnets.iter()
+        .any(|subnet| subnet.prefix_len() != subnet.max_prefix_len()) {
+        for subnet in allocated_subnets {
+            client.push_allowed_ip(subnet);
+        }
+    }
+    Ok(client)
+}
+
+
+/// The routers and clients whose configs must be redeployed after a change
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Redeploy {
+    routers: Vec<String>,
+    clients: Vec<String>
+}
+
+
+impl Redeploy {
+    pub fn routers(&self) -> &[String] { &self.routers }
+    pub fn clients(&self) -> &[String] { &self.clients }
+
+    pub fn is_empty(&self) -> bool {
+        self.routers.is_empty() && self.clients.is_empty()
+    }
+}
+
+
+/// The configs to redeploy once `pruned` have been removed or disabled:
+/// their routers, and in a mesh, the clients which may have peered with
+/// them directly
+pub fn prune_redeploy(configuration: &Configuration, pruned: &[EndPoint],
+                      now: &DateTime<Utc>) -> Redeploy {
+    let mut redeploy = Redeploy::default();
+    if pruned.is_empty() {
+        return redeploy;
+    }
+
+    for router in configuration.routers() {
+        if pruned.iter().any(|client| {
+                configuration.primary_router(client).map(|r| r.name())
+                    .ok() == Some(router.name())
+                || client.secondary_router() == Some(router.name())
+            }) {
+            redeploy.routers.push(router.name().to_string());
+        }
+    }
+    if configuration.topology() == Topology::Mesh {
+        for client in configuration.clients() {
+            if client.is_active(now) && pruned.iter().any(|pruned|
+                    pruned.external_address().is_some()
+                    || client.external_address().is_some()) {
+                redeploy.clients.push(client.name().to_string());
+            }
+        }
+    }
+    redeploy
+}
+
+
+/// The clients whose keys can be rotated, which are those without a user
+/// supplied public key
+pub fn rotatable_clients(configuration: &Configuration) -> Vec<String> {
+    configuration.clients()
+        .iter()
+        .filter(|client| client.private_key().is_some())
+        .map(|client| client.name().to_string())
+        .collect()
+}
+
+
+/// Rotate the keys of the named routers and clients, returning the configs
+/// to redeploy: every node which holds one of the rotated public keys.
+/// Routers peer with each other and their clients, and in a mesh clients
+/// peer with each other.
+pub fn rotate_keys(configuration: &mut Configuration, routers: &[String],
+                   clients: &[String], now: &DateTime<Utc>)
+    -> Result<Redeploy> {
+
+    for name in clients {
+        configuration.client_mut(name)?.rotate_keys(now)?;
+    }
+    for name in routers {
+        configuration.router_mut(name)?.rotate_keys(now)?;
+    }
+
+    let rotated = |name: &str| {
+        routers.iter().chain(clients.iter()).any(|rotated| rotated == name)
+    };
+    let mut redeploy = Redeploy::default();
+    for router in configuration.routers() {
+        if !routers.is_empty()
+            || configuration.router_clients(router, now)
+                .iter()
+                .any(|client| rotated(client.name())) {
+            redeploy.routers.push(router.name().to_string());
+        }
+    }
+    for client in configuration.clients() {
+        let primary = configuration.primary_router(client)?;
+        if rotated(client.name())
+            || rotated(primary.name())
+            || client.secondary_router().is_some_and(&rotated)
+            || configuration.direct_peers(client, now)
+                .iter()
+                .any(|peer| rotated(peer.name())) {
+            redeploy.clients.push(client.name().to_string());
+        }
+    }
+    Ok(redeploy)
+}
@@ -0,0 +1,1342 @@
+extern crate chrono;
+extern crate ipnet;
+extern crate serde_json;
+extern crate wireguard_configure;
+
+use chrono::{DateTime, Duration, Utc};
+use ipnet::{Contains, IpNet};
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use wireguard_configure::{AddrPort, Configuration, EndPoint, ErrorKind,
+                          InterfaceSettings, Router, Topology, Tunnel};
+use wireguard_configure::{acl, keys, networkd, nmconnection, output, qr,
+                          routeros, script, uci, update};
+use wireguard_configure::acl::{Protocol, Rule};
+use wireguard_configure::firewall::{Backend, ForwardPolicy};
+use wireguard_configure::import::{import, WgConfig};
+use wireguard_configure::output::Output;
+use wireguard_configure::update::{ClientUpdate, GroupUpdate, ListUpdate,
+                                  RouterUpdate, SettingsUpdate, TargetUpdate};
+
+
+const LEGACY_CONFIGURATION: &str = r#"---
+router:
+  name: "vpn-router"
+  private_key: "ADsIErTzl7FaGDI614/MM6Y4YL+edr6v1ls314Fx4Vc="
+  public_key: "560oUL8qMUbEFcQRys3tm/IbO8DPz96Oy6xrVlPuIjk="
+  external_address:
+    address: vpn.com
+    port: 47654
+  internal_address: 10.0.0.1
+master_subnet: ~
+clients:
+  - name: "client-a"
+    private_key: "6AXhGpbF36uRQNK3kt8SIwd1WJSGrfsdEnj89SArfls="
+    public_key: "QEtcp4V4c79HH1aCGpZy237k96HU0thzHD66100upTQ="
+    external_address: ~
+    internal_address: 10.0.1.1
+    allowed_ips:
+      - 10.0.1.0/24
+    persistent_keepalive: 25
+"#;
+
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("wireguard-configure-{}-{}.yaml",
+        name, std::process::id()))
+}
+
+
+/// Check a file's permissions, where there are unix modes to check
+#[cfg(unix)]
+fn assert_mode(path: &Path, mode: u32) {
+    use std::os::unix::fs::PermissionsExt;
+    assert_eq!(fs::metadata(path).unwrap().permissions().mode() & 0o777, mode);
+}
+
+#[cfg(not(unix))]
+fn assert_mode(_path: &Path, _mode: u32) {}
+
+
+fn configuration() -> Configuration {
+    let router = Router::new("vpn-router", "10.0.0.1".parse().unwrap(),
+        AddrPort::new("vpn.com", 47654)).unwrap();
+
+    let mut configuration = Configuration::new(router);
+    configuration.push_client(
+        EndPoint::new("client-a", "10.0.1.1".parse().unwrap()).unwrap()
+            .builder_push_allowed_ips("10.0.1.0/24".parse().unwrap())
+            .builder_persistent_keepalive(Some(25))).unwrap();
+    configuration.push_client(
+        EndPoint::new("client-b", "10.0.2.1".parse().unwrap()).unwrap()
+            .builder_push_internal_address("fd00::2".parse().unwrap()))
+        .unwrap();
+    configuration
+}
+
+
+#[test]
+fn save_and_open() {
+    let path = temp_path("save-and-open");
+    let configuration = configuration();
+    configuration.save(&path).unwrap();
+
+    let opened = Configuration::open(&path).unwrap();
+    let yaml = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    // Empty lists of previous keys and unset preshared keys aren't written
+    // out.
+    assert!(!yaml.contains("previous_keys"));
+    assert!(!yaml.contains("preshared_key"));
+    assert_eq!(opened.router("vpn-router").unwrap().public_key(),
+               configuration.router("vpn-router").unwrap().public_key());
+    assert_eq!(opened.clients().len(), 2);
+    assert_eq!(opened.client("client-b").unwrap().internal_addresses(),
+               configuration.client("client-b").unwrap().internal_addresses());
+
+    let mut configuration = configuration;
+    let preshared_key = keys::generate_preshared_key().unwrap();
+    configuration.client_mut("client-a").unwrap()
+        .set_preshared_key(Some(preshared_key.clone()));
+    configuration.save(&path).unwrap();
+    let opened = Configuration::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(opened.client("client-a").unwrap().preshared_key(),
+               Some(preshared_key.as_str()));
+    assert_eq!(opened.client("client-b").unwrap().preshared_key(), None);
+}
+
+
+#[test]
+fn open_legacy_configuration() {
+    let now = Utc::now();
+    let path = temp_path("legacy");
+    fs::write(&path, LEGACY_CONFIGURATION).unwrap();
+    let configuration = Configuration::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert!(configuration.master_subnets().is_empty());
+    assert!(configuration.validate(&now).is_empty());
+
+    let client_config = configuration.client_config("client-a", &now).unwrap();
+    assert!(client_config.contains(
+        "PublicKey = 560oUL8qMUbEFcQRys3tm/IbO8DPz96Oy6xrVlPuIjk="));
+    assert!(client_config.contains("Endpoint = vpn.com:47654"));
+    assert!(client_config.contains("PersistentKeepalive = 25"));
+    assert!(client_config.contains("AllowedIPs = 10.0.1.0/24"));
+}
+
+
+#[test]
+fn open_configuration_without_master_subnet() {
+    let path = temp_path("no-master-subnet");
+    fs::write(&path, LEGACY_CONFIGURATION.replace("master_subnet: ~\n", ""))
+        .unwrap();
+    let configuration = Configuration::open(&path).unwrap();
+    assert!(configuration.master_subnets().is_empty());
+
+    // An empty master subnet is left out again on save.
+    configuration.save(&path).unwrap();
+    let saved = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert!(!saved.contains("master_subnet"));
+}
+
+
+#[test]
+fn open_reports_yaml_location() {
+    let path = temp_path("bad-yaml");
+    fs::write(&path, "router:\n  name: [\n").unwrap();
+    let error = Configuration::open(&path).unwrap_err();
+    fs::remove_file(&path).unwrap();
+
+    match *error.kind() {
+        ErrorKind::Yaml(_, _, line, _) => assert!(line.is_some()),
+        ref kind => panic!("unexpected error {:?}", kind)
+    }
+
+    // The location is only shown once.
+    fs::write(&path, "---
+routers: []
+").unwrap();
+    let error = Configuration::open(&path).unwrap_err();
+    fs::remove_file(&path).unwrap();
+    let message = error.to_string();
+    assert!(message.contains(":2:"), "{}", message);
+    assert!(!message.contains(" at line "), "{}", message);
+}
+
+
+#[test]
+fn ipv6_external_addresses() {
+    let now = Utc::now();
+    let bare = AddrPort::new("fd00::1", 51820);
+    assert_eq!(bare.to_string(), "[fd00::1]:51820");
+    let parsed: AddrPort = bare.to_string().parse().unwrap();
+    assert_eq!(parsed.to_string(), "[fd00::1]:51820");
+    assert_eq!(parsed.port(), 51820);
+    assert_eq!(AddrPort::new("vpn.com", 1).to_string(), "vpn.com:1");
+    assert!("fd00::1:51820".parse::<AddrPort>().is_err());
+
+    let mut configuration = configuration();
+    configuration.router_mut("vpn-router").unwrap()
+        .set_external_address(bare);
+    let config = configuration.client_config("client-a", &now).unwrap();
+    assert!(config.contains("Endpoint = [fd00::1]:51820\n"));
+}
+
+
+#[test]
+fn router_config_has_every_client() {
+    let now = Utc::now();
+    let configuration = configuration();
+    let router_config = configuration.router_config("vpn-router", &now)
+        .unwrap();
+
+    assert!(router_config.starts_with("[Interface]"));
+    assert!(router_config.contains("ListenPort = 47654"));
+    assert!(router_config.contains("# client-a"));
+    assert!(router_config.contains("AllowedIPs = 10.0.2.1/32, fd00::2/128"));
+}
+
+
+#[test]
+fn linux_scripts() {
+    let now = Utc::now();
+    let configuration = configuration();
+
+    let router_script = script::router_linux_script(&configuration,
+        "vpn-router", Backend::Iptables, &now).unwrap();
+    assert!(router_script.contains("ip route add 10.0.1.0/24 dev wg0"));
+    assert!(router_script.contains("ip -6 route add fd00::2/128 dev wg0"));
+
+    let client_script =
+        script::client_linux_script(&configuration, "client-b", &now).unwrap();
+    assert!(client_script.contains("ip address add dev wg0 10.0.2.1/32"));
+    assert!(client_script.contains("ip -6 address add dev wg0 fd00::2/128"));
+}
+
+
+#[test]
+fn remove_clients() {
+    let mut configuration = configuration();
+
+    let removed = configuration.remove_client_by_name("client-a").unwrap();
+    assert_eq!(removed.name(), "client-a");
+    assert!(configuration.client("client-a").is_err());
+
+    match *configuration.remove_client_by_name("client-a")
+            .unwrap_err().kind() {
+        ErrorKind::ClientNotFound(ref name) => assert_eq!(name, "client-a"),
+        ref kind => panic!("unexpected error {:?}", kind)
+    }
+}
+
+
+#[test]
+fn save_refuses_invalid_configuration() {
+    let path = temp_path("invalid");
+    let mut configuration = configuration();
+    configuration.client_mut("client-b").unwrap()
+        .push_allowed_ip("10.0.1.128/25".parse().unwrap());
+
+    match *configuration.save(&path).unwrap_err().kind() {
+        ErrorKind::InvalidConfiguration(ref diagnostics) =>
+            assert_eq!(diagnostics[0].client(), Some("client-b")),
+        ref kind => panic!("unexpected error {:?}", kind)
+    }
+    assert!(!path.exists());
+}
+
+
+#[test]
+fn update_client() {
+    let now = Utc::now();
+    let mut configuration = configuration();
+    {
+        let client = configuration.client_mut("client-a").unwrap();
+        client.push_allowed_ip("10.0.3.0/24".parse().unwrap());
+        assert!(client.remove_allowed_ip(&"10.0.1.0/24".parse().unwrap()));
+        assert!(!client.remove_allowed_ip(&"10.0.9.0/24".parse().unwrap()));
+        client.set_external_address(Some("[fd00::9]:51820".parse().unwrap()));
+    }
+
+    let diagnostics = configuration.validate(&now);
+    assert_eq!(diagnostics.len(), 1);
+    assert!(!diagnostics[0].is_error());
+    assert_eq!(diagnostics[0].field(), "allowed_ips");
+
+    let router_config = configuration.router_config("vpn-router", &now)
+        .unwrap();
+    assert!(router_config.contains("Endpoint = [fd00::9]:51820"));
+    assert!(router_config.contains("AllowedIPs = 10.0.3.0/24"));
+
+    // Without allowed ips of its own, a client's host addresses are derived.
+    let client = configuration.client("client-b").unwrap();
+    assert!(client.explicit_allowed_ips().is_empty());
+    assert_eq!(client.allowed_ips().len(), 2);
+
+    // Adding to the allowed ips starts from the client's own, not the
+    // derived ones.
+    let mut allowed_ips = ListUpdate::new();
+    allowed_ips.set_add(vec!["10.0.8.0/24".parse().unwrap()]);
+    let mut update = ClientUpdate::new();
+    update.set_allowed_ips(allowed_ips);
+    update.set_persistent_keepalive(Some(Some(15)));
+    update.set_public_key(Some(
+        "QEtcp4V4c79HH1aCGpZy237k96HU0thzHD66100upTQ=".to_string()));
+    update::update_clients(&mut configuration, &["client-b".to_string()],
+                           &update).unwrap();
+    let client = configuration.client("client-b").unwrap();
+    let allowed_ip: IpNet = "10.0.8.0/24".parse().unwrap();
+    assert_eq!(client.explicit_allowed_ips(), &[allowed_ip]);
+    assert_eq!(client.persistent_keepalive(), Some(15));
+    assert!(client.private_key().is_none());
+
+    let mut excluded_routes = ListUpdate::new();
+    excluded_routes.set_remove(vec!["10.9.0.0/16".parse().unwrap()]);
+    let mut update = ClientUpdate::new();
+    update.set_excluded_routes(excluded_routes);
+    let error = update.apply(configuration.client_mut("client-a").unwrap())
+        .unwrap_err();
+    assert_eq!(error.to_string(),
+               "10.9.0.0/16 is not one of the current excluded routes");
+
+    let error = update::update_clients(&mut configuration,
+        &["client-a".to_string()], &ClientUpdate::new()).unwrap_err();
+    assert_eq!(error.to_string(), "nothing to change for client client-a");
+}
+
+
+#[test]
+fn update_routers_and_groups() {
+    let mut configuration = configuration();
+
+    let mut update = RouterUpdate::new();
+    update.set_master_subnets(Some(vec!["10.0.0.0/16".parse().unwrap()]));
+    update.set_masquerade(Some(Some(false)));
+    let mut target = TargetUpdate::new();
+    target.set_listen_port(Some(Some(51820)));
+    update.set_target(target);
+    assert!(update.apply(&mut configuration, "vpn-router").unwrap());
+    assert!(!RouterUpdate::new().apply(&mut configuration, "vpn-router")
+        .unwrap());
+    assert_eq!(configuration.master_subnets().len(), 1);
+    let router = configuration.router("vpn-router").unwrap();
+    assert_eq!(router.forwarding().masquerade(), Some(false));
+    assert_eq!(router.target().listen_port(), Some(51820));
+
+    let mut settings = SettingsUpdate::new();
+    settings.set_mtu(Some(Some(1380)));
+    settings.set_post_up(Some(Vec::new()));
+    let mut update = GroupUpdate::new();
+    update.set_settings(settings);
+    assert!(update.apply(configuration.group_mut("staff")).unwrap());
+    assert_eq!(configuration.group("staff").unwrap().settings().mtu(),
+               Some(1380));
+
+    configuration.client_mut("client-a").unwrap()
+        .set_groups(vec!["staff".to_string()]);
+    assert_eq!(update::group_client_names(&configuration, "staff").unwrap(),
+               vec!["client-a".to_string()]);
+    assert!(update::group_client_names(&configuration, "ops").is_err());
+
+    // An invalid override for a single config is refused.
+    let mut target = TargetUpdate::new();
+    target.set_interface_name(Some(Some("wg0; reboot".to_string())));
+    let error = target.apply_valid(
+        configuration.client_mut("client-a").unwrap().target_mut(),
+        Some("client-a"), "").unwrap_err();
+    match *error.kind() {
+        ErrorKind::InvalidConfiguration(..) => {},
+        ref kind => panic!("unexpected error {:?}", kind)
+    }
+
+    // New nodes are allocated addresses from the master subnets, and
+    // subnets are listed as allowed ips.
+    let client = update::new_client(&configuration, "client-c", None,
+                                    Some(24), None).unwrap();
+    let subnet: IpNet = "10.0.3.0/24".parse().unwrap();
+    assert_eq!(client.explicit_allowed_ips(), &[subnet]);
+    let client = update::new_client(&configuration, "client-c", None,
+                                    None, None).unwrap();
+    assert!(client.explicit_allowed_ips().is_empty());
+    assert!(update::new_client(&configuration, "client-a", None, None, None)
+        .is_err());
+    let router = update::new_router(&configuration, "second-router", None,
+        AddrPort::new("vpn2.com", 51820)).unwrap();
+    assert!(configuration.master_subnets()[0]
+        .contains(&router.internal_addresses()[0]));
+}
+
+
+#[test]
+fn rotate_keys() {
+    let now: DateTime<Utc> = "2024-03-01T12:00:00Z".parse().unwrap();
+    let mut configuration = configuration();
+    let old_router_key =
+        configuration.router("vpn-router").unwrap().public_key().to_string();
+    let old_client_key =
+        configuration.client("client-a").unwrap().public_key().to_string();
+
+    configuration.router_mut("vpn-router").unwrap().rotate_keys(&now)
+        .unwrap();
+    configuration.client_mut("client-a").unwrap().rotate_keys(&now).unwrap();
+
+    let router = configuration.router("vpn-router").unwrap();
+    assert_ne!(router.public_key(), old_router_key);
+    assert_eq!(router.previous_keys()[0].public_key(), old_router_key);
+    assert_eq!(router.previous_keys()[0].rotated_at(), &now);
+
+    let client = configuration.client("client-a").unwrap();
+    assert_ne!(client.public_key(), old_client_key);
+    assert_eq!(client.previous_keys()[0].public_key(), old_client_key);
+    assert_eq!(client.previous_keys()[0].rotated_at(), &now);
+    assert!(configuration.validate(&now).is_empty());
+
+    let path = temp_path("rotate-keys");
+    configuration.save(&path).unwrap();
+    let opened = Configuration::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(opened.client("client-a").unwrap().previous_keys()[0]
+                   .public_key(), old_client_key);
+
+    let client = configuration.client_mut("client-b").unwrap();
+    client.set_private_key(None);
+    assert!(client.rotate_keys(&now).is_err());
+    assert_eq!(update::rotatable_clients(&configuration), vec!["client-a"]);
+
+    // A client's new key only reaches its router and itself, until the
+    // clients peer with each other in a mesh.
+    let redeploy = update::rotate_keys(&mut configuration, &[],
+        &["client-a".to_string()], &now).unwrap();
+    assert_eq!(redeploy.routers(), &["vpn-router"]);
+    assert_eq!(redeploy.clients(), &["client-a"]);
+    let redeploy = update::rotate_keys(&mut configuration,
+        &["vpn-router".to_string()], &[], &now).unwrap();
+    assert_eq!(redeploy.clients(), &["client-a", "client-b"]);
+    let router = configuration.router("vpn-router").unwrap();
+    assert_eq!(router.previous_keys().len(), 2);
+    assert_eq!(router.previous_keys()[1].rotated_at(), &now);
+
+    configuration.set_topology(Topology::Mesh);
+    configuration.client_mut("client-b").unwrap()
+        .set_external_address(Some(AddrPort::new("b.vpn.com", 51820)));
+    let redeploy = update::rotate_keys(&mut configuration, &[],
+        &["client-a".to_string()], &now).unwrap();
+    assert_eq!(redeploy.clients(), &["client-a", "client-b"]);
+}
+
+
+#[test]
+fn wg_quick_configs() {
+    let now = Utc::now();
+    let mut configuration = configuration();
+    configuration.client_defaults_mut()
+        .set_dns(vec!["10.0.0.1".to_string(), "vpn.lan".to_string()]);
+    configuration.client_defaults_mut().set_mtu(Some(1380));
+    configuration.router_mut("vpn-router").unwrap().settings_mut()
+        .set_post_up(vec!["echo up".to_string()]);
+    {
+        let client = configuration.client_mut("client-b").unwrap();
+        let mut settings = InterfaceSettings::new();
+        settings.set_mtu(Some(1280));
+        settings.set_table(Some("off".to_string()));
+        *client.settings_mut() = settings;
+    }
+
+    let client_a = configuration.client_wg_quick_config("client-a", &now)
+        .unwrap();
+    assert!(client_a.contains("Address = 10.0.1.1/32\n"));
+    assert!(client_a.contains("DNS = 10.0.0.1, vpn.lan\n"));
+    assert!(client_a.contains("MTU = 1380\n"));
+
+    // Settings of the client's own replace the defaults one by one.
+    let client_b = configuration.client_wg_quick_config("client-b", &now)
+        .unwrap();
+    assert!(client_b.contains("Address = 10.0.2.1/32, fd00::2/128\n"));
+    assert!(client_b.contains("DNS = 10.0.0.1, vpn.lan\n"));
+    assert!(client_b.contains("MTU = 1280\n"));
+    assert!(client_b.contains("Table = off\n"));
+
+    let router = configuration.router_wg_quick_config("vpn-router", &now)
+        .unwrap();
+    assert!(router.contains("Address = 10.0.0.1/32\n"));
+    assert!(router.contains("PostUp = echo up\n"));
+    assert!(!router.contains("DNS"));
+    assert!(!configuration.client_config("client-a", &now).unwrap()
+        .contains("Address"));
+
+    let path = temp_path("wg-quick");
+    configuration.save(&path).unwrap();
+    let opened = Configuration::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(opened.client_wg_quick_config("client-b", &now).unwrap(),
+               client_b);
+}
+
+
+#[test]
+fn qr_codes() {
+    let now = Utc::now();
+    let config = configuration().client_wg_quick_config("client-a", &now)
+        .unwrap();
+
+    let terminal = qr::terminal(&config).unwrap();
+    assert!(terminal.lines().count() > 20);
+    assert!(qr::svg(&config).unwrap().contains("<svg"));
+
+    // The codes hold the private key, so only the owner may read them.
+    let path = temp_path("qr").with_extension("png");
+    qr::write_png(&config, &path).unwrap();
+    let png = fs::read(&path).unwrap();
+    assert_mode(&path, 0o600);
+    fs::remove_file(&path).unwrap();
+    assert_eq!(&png[1..4], b"PNG");
+
+    let path = temp_path("qr").with_extension("svg");
+    qr::write_svg(&config, &path).unwrap();
+    assert_mode(&path, 0o600);
+    assert!(fs::read_to_string(&path).unwrap().contains("<svg"));
+    fs::remove_file(&path).unwrap();
+
+    match *qr::terminal(&"x".repeat(8000)).unwrap_err().kind() {
+        ErrorKind::QrCode(_) => {},
+        ref kind => panic!("unexpected error {:?}", kind)
+    }
+}
+
+
+#[test]
+fn mesh_topology() {
+    let now = Utc::now();
+    let mut configuration = configuration();
+    configuration.set_master_subnets(vec!["10.0.0.0/16".parse().unwrap(),
+                                          "fd00::/64".parse().unwrap()]);
+    configuration.client_mut("client-a").unwrap()
+        .set_external_address(Some("a.com:51820".parse().unwrap()));
+    configuration.push_client(
+        EndPoint::new("client-c", "10.0.3.1".parse().unwrap()).unwrap())
+        .unwrap();
+
+    // Hub and spoke configs only ever have the router as a peer.
+    let client_b = configuration.client_config("client-b", &now).unwrap();
+    assert_eq!(client_b.matches("[Peer]").count(), 1);
+
+    configuration.set_topology(Topology::Mesh);
+
+    // client-b and client-c are both behind NAT, so only reach each other
+    // through the router.
+    let client_b = configuration.client_config("client-b", &now).unwrap();
+    assert_eq!(client_b.matches("[Peer]").count(), 2);
+    assert!(client_b.contains("# client-a\nPublicKey"));
+    assert!(client_b.contains(
+        "Endpoint = a.com:51820\nAllowedIPs = 10.0.1.0/24"));
+    assert!(!client_b.contains("# client-c"));
+    assert!(client_b.contains("AllowedIPs = 10.0.0.0/24, 10.0.2.0/23, \
+                               10.0.4.0/22, 10.0.8.0/21, 10.0.16.0/20, \
+                               10.0.32.0/19, 10.0.64.0/18, 10.0.128.0/17"));
+
+    let client_a = configuration.client_config("client-a", &now).unwrap();
+    assert_eq!(client_a.matches("[Peer]").count(), 3);
+    assert!(client_a.contains("# client-c\nPublicKey"));
+    assert!(client_a.contains("AllowedIPs = 10.0.3.1/32"));
+    let router_allowed_ips = client_a.lines()
+        .find(|line| line.starts_with("AllowedIPs = 10.0.0.0/23, 10.0.2.0/32"))
+        .unwrap();
+    assert!(!router_allowed_ips.contains("10.0.2.1/32"));
+    assert!(!router_allowed_ips.contains("10.0.3.1/32"));
+
+    let path = temp_path("mesh");
+    configuration.save(&path).unwrap();
+    let opened = Configuration::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(opened.topology(), Topology::Mesh);
+}
+
+
+#[test]
+fn multiple_routers() {
+    let now = Utc::now();
+    let mut configuration = configuration();
+    configuration.set_master_subnets(vec!["10.0.0.0/16".parse().unwrap(),
+                                          "fd00::/64".parse().unwrap()]);
+    configuration.push_router(Router::new("hub2", "10.0.0.2".parse().unwrap(),
+        AddrPort::new("hub2.com", 51820)).unwrap()).unwrap();
+    assert!(configuration.push_router(Router::new("hub2",
+        "10.0.0.3".parse().unwrap(), AddrPort::new("hub3.com", 51820))
+        .unwrap()).is_err());
+
+    configuration.client_mut("client-a").unwrap()
+        .set_secondary_router(Some("hub2".to_string()));
+    configuration.client_mut("client-b").unwrap()
+        .set_primary_router(Some("hub2".to_string()));
+    assert!(configuration.validate(&now).is_empty());
+
+    // Each router peers with its own clients and the other router, which
+    // routes the clients it doesn't share.
+    let vpn_router = configuration.router_config("vpn-router", &now).unwrap();
+    assert!(vpn_router.contains("# client-a\n"));
+    assert!(!vpn_router.contains("# client-b\n"));
+    assert!(vpn_router.contains("# hub2\n"));
+    assert!(vpn_router.contains(
+        "AllowedIPs = 10.0.0.2/32, 10.0.2.1/32, fd00::2/128"));
+    let hub2 = configuration.router_config("hub2", &now).unwrap();
+    assert!(hub2.contains("# client-a\n"));
+    assert!(hub2.contains("# client-b\n"));
+    assert!(hub2.contains(
+        "Endpoint = vpn.com:47654\nAllowedIPs = 10.0.0.1/32"));
+
+    // The secondary router only gets its own address, so the two peers
+    // never share allowed ips.
+    let client_a = configuration.client_config("client-a", &now).unwrap();
+    let peers: Vec<&str> = client_a.split("[Peer]").skip(1).collect();
+    assert_eq!(peers.len(), 2);
+    assert!(peers[0].contains("# vpn-router\n"));
+    assert!(!peers[0].contains("10.0.0.2/32"));
+    assert!(peers[1].contains("# hub2\n"));
+    assert!(peers[1].ends_with("AllowedIPs = 10.0.0.2/32"));
+
+    configuration.fail_over("client-a").unwrap();
+    let client_a = configuration.client_config("client-a", &now).unwrap();
+    let peers: Vec<&str> = client_a.split("[Peer]").skip(1).collect();
+    assert!(peers[0].contains("# hub2\n"));
+    assert!(peers[1].ends_with("AllowedIPs = 10.0.0.1/32"));
+    assert!(configuration.fail_over("client-b").is_err());
+
+    configuration.client_mut("client-b").unwrap()
+        .set_secondary_router(Some("hub3".to_string()));
+    let diagnostics = configuration.validate(&now);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].field(), "secondary_router");
+    configuration.client_mut("client-b").unwrap()
+        .set_secondary_router(None);
+
+    let path = temp_path("multiple-routers");
+    configuration.save(&path).unwrap();
+    let opened = Configuration::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(opened.routers().len(), 2);
+
+    configuration.remove_router_by_name("hub2").unwrap();
+    assert!(configuration.remove_router_by_name("vpn-router").is_err());
+}
+
+
+#[test]
+fn import_configs() {
+    let now = Utc::now();
+    let mut configuration = configuration();
+    configuration.client_mut("client-a").unwrap().settings_mut()
+        .set_dns(vec!["10.0.0.1".to_string()]);
+
+    let router = WgConfig::parse("wg0.conf",
+        &configuration.router_wg_quick_config("vpn-router", &Utc::now())
+            .unwrap()).unwrap();
+    let client_a = WgConfig::parse("client-a.conf",
+        &configuration.client_wg_quick_config("client-a", &now).unwrap())
+        .unwrap();
+
+    let (imported, diagnostics) =
+        import(&router, &[client_a], None, None).unwrap();
+    assert!(diagnostics.is_empty());
+
+    let original = configuration.router("vpn-router").unwrap();
+    let router = imported.router("vpn-router").unwrap();
+    assert_eq!(router.private_key(), original.private_key());
+    assert_eq!(router.external_address().to_string(), "vpn.com:47654");
+    assert_eq!(router.internal_addresses(), original.internal_addresses());
+
+    // client-a had its own config, client-b is only known from the router.
+    let client_a = imported.client("client-a").unwrap();
+    let original = configuration.client("client-a").unwrap();
+    assert_eq!(client_a.private_key(), original.private_key());
+    assert_eq!(client_a.internal_addresses(), original.internal_addresses());
+    assert_eq!(client_a.allowed_ips(), original.allowed_ips());
+    assert_eq!(client_a.persistent_keepalive(), Some(25));
+    assert_eq!(client_a.settings().dns(), &["10.0.0.1".to_string()]);
+
+    let client_b = imported.client("client-b").unwrap();
+    let original = configuration.client("client-b").unwrap();
+    assert_eq!(client_b.private_key(), None);
+    assert_eq!(client_b.public_key(), original.public_key());
+    assert_eq!(client_b.internal_addresses(), original.internal_addresses());
+    assert!(imported.validate(&now).is_empty());
+
+    // Without a client config, the router's endpoint has to be given.
+    assert!(import(&router_config(&configuration), &[], None, None).is_err());
+    let (imported, diagnostics) = import(&router_config(&configuration), &[],
+        Some("vpn.com:47654".parse().unwrap()), None).unwrap();
+    assert!(diagnostics.is_empty());
+    let router = imported.router("vpn-router").unwrap();
+    assert_eq!(router.target().listen_port(), None);
+
+    // A router behind a forwarded port listens on another than clients
+    // connect to, and both are kept.
+    configuration.router_mut("vpn-router").unwrap().target_mut()
+        .set_listen_port(Some(51820));
+    let router = router_config(&configuration);
+    let client_a = WgConfig::parse("client-a.conf",
+        &configuration.client_wg_quick_config("client-a", &now).unwrap())
+        .unwrap();
+    let (imported, diagnostics) =
+        import(&router, &[client_a], None, None).unwrap();
+    assert!(diagnostics.is_empty());
+    let router = imported.router("vpn-router").unwrap();
+    assert_eq!(router.external_address().to_string(), "vpn.com:47654");
+    assert_eq!(router.target().listen_port(), Some(51820));
+    assert!(imported.router_config("vpn-router", &now).unwrap()
+        .contains("ListenPort = 51820\n"));
+    assert!(imported.client_config("client-a", &now).unwrap()
+        .contains("Endpoint = vpn.com:47654\n"));
+}
+
+
+fn router_config(configuration: &Configuration) -> WgConfig {
+    WgConfig::parse("wg0.conf",
+        &configuration.router_wg_quick_config("vpn-router", &Utc::now())
+            .unwrap()).unwrap()
+}
+
+
+#[test]
+fn import_errors() {
+    let error = WgConfig::parse("wg0.conf", "[Interface]\nPrivateKey\n")
+        .unwrap_err();
+    assert_eq!(error.to_string(), "wg0.conf: line 2: expected key = value, \
+                                   got PrivateKey");
+    assert!(WgConfig::parse("wg0.conf", "[Peer]\nPublicKey = x\n").is_err());
+    assert!(WgConfig::parse("wg0.conf", "ListenPort = 1\n").is_err());
+
+    // A config written by something else, with no name comments or
+    // addresses.
+    let router = WgConfig::parse("wg0.conf", "[Interface]
+PrivateKey = ADsIErTzl7FaGDI614/MM6Y4YL+edr6v1ls314Fx4Vc=
+ListenPort = 51820
+SaveConfig = true
+
+[Peer]
+PublicKey = QEtcp4V4c79HH1aCGpZy237k96HU0thzHD66100upTQ=
+AllowedIPs = 10.0.1.0/24, fd00:1::/64
+").unwrap();
+    assert!(import(&router, &[], Some("vpn.com:51820".parse().unwrap()), None)
+        .is_err());
+    let (imported, diagnostics) = import(&router, &[],
+        Some("vpn.com:51820".parse().unwrap()),
+        Some(vec!["10.0.0.1".parse().unwrap()])).unwrap();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].field(), "SaveConfig");
+    assert_eq!(imported.routers()[0].name(), "wg0");
+    let peer = imported.client("peer-1").unwrap();
+    assert_eq!(peer.internal_addresses(),
+               &["10.0.1.1".parse::<std::net::IpAddr>().unwrap(),
+                 "fd00:1::1".parse().unwrap()]);
+}
+
+
+#[test]
+fn tunnel_modes() {
+    let now = Utc::now();
+    let mut configuration = configuration();
+    configuration.set_master_subnets(vec!["10.0.0.0/16".parse().unwrap(),
+                                          "fd00::/64".parse().unwrap()]);
+
+    let client = configuration.client("client-a").unwrap();
+    assert_eq!(configuration.client_allowed_ips(client),
+               configuration.master_subnets());
+
+    configuration.client_mut("client-a").unwrap().set_tunnel(Tunnel::Full);
+    let client = configuration.client("client-a").unwrap();
+    assert_eq!(configuration.client_allowed_ips(client),
+               vec!["0.0.0.0/0".parse::<IpNet>().unwrap(),
+                    "::/0".parse().unwrap()]);
+    let script = script::client_linux_script(&configuration, "client-a", &now)
+        .unwrap();
+    assert!(script.contains("ip route add 0.0.0.0/1 dev wg0\n\
+                             ip route add 128.0.0.0/1 dev wg0\n"));
+    assert!(script.contains("getent ahostsv4 vpn.com"));
+    configuration.router_mut("vpn-router").unwrap()
+        .set_external_address(AddrPort::new("[fd00::1]", 47654));
+    let script = script::client_linux_script(&configuration, "client-a", &now)
+        .unwrap();
+    assert!(script.contains("ip -6 route add fd00::1 $(ip -6 route show \
+                             default | awk '{print \"via\", $3, \"dev\", \
+                             $5; exit}')\n"));
+    let script = script::router_linux_script(&configuration, "vpn-router",
+                                               Backend::Iptables, &now)
+        .unwrap();
+    assert!(script.contains("iptables -t nat -A POSTROUTING -s 10.0.1.0/24 \
+                             ! -o wg0 -j MASQUERADE"));
+    assert!(!script.contains("ip6tables"));
+
+    configuration.client_mut("client-a").unwrap()
+        .set_excluded_routes(vec!["128.0.0.0/1".parse().unwrap(),
+                                  "64.0.0.0/2".parse().unwrap(),
+                                  "::/1".parse().unwrap()]);
+    let client = configuration.client("client-a").unwrap();
+    assert_eq!(configuration.client_allowed_ips(client),
+               vec!["0.0.0.0/2".parse::<IpNet>().unwrap(),
+                    "8000::/1".parse().unwrap()]);
+    assert!(configuration.validate(&now).is_empty());
+
+    configuration.client_mut("client-a").unwrap()
+        .set_excluded_routes(vec!["10.0.0.0/24".parse().unwrap()]);
+    let diagnostics = configuration.validate(&now);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].field(), "excluded_routes");
+}
+
+
+#[test]
+fn forwarding() {
+    let now = Utc::now();
+    let mut configuration = configuration();
+    {
+        let forwarding = configuration.router_mut("vpn-router").unwrap()
+            .forwarding_mut();
+        forwarding.set_egress_interface(Some("eth0".to_string()));
+        forwarding.set_masquerade(Some(true));
+        forwarding.set_forward_policy(Some(ForwardPolicy::Drop));
+    }
+
+    let script = script::router_linux_script(&configuration, "vpn-router",
+                                               Backend::Iptables, &now)
+        .unwrap();
+    assert!(script.contains("sysctl -w net.ipv4.ip_forward=1"));
+    // Rules are only appended when missing, so the script can be rerun.
+    assert!(script.contains("iptables -P FORWARD DROP\n\
+                             iptables -C FORWARD -i wg0 -j ACCEPT 2>/dev/null \
+                             || iptables -A FORWARD -i wg0 -j ACCEPT\n"));
+    assert!(script.contains("iptables -t nat -C POSTROUTING -s 10.0.1.0/24 \
+                             -o eth0 -j MASQUERADE 2>/dev/null || "));
+    assert!(script.contains("iptables -t nat -A POSTROUTING -s 10.0.1.0/24 \
+                             -o eth0 -j MASQUERADE"));
+    assert!(script.contains("ip6tables -t nat -A POSTROUTING -s fd00::2/128 \
+                             -o eth0 -j MASQUERADE"));
+
+    let script = script::router_linux_script(&configuration, "vpn-router",
+                                               Backend::Nftables, &now)
+        .unwrap();
+    assert!(!script.contains("iptables"));
+    assert!(script.contains("nft -f - <<'EOF'\ntable inet wireguard\n\
+                             delete table inet wireguard\n\
+                             table inet wireguard {\n"));
+    assert!(script.contains("policy drop;"));
+    assert!(script.contains("ip saddr 10.0.1.0/24 oifname \"eth0\" \
+                             masquerade"));
+
+    configuration.router_mut("vpn-router").unwrap().forwarding_mut()
+        .set_masquerade(Some(false));
+    let script = script::router_linux_script(&configuration, "vpn-router",
+                                               Backend::Iptables, &now)
+        .unwrap();
+    assert!(!script.contains("MASQUERADE"));
+
+    // The forwarding settings survive a save and load.
+    let path = temp_path("forwarding");
+    configuration.save(&path).unwrap();
+    let loaded = Configuration::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let forwarding = loaded.router("vpn-router").unwrap().forwarding();
+    assert_eq!(forwarding.egress_interface(), Some("eth0"));
+    assert_eq!(forwarding.masquerade(), Some(false));
+    assert_eq!(forwarding.forward_policy(), Some(ForwardPolicy::Drop));
+}
+
+
+#[test]
+fn acl_firewall() {
+    let mut configuration = configuration();
+    configuration.push_client(
+        EndPoint::new("client-c", "10.0.3.1".parse().unwrap()).unwrap())
+        .unwrap();
+    {
+        let acl = configuration.acl_mut();
+        acl.set_group("contractors", vec!["client-b".to_string()]);
+        acl.set_group("servers", vec!["client-a".to_string(),
+                                      "client-c".to_string()]);
+        acl.push_rule(Rule::new("contractors", "servers")
+            .builder_protocol(Protocol::Tcp)
+            .builder_ports(vec![22, 443]));
+        acl.push_rule(Rule::new("servers", "servers"));
+    }
+    assert!(configuration.validate(&Utc::now()).is_empty());
+
+    let ruleset = acl::nftables_ruleset(&configuration,
+        configuration.router("vpn-router").unwrap()).unwrap();
+    assert!(ruleset.contains("iifname \"wg0\" oifname \"wg0\" jump clients"));
+    assert!(ruleset.contains("ip saddr { 10.0.2.1/32 } \
+                              ip daddr { 10.0.1.0/24, 10.0.3.1/32 } \
+                              tcp dport { 22, 443 } accept"));
+    assert!(ruleset.contains("ip saddr { 10.0.1.0/24, 10.0.3.1/32 } \
+                              ip daddr { 10.0.1.0/24, 10.0.3.1/32 } accept"));
+    // Only client-b has an IPv6 address, so no ip6 rule has both ends.
+    assert!(!ruleset.contains("ip6 saddr"));
+    assert!(ruleset.ends_with("        drop\n    }\n}"));
+
+    // The acl survives a save and load.
+    let path = temp_path("acl");
+    configuration.save(&path).unwrap();
+    let mut loaded = Configuration::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(acl::nftables_ruleset(&loaded,
+        loaded.router("vpn-router").unwrap()).unwrap(), ruleset);
+
+    // Removed clients leave their groups.
+    loaded.remove_client_by_name("client-c").unwrap();
+    assert_eq!(loaded.acl().group("servers").unwrap(),
+               &["client-a".to_string()]);
+
+    loaded.acl_mut().push_rule(Rule::new("contractors", "admins"));
+    let diagnostics = loaded.validate(&Utc::now());
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].field(), "acl.rules.2.to");
+    assert!(acl::nftables_ruleset(&loaded,
+        loaded.router("vpn-router").unwrap()).is_err());
+}
+
+
+#[test]
+fn client_groups() {
+    let now = Utc::now();
+    let mut configuration = configuration();
+    configuration.push_client(
+        EndPoint::new("client-c", "10.0.3.1".parse().unwrap()).unwrap()
+            .builder_push_group("engineering")
+            .builder_push_group("laptops")).unwrap();
+    configuration.client_mut("client-a").unwrap()
+        .set_groups(vec!["engineering".to_string()]);
+    {
+        let group = configuration.group_mut("engineering");
+        group.settings_mut().set_dns(vec!["10.0.0.53".to_string()]);
+        group.settings_mut().set_mtu(Some(1380));
+        group.set_routes(vec!["192.168.50.0/24".parse().unwrap()]);
+    }
+    configuration.group_mut("laptops").settings_mut().set_mtu(Some(1280));
+    configuration.client_defaults_mut().set_dns(vec!["1.1.1.1".to_string()]);
+
+    let members: Vec<&str> = configuration.group_members("engineering")
+        .iter()
+        .map(|client| client.name())
+        .collect();
+    assert_eq!(members, vec!["client-a", "client-c"]);
+
+    // The client's first group wins, and defaults fill in the rest.
+    let client = configuration.client("client-c").unwrap();
+    let settings = configuration.client_settings(client);
+    assert_eq!(settings.dns(), &["10.0.0.53".to_string()]);
+    assert_eq!(settings.mtu(), Some(1380));
+    let client = configuration.client("client-b").unwrap();
+    assert_eq!(configuration.client_settings(client).dns(),
+               &["1.1.1.1".to_string()]);
+    assert!(!configuration.client_allowed_ips(client)
+        .contains(&"192.168.50.0/24".parse().unwrap()));
+
+    let config = configuration.client_wg_quick_config("client-c", &now)
+        .unwrap();
+    assert!(config.contains("DNS = 10.0.0.53\nMTU = 1380\n"));
+    assert!(config.contains(", 192.168.50.0/24"));
+
+    // Clients listed under an acl group are members too, and get the
+    // group's settings.
+    configuration.acl_mut().set_group("engineering",
+                                      vec!["client-b".to_string()]);
+    assert_eq!(configuration.group_members("engineering").len(), 3);
+    let client = configuration.client("client-b").unwrap();
+    assert!(configuration.in_group(client, "engineering"));
+    assert_eq!(configuration.client_group_names(client), vec!["engineering"]);
+    assert_eq!(configuration.client_settings(client).mtu(), Some(1380));
+    assert!(configuration.has_group("laptops"));
+    assert!(!configuration.has_group("sales"));
+
+    let path = temp_path("client-groups");
+    configuration.save(&path).unwrap();
+    let mut loaded = Configuration::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.client("client-c").unwrap().groups(),
+               &["engineering".to_string(), "laptops".to_string()]);
+    assert_eq!(loaded.group("engineering").unwrap().routes(),
+               configuration.group("engineering").unwrap().routes());
+
+    loaded.remove_group("laptops").unwrap();
+    assert!(loaded.group("laptops").is_none());
+    assert_eq!(loaded.client("client-c").unwrap().groups(),
+               &["engineering".to_string()]);
+
+    // Groups which acl rules use are kept, and removing a group takes it
+    // out of the acl too.
+    loaded.acl_mut().push_rule(Rule::new("engineering", "engineering"));
+    assert!(loaded.remove_group("engineering").is_err());
+    assert!(loaded.has_group("engineering"));
+    configuration.remove_group("engineering").unwrap();
+    assert!(!configuration.has_group("engineering"));
+    assert!(configuration.acl().group("engineering").is_none());
+}
+
+
+#[test]
+fn expiry_and_disabling() {
+    let mut configuration = configuration();
+    configuration.set_topology(Topology::Mesh);
+    configuration.client_mut("client-a").unwrap()
+        .set_external_address(Some(AddrPort::new("a.vpn.com", 51820)));
+    configuration.push_client(
+        EndPoint::new("client-c", "10.0.3.1".parse().unwrap()).unwrap())
+        .unwrap();
+    let now = Utc::now();
+    configuration.client_mut("client-b").unwrap()
+        .set_expires_at(Some(now - Duration::try_hours(1).unwrap()));
+    configuration.client_mut("client-c").unwrap()
+        .set_expires_at(Some(now + Duration::try_days(30).unwrap()));
+
+    let client_b = configuration.client("client-b").unwrap();
+    assert!(client_b.is_expired(&now));
+    assert!(!client_b.is_active(&now));
+    assert!(configuration.client("client-c").unwrap().is_active(&now));
+
+    // Expired clients are left out of router configs and mesh peers.
+    let config = configuration.router_config("vpn-router", &now).unwrap();
+    assert!(config.contains("# client-c"));
+    assert!(!config.contains("# client-b"));
+    let config = configuration.client_config("client-a", &now).unwrap();
+    assert!(!config.contains("# client-b"));
+    // Configs are generated as of the given time.
+    let later = now + Duration::try_days(31).unwrap();
+    let router = configuration.router("vpn-router").unwrap();
+    assert!(configuration.router_clients(router, &later).iter()
+        .all(|client| client.name() == "client-a"));
+    let diagnostics = configuration.validate(&now);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].field(), "expires_at");
+    // Expiry is checked as of the given time too.
+    let earlier = now - Duration::try_hours(2).unwrap();
+    assert!(configuration.validate(&earlier).is_empty());
+    let diagnostics = configuration.validate(&later);
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(diagnostics[1].client(), Some("client-c"));
+
+    configuration.client_mut("client-c").unwrap().set_disabled(true);
+    let config = configuration.router_config("vpn-router", &now).unwrap();
+    assert!(!config.contains("# client-c"));
+    configuration.client_mut("client-c").unwrap().set_disabled(false);
+
+    let path = temp_path("expiry");
+    configuration.save(&path).unwrap();
+    let mut loaded = Configuration::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.client("client-b").unwrap().expires_at(),
+               configuration.client("client-b").unwrap().expires_at());
+    assert!(!loaded.client("client-c").unwrap().is_disabled());
+
+    let pruned = loaded.prune(&now, true);
+    assert_eq!(pruned.len(), 1);
+    assert!(loaded.client("client-b").unwrap().is_disabled());
+    // In a mesh, only clients with an external address peered with it.
+    let redeploy = update::prune_redeploy(&loaded, &pruned, &now);
+    assert_eq!(redeploy.routers(), &["vpn-router"]);
+    assert_eq!(redeploy.clients(), &["client-a"]);
+    assert!(update::prune_redeploy(&loaded, &[], &now).is_empty());
+    assert!(loaded.prune(&now, true).is_empty());
+
+    let pruned = loaded.prune(&now, false);
+    assert_eq!(pruned[0].name(), "client-b");
+    assert!(loaded.client_by_name("client-b").is_none());
+    assert!(loaded.client_by_name("client-c").is_some());
+}
+
+
+#[test]
+fn script_targets() {
+    let now = Utc::now();
+    assert_eq!(script::quote("wg0"), "wg0");
+    assert_eq!(script::quote("/etc/wireguard/my vpn.conf"),
+               "'/etc/wireguard/my vpn.conf'");
+    assert_eq!(script::quote("it's"), "'it'\\''s'");
+    assert_eq!(script::quote(""), "''");
+
+    let mut configuration = configuration();
+    {
+        let router = configuration.router_mut("vpn-router").unwrap();
+        router.target_mut().set_interface_name(Some("wg-hub".to_string()));
+        router.target_mut().set_listen_port(Some(51820));
+        router.forwarding_mut().set_forward_policy(Some(ForwardPolicy::Drop));
+    }
+    configuration.client_mut("client-a").unwrap().target_mut()
+        .set_config_path(Some("/etc/wireguard/my vpn.conf".to_string()));
+
+    let config = configuration.router_config("vpn-router", &now).unwrap();
+    assert!(config.contains("ListenPort = 51820\n"));
+    // Peers still reach the router on its external address.
+    let config = configuration.client_config("client-a", &now).unwrap();
+    assert!(config.contains("Endpoint = vpn.com:47654\n"));
+
+    let script = script::router_linux_script(&configuration, "vpn-router",
+                                               Backend::Iptables, &now)
+        .unwrap();
+    assert!(script.starts_with("cat > wg-hub.conf <<'EOF'\n"));
+    assert!(script.contains("wg setconf wg-hub wg-hub.conf\n"));
+    assert!(script.contains("ip route add 10.0.1.0/24 dev wg-hub\n"));
+    assert!(script.contains("iptables -A FORWARD -i wg-hub -j ACCEPT\n"));
+    assert!(!script.contains("wg0"));
+    let ruleset = acl::nftables_ruleset(&configuration,
+        configuration.router("vpn-router").unwrap()).unwrap();
+    assert!(ruleset.contains("iifname \"wg-hub\" oifname \"wg-hub\""));
+
+    let script = script::client_linux_script(&configuration, "client-a", &now)
+        .unwrap();
+    assert!(script.contains("wg setconf wg0 '/etc/wireguard/my vpn.conf'\n"));
+    let script = script::client_osx_script(&configuration, "client-a", &now)
+        .unwrap();
+    assert!(script.contains("sudo wireguard-go utun9\n"));
+
+    // OS X only brings up utun devices.
+    let target = configuration.client_mut("client-a").unwrap().target_mut();
+    target.set_interface_name(Some("wg1".to_string()));
+    assert!(target.osx_interface().is_err());
+    target.set_interface_name(Some("utun".to_string()));
+    assert!(target.osx_interface().is_err());
+    target.set_interface_name(Some("utun3".to_string()));
+    assert_eq!(target.osx_interface().unwrap(), "utun3");
+    assert!(script::client_osx_script(&configuration, "client-a", &now)
+        .unwrap()
+        .contains("sudo wireguard-go utun3\n"));
+
+    configuration.client_mut("client-b").unwrap().target_mut()
+        .set_interface_name(Some("wg0; reboot".to_string()));
+    let diagnostics = configuration.validate(&now);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].field(), "target.interface_name");
+    let target = configuration.client("client-b").unwrap().target();
+    assert_eq!(target.validate(Some("client-b"), ""), diagnostics);
+}
+
+
+#[test]
+fn networkd_units() {
+    let now = Utc::now();
+    let mut configuration = configuration();
+    configuration.client_defaults_mut()
+        .set_dns(vec!["10.0.0.53".to_string(), "vpn.lan".to_string()]);
+
+    let units = networkd::router_units(&configuration, "vpn-router", &now)
+        .unwrap();
+    assert_eq!(units.netdev_file_name(), "wg0.netdev");
+    assert!(units.netdev().starts_with("[NetDev]\nName=wg0\nKind=wireguard\n"));
+    assert!(units.netdev().contains("ListenPort=47654\n"));
+    assert!(units.netdev().contains(
+        "[WireGuardPeer]\n# client-b\nPublicKey="));
+    assert!(units.netdev().ends_with(
+        "AllowedIPs=10.0.2.1/32\nAllowedIPs=fd00::2/128"));
+    assert!(units.network().contains("[Network]\nAddress=10.0.0.1/32\n"));
+    assert!(units.network().contains("[Route]\nDestination=10.0.1.0/24\n"));
+
+    let units = networkd::client_units(&configuration, "client-a", &now)
+        .unwrap();
+    assert!(units.netdev().contains("Endpoint=vpn.com:47654\n"));
+    assert!(!units.netdev().contains("FirewallMark"));
+    assert!(units.network().contains("DNS=10.0.0.53\nDomains=vpn.lan\n"));
+    assert!(units.network().contains("[Route]\nDestination=10.0.0.1/32\n"));
+    assert!(units.network().ends_with("[Route]\nDestination=fd00::2/128"));
+
+    configuration.client_mut("client-a").unwrap().set_tunnel(Tunnel::Full);
+    let units = networkd::client_units(&configuration, "client-a", &now)
+        .unwrap();
+    assert!(units.netdev().contains("FirewallMark=0xca6c\n"));
+    assert!(units.network().contains(
+        "[Route]\nDestination=0.0.0.0/0\nTable=51820\n"));
+    assert!(units.network().contains("InvertRule=yes\nTable=51820\n"));
+
+    let directory = env::temp_dir().join(format!(
+        "wireguard-configure-networkd-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let paths = units.write(&directory).unwrap();
+    let netdev = fs::read_to_string(&paths[0]).unwrap();
+    assert_mode(&paths[0], 0o640);
+    fs::remove_dir_all(&directory).unwrap();
+    assert_eq!(paths[1], directory.join("wg0.network"));
+    assert_eq!(netdev, format!("{}\n", units.netdev()));
+}
+
+
+#[test]
+fn nmconnection_keyfile() {
+    let now = Utc::now();
+    let mut configuration = configuration();
+    configuration.client_defaults_mut()
+        .set_dns(vec!["10.0.0.53".to_string(), "fd00::53".to_string(),
+                      "vpn.lan".to_string()]);
+    configuration.client_mut("client-b").unwrap()
+        .set_preshared_key(Some(keys::generate_preshared_key().unwrap()));
+
+    let keyfile = nmconnection::client_keyfile(&configuration, "client-a", &now)
+        .unwrap();
+    let uuid = "e4c8a0bd-1e13-5a3a-9d0e-6c16b69f7ad3";
+    assert_eq!(nmconnection::connection_uuid("client-a"), uuid);
+    assert!(keyfile.starts_with(&format!(
+        "[connection]\nid=client-a\nuuid={}\ntype=wireguard\n\
+         interface-name=wg0\n", uuid)));
+    assert!(keyfile.contains(&format!(
+        "[wireguard-peer.{}]\nendpoint=vpn.com:47654\n\
+         persistent-keepalive=25\n",
+        configuration.router("vpn-router").unwrap().public_key())));
+    assert!(keyfile.contains(
+        "[ipv4]\nmethod=manual\naddress1=10.0.1.1/32\ndns=10.0.0.53;\n\
+         dns-search=vpn.lan;"));
+    assert!(keyfile.ends_with("[ipv6]\nmethod=disabled"));
+
+    let keyfile = nmconnection::client_keyfile(&configuration, "client-b", &now)
+        .unwrap();
+    assert!(keyfile.contains("preshared-key-flags=0\n"));
+    assert!(keyfile.ends_with(
+        "[ipv6]\nmethod=manual\naddress1=fd00::2/128\ndns=fd00::53;"));
+
+    // The UUID is taken from the name, so survives rotating keys.
+    configuration.client_mut("client-a").unwrap().rotate_keys(&now)
+        .unwrap();
+    let keyfile = nmconnection::client_keyfile(&configuration, "client-a", &now)
+        .unwrap();
+    assert!(keyfile.contains(&format!("\nuuid={}\n", uuid)));
+    assert_ne!(nmconnection::connection_uuid("client-b"), uuid);
+}
+
+
+#[test]
+fn uci_config() {
+    let now = Utc::now();
+    assert_eq!(uci::quote("wg0"), "'wg0'");
+    assert_eq!(uci::quote("it's"), "'it'\\''s'");
+
+    let mut configuration = configuration();
+    configuration.client_mut("client-a").unwrap().settings_mut()
+        .set_mtu(Some(1380));
+    configuration.router_mut("vpn-router").unwrap()
+        .set_external_address(AddrPort::new("[fd00::1]", 51820));
+
+    let config = uci::client_config(&configuration, "client-a", &now).unwrap();
+    assert!(config.starts_with(
+        "package network\n\nconfig interface 'wg0'\n\
+         \toption proto 'wireguard'\n\toption private_key '"));
+    assert!(config.contains(
+        "\tlist addresses '10.0.1.1/32'\n\toption mtu '1380'\n"));
+    assert!(config.contains(&format!(
+        "config wireguard_wg0\n\toption description 'vpn-router'\n\
+         \toption public_key '{}'\n\toption endpoint_host 'fd00::1'\n\
+         \toption endpoint_port '51820'\n\toption persistent_keepalive '25'\n\
+         \toption route_allowed_ips '1'\n\tlist allowed_ips '10.0.1.0/24'\n",
+        configuration.router("vpn-router").unwrap().public_key())));
+    assert!(config.contains(
+        "package firewall\n\nconfig zone\n\toption name 'wg0'\n\
+         \tlist network 'wg0'\n"));
+    assert!(config.ends_with(
+        "config forwarding\n\toption src 'wg0'\n\toption dest 'lan'"));
+
+    // Section names can't contain `-`, and zone names are cut short.
+    configuration.client_mut("client-a").unwrap().target_mut()
+        .set_interface_name(Some("wg-branch-office".to_string()));
+    let config = uci::client_config(&configuration, "client-a", &now).unwrap();
+    assert!(config.contains("config interface 'wg_branch_office'\n"));
+    assert!(config.contains("config wireguard_wg_branch_office\n"));
+    assert!(config.contains(
+        "\toption name 'wg_branch_o'\n\tlist network 'wg_branch_office'\n"));
+    assert!(config.ends_with(
+        "\toption src 'wg_branch_o'\n\toption dest 'lan'"));
+}
+
+
+#[test]
+fn routeros_commands() {
+    let now = Utc::now();
+    assert_eq!(routeros::quote("10.0.0.0/24,fd00::/64"),
+               "10.0.0.0/24,fd00::/64");
+    assert_eq!(routeros::quote("my vpn"), "\"my vpn\"");
+    assert_eq!(routeros::quote("a\"b$c?\\\n"), "\"a\\\"b\\$c\\?\\\\\\n\"");
+    assert_eq!(routeros::quote(""), "\"\"");
+
+    let mut configuration = configuration();
+    configuration.client_mut("client-a").unwrap()
+        .set_preshared_key(Some(keys::generate_preshared_key().unwrap()));
+
+    let commands = routeros::router_commands(&configuration, "vpn-router", &now)
+        .unwrap();
+    let router = configuration.router("vpn-router").unwrap();
+    assert!(commands.starts_with(&format!(
+        "/interface wireguard add name=wg0 private-key=\"{}\" \
+         listen-port=47654\n", router.private_key())));
+    assert!(commands.contains(
+        "allowed-address=10.0.2.1/32,fd00::2/128 comment=client-b\n"));
+    assert!(commands.contains(
+        "/ip address add address=10.0.0.1/32 interface=wg0\n"));
+    assert!(commands.ends_with(
+        "/ipv6 route add dst-address=fd00::2/128 gateway=wg0"));
+
+    configuration.client_mut("client-a").unwrap().set_tunnel(Tunnel::Full);
+    let gateways: Vec<IpAddr> = vec!["192.168.88.1".parse().unwrap()];
+    assert!(routeros::client_commands(&configuration, "client-a", &gateways,
+                                      &now).is_err());
+    configuration.router_mut("vpn-router").unwrap()
+        .set_external_address(AddrPort::new("203.0.113.1", 47654));
+    assert!(routeros::client_commands(&configuration, "client-a", &[], &now)
+        .is_err());
+    let commands = routeros::client_commands(&configuration, "client-a",
+                                             &gateways, &now).unwrap();
+    assert!(commands.contains(&format!(
+        "/interface wireguard peers add interface=wg0 public-key=\"{}\" \
+         preshared-key=\"{}\" endpoint-address=203.0.113.1 \
+         endpoint-port=47654 \
+         persistent-keepalive=25s allowed-address=0.0.0.0/0,::/0 \
+         comment=vpn-router\n",
+        configuration.router("vpn-router").unwrap().public_key(),
+        configuration.client("client-a").unwrap().preshared_key().unwrap())));
+    assert!(commands.contains(
+        "/ip route add dst-address=128.0.0.0/1 gateway=wg0\n"));
+    assert!(commands.ends_with(
+        "/ip route add dst-address=203.0.113.1/32 gateway=192.168.88.1"));
+}
+
+
+#[test]
+fn json_output() {
+    let now = Utc::now();
+    let mut configuration = configuration();
+    configuration.client_mut("client-b").unwrap().set_disabled(true);
+
+    let listing = output::listing(&configuration, None, &Utc::now());
+    let json: Value = serde_json::from_str(
+        &Output::Json.serialize(&listing).unwrap()).unwrap();
+    assert_eq!(json["version"], output::SCHEMA_VERSION);
+    assert_eq!(json["nodes"][0]["kind"], "router");
+    assert_eq!(json["nodes"][0]["endpoint"], "vpn.com:47654");
+    assert_eq!(json["nodes"][1]["name"], "client-a");
+    assert_eq!(json["nodes"][1]["allowed_ips"][0], "10.0.1.0/24");
+    assert_eq!(json["nodes"][1]["persistent_keepalive"], 25);
+    assert_eq!(json["nodes"][1]["endpoint"], Value::Null);
+    assert_eq!(json["nodes"][2]["internal_addresses"][1], "fd00::2");
+    assert_eq!(json["nodes"][2]["status"], "disabled");
+
+    let config = output::client_config(&configuration, "client-a", &now)
+        .unwrap();
+    let json: Value = serde_json::from_str(
+        &Output::Json.serialize(&config).unwrap()).unwrap();
+    assert_eq!(json["kind"], "client");
+    assert_eq!(json["interface"]["name"], "wg0");
+    assert_eq!(json["interface"]["addresses"][0], "10.0.1.1/32");
+    assert_eq!(json["interface"]["listen_port"], Value::Null);
+    assert_eq!(json["peers"][0]["name"], "vpn-router");
+    assert_eq!(json["peers"][0]["endpoint"], "vpn.com:47654");
+    assert_eq!(json["peers"][0]["persistent_keepalive"], 25);
+
+    let config = output::router_config(&configuration, "vpn-router", &now)
+        .unwrap();
+    assert_eq!(config.interface().listen_port(), Some(47654));
+    // Disabled clients aren't peers of the router.
+    assert_eq!(config.peers().len(), 1);
+    assert_eq!(config.peers()[0].allowed_ips(),
+               &["10.0.1.0/24".parse::<IpNet>().unwrap()]);
+    assert!(Output::Yaml.serialize(&config).unwrap()
+        .contains("kind: router\n"));
+    assert!(Output::Table.serialize(&config).is_err());
+}